@@ -0,0 +1,48 @@
+//! Checks the vanilla block state reports and copies them to `OUT_DIR` for `server::registry`.
+//!
+//! The reports aren't committed. Generate one per version with the vanilla server's data generator
+//! (1.20.5 and later need Java 21):
+//!
+//! ```sh
+//! java -DbundlerMainClass=net.minecraft.data.Main -jar server-1.21.jar --reports --output reports-1.21
+//! cp reports-1.21/reports/blocks.json src/server/blocks_1.21.json
+//! ```
+//!
+//! Without them every block would be sent as air, so the build fails. Setting
+//! `SWANDIST_SKIP_BLOCK_REPORTS` builds anyway with empty reports, e.g. to work on something else.
+
+use std::env;
+use std::fs;
+use std::path::Path;
+
+/// Versions with their own block state IDs
+const VERSIONS: [&str; 3] = ["1.20.4", "1.20.6", "1.21"];
+const SKIP_VAR: &str = "SWANDIST_SKIP_BLOCK_REPORTS";
+
+fn main() {
+    let out_dir = env::var("OUT_DIR").unwrap();
+    let skip = env::var_os(SKIP_VAR).is_some();
+    println!("cargo:rerun-if-env-changed={SKIP_VAR}");
+
+    for version in VERSIONS {
+        let path = format!("src/server/blocks_{version}.json");
+        println!("cargo:rerun-if-changed={path}");
+        let problem = match fs::read_to_string(&path) {
+            // Any real report lists stone
+            Ok(report) if report.contains("\"minecraft:stone\"") => {
+                fs::write(Path::new(&out_dir).join(format!("blocks_{version}.json")), report).unwrap();
+                continue;
+            },
+            Ok(_) => "isn't a vanilla block state report",
+            Err(_) => "is missing"
+        };
+        if !skip {
+            panic!("{path} {problem}. Generate it with\n\n    \
+                    java -DbundlerMainClass=net.minecraft.data.Main -jar server-{version}.jar --reports --output reports-{version}\n    \
+                    cp reports-{version}/reports/blocks.json {path}\n\n\
+                    (see build.rs), or set {SKIP_VAR}=1 to build with every block sent as air.");
+        }
+        println!("cargo:warning={path} {problem}; every block will be sent as air on {version}");
+        fs::write(Path::new(&out_dir).join(format!("blocks_{version}.json")), "{}").unwrap();
+    }
+}
@@ -1,4 +1,5 @@
 use std::collections::HashMap;
+use serde_derive::Deserialize;
 use crate::Tag;
 
 #[derive(Debug, Clone)]
@@ -43,4 +44,125 @@ impl Block {
         }
     }
 
+    /// Properties as strings, the way the block state report lists them
+    pub fn properties(&self) -> Vec<(String, String)> {
+        match self.data.get("Properties").and_then(|p| p.as_compound()) {
+            Ok(props) => props.iter()
+                .map(|(k, v)| {
+                    let v = match v {
+                        Tag::String(s) => s.clone(),
+                        Tag::Byte(b) => (*b != 0).to_string(),
+                        Tag::Int(i) => i.to_string(),
+                        _ => String::new()
+                    };
+                    (k.clone(), v)
+                })
+                .collect(),
+            Err(_) => Vec::new()
+        }
+    }
+
+    pub fn is_air(&self) -> bool {
+        matches!(self.name().map(|n| n.as_str()), None | Some("minecraft:air" | "minecraft:cave_air" | "minecraft:void_air"))
+    }
+}
+
+#[derive(Deserialize)]
+struct ReportState {
+    id: i32,
+    #[serde(default)]
+    properties: HashMap<String, String>,
+    #[serde(default)]
+    default: bool
+}
+
+#[derive(Deserialize)]
+struct ReportBlock {
+    states: Vec<ReportState>
+}
+
+/// A block name with its properties sorted by key, identifying one block state
+type StateKey = (String, Vec<(String, String)>);
+
+/// Block state IDs from a vanilla block state report (`blocks.json`), looked up in both directions
+pub struct BlockStates {
+    ids: HashMap<StateKey, i32>,
+    defaults: HashMap<String, i32>,
+    states: HashMap<i32, StateKey>
+}
+
+impl BlockStates {
+    pub fn from_report(json: &str) -> anyhow::Result<BlockStates> {
+        let report: HashMap<String, ReportBlock> = serde_json::from_str(json)?;
+        let mut index = BlockStates { ids: HashMap::new(), defaults: HashMap::new(), states: HashMap::new() };
+        for (name, block) in report {
+            for state in block.states {
+                let key = state_key(&name, state.properties.into_iter().collect());
+                if state.default {
+                    index.defaults.insert(name.clone(), state.id);
+                }
+                index.ids.insert(key.clone(), state.id);
+                index.states.insert(state.id, key);
+            }
+        }
+        Ok(index)
+    }
+
+    /// Number of block states, which sets how many bits a state ID needs
+    pub fn len(&self) -> usize {
+        self.states.len()
+    }
+
+    pub fn get_id(&self, name: &str, properties: Vec<(String, String)>) -> Option<i32> {
+        let default = *self.defaults.get(name)?;
+        let key = state_key(name, properties);
+        if let Some(id) = self.ids.get(&key) {
+            return Some(*id);
+        }
+        // Missing or unknown properties keep the default state's values
+        let mut merged: HashMap<String, String> = self.states.get(&default)?.1.iter().cloned().collect();
+        for (k, v) in key.1 {
+            if let Some(value) = merged.get_mut(&k) {
+                *value = v;
+            }
+        }
+        Some(self.ids.get(&state_key(name, merged.into_iter().collect())).copied().unwrap_or(default))
+    }
+
+    pub fn get_block(&self, id: i32) -> Option<Block> {
+        let (name, properties) = self.states.get(&id)?;
+        Some(Block::new(name, properties.iter().cloned().collect()))
+    }
+}
+
+fn state_key(name: &str, mut properties: Vec<(String, String)>) -> StateKey {
+    properties.sort();
+    (name.to_owned(), properties)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use crate::block::{Block, BlockStates};
+
+    #[test]
+    fn test_block_state_index() {
+        let states = BlockStates::from_report(r#"{
+            "minecraft:air": {"states": [{"id": 0, "default": true}]},
+            "minecraft:oak_log": {
+                "properties": {"axis": ["x", "y", "z"]},
+                "states": [
+                    {"id": 130, "properties": {"axis": "x"}},
+                    {"id": 131, "properties": {"axis": "y"}, "default": true},
+                    {"id": 132, "properties": {"axis": "z"}}
+                ]
+            }
+        }"#).unwrap();
+        let log = Block::new("minecraft:oak_log", HashMap::from([("axis".to_owned(), "z".to_owned())]));
+        assert_eq!(states.get_id("minecraft:oak_log", log.properties()), Some(132));
+        assert_eq!(states.get_id("minecraft:oak_log", Vec::new()), Some(131));
+        assert_eq!(states.get_id("minecraft:unknown", Vec::new()), None);
+        assert_eq!(states.get_block(130).unwrap().properties(), vec![("axis".to_owned(), "x".to_owned())]);
+        assert_eq!(states.len(), 4);
+    }
 }
@@ -3,82 +3,59 @@ use std::io::Cursor;
 use std::path::Path;
 use bytes::{BufMut, BytesMut};
 use itertools::Itertools;
-use lazy_static::lazy_static;
 use log::warn;
-use serde_derive::{Serialize, Deserialize};
-use serde_json::{Value, Map};
 use crate::block::Block;
+use crate::server::registry;
 use crate::server::utils::write_varint;
 use crate::server::version::ProtocolVersion;
 use crate::{Region, Tag};
 
-trait StringToStringMap {
-    fn string_to_string_map(&self) -> HashMap<String, String>;
-}
-
-impl StringToStringMap for HashMap<String, Value> {
-    fn string_to_string_map(&self) -> HashMap<String, String> {
-        self.iter()
-            .map(|(k, v)| {
-                let v = match v.clone() {
-                    e @ Value::Number(_) | e @ Value::Bool(_) => e.to_string(),
-                    Value::String(s) => s,
-                    _ => {
-                        warn!(r#"Warning : Can not convert field : "{}'s value to String, It will be empty string."#, k);
-                        "".to_string()
-                    }
-                };
+/// Used for sections without biome data, and for biomes the client's registry doesn't have
+const DEFAULT_BIOME: &str = "minecraft:plains";
 
-                (k.clone(), v)
-            })
-            .collect()
-    }
-}
 
-trait StringToStringVecMap {
-    fn string_to_string_map(&self) -> HashMap<String, Vec<String>>;
+/// Bits needed to tell apart `count` values
+fn bits_for(count: usize) -> u32 {
+    (count.max(2) - 1).ilog2() + 1
 }
 
-impl StringToStringVecMap for HashMap<String, Value> {
-    fn string_to_string_map(&self) -> HashMap<String, Vec<String>> {
-        self.iter()
-            .map(|(k, v)| {
-                let v = match v.clone() {
-                    Value::Array(s) => s.iter().map(|v| {
-                        match v.clone() {
-                            Value::String(st) => st,
-                            _ => "".to_string()
-                        }
-                    }).collect(),
-                    _ => vec!["".to_string()]
-                };
-
-                (k.clone(), v)
-            })
-            .collect()
+/// Writes a paletted container: a single value, indices into a palette of at most `max_indirect_bits`,
+/// or the values themselves in `direct_bits` each
+fn write_paletted_container(buf: &mut BytesMut, values: &[i32], min_bits: u32, max_indirect_bits: u32, direct_bits: u32) {
+    let palette: Vec<i32> = values.iter().copied().unique().sorted().collect();
+    if palette.len() <= 1 {
+        buf.put_u8(0);  // single valued
+        write_varint(buf, palette.first().copied().unwrap_or(0));
+        write_varint(buf, 0);  // empty array
+        return;
     }
-}
 
-#[derive(Deserialize, Serialize)]
-struct BlockState {
-    id: i32,
-    properties: Option<HashMap<String, String>>,
-    default: Option<bool>
-}
-
-#[derive(Deserialize, Serialize)]
-struct BlockType {
-    #[serde(default = "HashMap::new")]
-    properties: HashMap<String, Vec<String>>,
-    states: Vec<BlockState>
-}
-
-type Blocks = Map<String, Value>;
-
-static BLOCKS_JSON: &str = include_str!("server/blocks.json");
-
-lazy_static! {
-    static ref BLOCKS: Blocks = serde_json::from_str(&BLOCKS_JSON).unwrap();
+    let mut bits = bits_for(palette.len()).max(min_bits);
+    let indirect = bits <= max_indirect_bits;
+    let entries: Vec<u64> = if indirect {
+        let indices: HashMap<i32, u64> = palette.iter().enumerate().map(|(i, v)| (*v, i as u64)).collect();
+        values.iter().map(|v| indices[v]).collect()
+    } else {
+        bits = direct_bits;
+        values.iter().map(|v| *v as u64).collect()
+    };
+    // Entries don't span longs
+    let per_long = (64 / bits) as usize;
+    let data: Vec<u64> = entries.chunks(per_long)
+        .map(|vals| vals.iter().enumerate().fold(0u64, |l, (j, val)| l | (val << (bits as usize * j))))
+        .collect();
+
+    buf.put_u8(bits as u8);
+    if indirect {
+        write_varint(buf, palette.len() as i32);
+        for v in &palette {
+            write_varint(buf, *v);
+        }
+    }
+    write_varint(buf, data.len() as i32);
+    for l in data {
+        buf.put_u64(l);
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -87,6 +64,9 @@ pub struct SubChunk {
     pub(crate) blocks: Vec<u16>,
     pub(crate) block_light: Option<Vec<u8>>,
     pub(crate) sky_light: Option<Vec<u8>>,
+    pub(crate) biome_palette: Vec<String>,
+    /// Palette indices of the 4x4x4 biome cells, or empty when the palette has a single biome
+    pub(crate) biomes: Vec<u8>,
 }
 
 impl SubChunk {
@@ -96,6 +76,8 @@ impl SubChunk {
             blocks: vec![0u16; 4096],
             block_light: Some(vec![255u8; 2048]),  // 2 per block, all 15
             sky_light: Some(vec![255u8; 2048]),
+            biome_palette: vec![DEFAULT_BIOME.to_owned()],
+            biomes: Vec::new(),
         }
     }
 
@@ -105,6 +87,8 @@ impl SubChunk {
         let mut palette = None;
         let mut block_light = None;
         let mut sky_light = None;
+        let mut biome_palette = Vec::new();
+        let mut biomes = Vec::new();
         if let Tag::Compound(root) = data {
             if let Some(states_tag) = root.get("block_states") {
                 if let Tag::Compound(states) = states_tag {
@@ -119,6 +103,16 @@ impl SubChunk {
                         }
                     }
                 }
+                if let Some(Tag::Compound(biomes_tag)) = root.get("biomes") {
+                    if let Some(Tag::List(palette_list)) = biomes_tag.get("palette") {
+                        biome_palette = palette_list.iter()
+                            .filter_map(|b| b.as_string().ok().cloned())
+                            .collect();
+                    }
+                    if let Some(Tag::LongArray(data)) = biomes_tag.get("data") {
+                        biomes = Self::decode_biomes(biome_palette.len(), data);
+                    }
+                }
             } else {
                 if let Some(states_tag) = root.get("BlockStates") {
                     if let Tag::LongArray(states) = states_tag {
@@ -157,7 +151,11 @@ impl SubChunk {
             block_vals = Vec::<u16>::new();
         }
 
-        Self { palette: cloned_palette, blocks: block_vals, block_light, sky_light }
+        if biome_palette.is_empty() {
+            biome_palette.push(DEFAULT_BIOME.to_owned());
+        }
+
+        Self { palette: cloned_palette, blocks: block_vals, block_light, sky_light, biome_palette, biomes }
     }
 
     fn decode_state(mut val: u64, bits: u32, mask: u64, per_state: u32) -> Vec<u16> {
@@ -170,16 +168,62 @@ impl SubChunk {
     }
 
     fn decode_blocks(palette: &Vec<Tag>, states: &Vec<i64>) -> Vec<u16> {
-        let bits = (palette.len().ilog2() + 1).max(4);
+        let bits = bits_for(palette.len()).max(4);
         let mask = (1u32 << bits) - 1u32;
         let per_state = 64 / bits;
         let mut blocks = Vec::<u16>::new();
         for num in states {
             blocks.append(&mut Self::decode_state(*num as u64, bits, mask as u64, per_state));
         }
+        // The last long is padded when 4096 isn't a multiple of the entries per long
+        blocks.truncate(4096);
         blocks
     }
 
+    /// Biomes pack just enough bits for the palette, without the minimum that block states have
+    fn decode_biomes(palette_len: usize, data: &Vec<i64>) -> Vec<u8> {
+        if palette_len <= 1 {
+            return Vec::new();
+        }
+        let bits = bits_for(palette_len);
+        let per_long = 64 / bits;
+        let mask = (1u64 << bits) - 1;
+        let mut biomes = Vec::with_capacity(64);
+        for num in data {
+            let mut val = *num as u64;
+            for _ in 0..per_long {
+                if biomes.len() == 64 {
+                    break;
+                }
+                biomes.push((val & mask) as u8);
+                val >>= bits;
+            }
+        }
+        biomes.resize(64, 0);
+        biomes
+    }
+
+    /// Biome name at a 4x4x4 cell of this section
+    pub fn get_biome(&self, x: u8, y: u8, z: u8) -> &str {
+        let idx = self.biomes.get(((y as usize) * 4 + (z as usize)) * 4 + (x as usize)).copied().unwrap_or(0);
+        self.biome_palette.get(idx as usize).unwrap_or(&self.biome_palette[0])
+    }
+
+    /// Writes the biome paletted container, numbering biomes by the client's registry
+    fn write_biomes(&self, buf: &mut BytesMut, ids: &HashMap<String, i32>) {
+        let fallback = ids.get(DEFAULT_BIOME).copied().unwrap_or(0);
+        let palette: Vec<i32> = self.biome_palette.iter()
+            .map(|name| ids.get(name).copied().unwrap_or_else(|| {
+                warn!("unknown biome {name}, sending {DEFAULT_BIOME}");
+                fallback
+            }))
+            .collect();
+        let biomes: Vec<i32> = (0..64)
+            .map(|i| palette[self.biomes.get(i).copied().unwrap_or(0) as usize % palette.len()])
+            .collect();
+        write_paletted_container(buf, &biomes, 0, 3, bits_for(ids.len()));
+    }
+
     pub fn get_block(&self, x: u8, y: u8, z: u8) -> Option<Block> {
         if let Some(id) = self.blocks.get(((x as u16) + (z as u16) * 16 + (y as u16) * 256) as usize) {
             if let Some(block) = self.palette.get(*id as usize) {
@@ -191,9 +235,102 @@ impl SubChunk {
 }
 
 
+/// Lowest block of the overworld
+const MIN_Y: i32 = -64;
+const WORLD_HEIGHT: u32 = 384;
+const HEIGHTMAP_TYPES: [&str; 2] = ["MOTION_BLOCKING", "WORLD_SURFACE"];
+
+/// Blocks without a collision box, which `MOTION_BLOCKING` looks through unless they hold water
+fn blocks_motion(block: &Tag) -> bool {
+    let name = match block.get("Name").and_then(|n| n.as_string()) {
+        Ok(name) => name.trim_start_matches("minecraft:"),
+        Err(_) => return false
+    };
+    let waterlogged = block.get("Properties").and_then(|p| p.get("waterlogged")).and_then(|w| w.as_string())
+        .map(|w| w == "true").unwrap_or(false);
+    if waterlogged || name == "water" || name == "lava" || name == "bubble_column" {
+        return true;
+    }
+    !(is_air(name) || name.ends_with("grass") || name.ends_with("fern") || name.ends_with("sapling")
+        || name.ends_with("torch") || name.ends_with("sign") || name.ends_with("rail")
+        || name.ends_with("button") || name.ends_with("carpet") || name.ends_with("banner")
+        || name.ends_with("mushroom") || name.ends_with("roots") || name.ends_with("vines")
+        || matches!(name, "dandelion" | "poppy" | "blue_orchid" | "allium" | "azure_bluet" | "red_tulip"
+            | "orange_tulip" | "white_tulip" | "pink_tulip" | "oxeye_daisy" | "cornflower" | "lily_of_the_valley"
+            | "wither_rose" | "sunflower" | "lilac" | "rose_bush" | "peony" | "dead_bush" | "seagrass"
+            | "sugar_cane" | "wheat" | "carrots" | "potatoes" | "beetroots" | "redstone_wire" | "lever"
+            | "tripwire" | "tripwire_hook" | "vine" | "snow" | "cobweb" | "fire" | "soul_fire" | "light"))
+}
+
+fn is_air(name: &str) -> bool {
+    matches!(name, "air" | "cave_air" | "void_air")
+}
+
+/// Packs one height per column, each as many bits as the world height needs, without spanning longs
+fn pack_heightmap(heights: &[u32]) -> Vec<i64> {
+    let bits = u32::BITS - WORLD_HEIGHT.leading_zeros();
+    let per_long = (64 / bits) as usize;
+    heights.chunks(per_long)
+        .map(|hs| hs.iter().enumerate().fold(0u64, |l, (j, h)| l | ((*h as u64) << (bits as usize * j))) as i64)
+        .collect()
+}
+
+/// Block entity fields the client never reads, besides the position and type sent alongside them
+const HIDDEN_BLOCK_ENTITY_FIELDS: [&str; 7] = ["id", "x", "y", "z", "keepPacked", "Items", "LootTable"];
+
+/// Turns a saved block entity into its packed XZ, Y, type ID and data, as chunk packets carry them
+fn network_block_entity(entity: &Tag, v: ProtocolVersion) -> Option<(u8, i16, i32, Tag)> {
+    let id = entity.get("id").ok()?.as_string().ok()?;
+    // Block entities saved before 1.11 used names like `Chest`
+    let id = if id.contains(':') { id.clone() } else { format!("minecraft:{}", id.to_lowercase()) };
+    let type_id = match registry::block_entity_type_id(&id, v) {
+        Some(type_id) => type_id,
+        None => {
+            warn!("no block entity type {id} in {}", v.get_name());
+            return None;
+        }
+    };
+    let x = entity.get("x").ok()?.as_int().ok()?;
+    let y = entity.get("y").ok()?.as_int().ok()?;
+    let z = entity.get("z").ok()?.as_int().ok()?;
+
+    let mut data = entity.as_compound().ok()?.clone();
+    for field in HIDDEN_BLOCK_ENTITY_FIELDS {
+        data.remove(field);
+    }
+    if id == "minecraft:sign" && !data.contains_key("front_text") {
+        upgrade_sign_text(&mut data);
+    }
+    Some(((((x & 15) << 4) | (z & 15)) as u8, y as i16, type_id, Tag::Compound(data)))
+}
+
+/// Moves the text of a sign saved before 1.20 onto its front, where newer clients look for it
+fn upgrade_sign_text(data: &mut HashMap<String, Tag>) {
+    let empty = Tag::String("\"\"".to_owned());
+    let messages = (1..=4)
+        .map(|i| data.remove(&format!("Text{i}")).unwrap_or_else(|| empty.clone()))
+        .collect();
+    let mut front = HashMap::new();
+    front.insert("messages".to_owned(), Tag::List(messages));
+    front.insert("color".to_owned(), data.remove("Color").unwrap_or_else(|| Tag::String("black".to_owned())));
+    front.insert("has_glowing_text".to_owned(), data.remove("GlowingText").unwrap_or(Tag::Byte(0)));
+
+    let mut back = HashMap::new();
+    back.insert("messages".to_owned(), Tag::List(vec![empty; 4]));
+    back.insert("color".to_owned(), Tag::String("black".to_owned()));
+    back.insert("has_glowing_text".to_owned(), Tag::Byte(0));
+
+    data.insert("front_text".to_owned(), Tag::Compound(front));
+    data.insert("back_text".to_owned(), Tag::Compound(back));
+    data.insert("is_waxed".to_owned(), Tag::Byte(0));
+}
+
 #[derive(Debug, Clone)]
 pub struct Chunk {
-    subchunks: HashMap<i8, SubChunk>
+    subchunks: HashMap<i8, SubChunk>,
+    /// `Heightmaps` as saved with the chunk, if they were
+    heightmaps: Option<Tag>,
+    block_entities: Vec<Tag>
 }
 
 impl Chunk {
@@ -202,16 +339,22 @@ impl Chunk {
         for i in -4..24 {
             subchunks.insert(i, SubChunk::empty());
         }
-        Chunk { subchunks }
+        Chunk { subchunks, heightmaps: None, block_entities: Vec::new() }
     }
 
     pub fn new(data: Tag) -> Chunk {
         let mut subchunks = Vec::<Tag>::new();
+        let mut heightmaps = None;
+        let mut block_entities = Vec::new();
         if let Tag::Compound(root) = &data {
             if let Some(sections_tag) = root.get("sections") {
                 if let Tag::List(sections) = sections_tag {
                     subchunks = sections.clone();
                 }
+                heightmaps = root.get("Heightmaps").cloned();
+                if let Some(Tag::List(entities)) = root.get("block_entities") {
+                    block_entities = entities.clone();
+                }
             } else if let Some(level_tag) = root.get("Level") {
                 if let Tag::Compound(level) = level_tag {
                     if let Some(sections_tag) = level.get("Sections") {
@@ -219,6 +362,10 @@ impl Chunk {
                             subchunks = sections.clone();
                         }
                     }
+                    heightmaps = level.get("Heightmaps").cloned();
+                    if let Some(Tag::List(entities)) = level.get("TileEntities") {
+                        block_entities = entities.clone();
+                    }
                 }
             }
         }
@@ -226,7 +373,73 @@ impl Chunk {
         for subchunk in subchunks {
             subchunks_loaded.insert(subchunk.get("Y").unwrap().as_byte().unwrap(), SubChunk::new(&subchunk));
         }
-        Self { subchunks: subchunks_loaded }
+        Self { subchunks: subchunks_loaded, heightmaps, block_entities }
+    }
+
+    /// The heightmaps the client needs, taken from the saved chunk when they fit this world's height
+    pub fn heightmaps(&self) -> Tag {
+        let expected_len = pack_heightmap(&[0; 256]).len();
+        let saved = self.heightmaps.as_ref().and_then(|saved| {
+            let mut heightmaps = HashMap::new();
+            for key in HEIGHTMAP_TYPES {
+                match saved.get(key) {
+                    Ok(Tag::LongArray(data)) if data.len() == expected_len => {
+                        heightmaps.insert(key.to_owned(), Tag::LongArray(data.clone()));
+                    },
+                    _ => return None
+                }
+            }
+            Some(Tag::Compound(heightmaps))
+        });
+        saved.unwrap_or_else(|| self.compute_heightmaps())
+    }
+
+    /// Finds the highest block of each column, stored as its height above the bottom of the world
+    pub fn compute_heightmaps(&self) -> Tag {
+        let mut motion_blocking = vec![0u32; 256];
+        let mut world_surface = vec![0u32; 256];
+        let min_section = (MIN_Y >> 4) as i8;
+        let max_section = min_section + (WORLD_HEIGHT / 16) as i8 - 1;
+        for cy in (min_section..=max_section).rev() {
+            let section = match self.get_subchunk(cy) {
+                Some(section) => section,
+                None => continue
+            };
+            let solid: Vec<(bool, bool)> = section.palette.iter()
+                .map(|block| {
+                    let name = block.get("Name").and_then(|n| n.as_string()).map(|n| n.trim_start_matches("minecraft:")).unwrap_or("air");
+                    (!is_air(name), blocks_motion(block))
+                })
+                .collect();
+            if !solid.iter().any(|(surface, _)| *surface) {
+                continue;
+            }
+            for column in 0..256 {
+                if motion_blocking[column] != 0 {
+                    continue;
+                }
+                for y in (0..16).rev() {
+                    let idx = section.blocks.get(y * 256 + column).copied().unwrap_or(0);
+                    let (surface, motion) = solid.get(idx as usize).copied().unwrap_or((false, false));
+                    let height = ((cy as i32) * 16 + y as i32 - MIN_Y + 1) as u32;
+                    if surface && world_surface[column] == 0 {
+                        world_surface[column] = height;
+                    }
+                    if motion {
+                        motion_blocking[column] = height;
+                        break;
+                    }
+                }
+            }
+            if motion_blocking.iter().all(|h| *h != 0) {
+                break;
+            }
+        }
+
+        let mut heightmaps = HashMap::new();
+        heightmaps.insert("MOTION_BLOCKING".to_owned(), Tag::LongArray(pack_heightmap(&motion_blocking)));
+        heightmaps.insert("WORLD_SURFACE".to_owned(), Tag::LongArray(pack_heightmap(&world_surface)));
+        Tag::Compound(heightmaps)
     }
 
     pub fn get_subchunk(&self, y: i8) -> Option<&SubChunk> {
@@ -245,6 +458,8 @@ impl Chunk {
     }
 
     pub fn serialize_to_chunk_packet(&self, output: &mut BytesMut, v: ProtocolVersion) {
+        let biome_ids = registry::biome_ids(v);
+        let block_states = registry::block_states(v);
         let mut buf = BytesMut::new();
         let mut skylight = 0;
         let mut skylight_data = HashMap::new();
@@ -253,8 +468,6 @@ impl Chunk {
         let mut blocklight_data = HashMap::new();
         let mut blocklight_empty = 0;
         for cy in -4..20 {
-            let mut full_block_count = 0;
-            let mut blocks = vec![0u16; 4096];
 
             // Light
             let section_opt = self.get_subchunk(cy);
@@ -272,109 +485,24 @@ impl Chunk {
                     skylight_empty |= 1u64 << ((cy as i64) + 4);
                 }
 
-                for y in 0..16 {
-                    for z in 0..16 {
-                        for x in 0..16 {
-                            let idx = (((y as u16) * 16 + (z as u16)) * 16) + (x as u16);
-                            let block = section.get_block(x, y, z).unwrap_or_else(|| {
-                                let mut tag = HashMap::new();
-                                tag.insert("Name".to_owned(), Tag::String("minecraft:air".to_owned()));
-                                Block { data: Tag::Compound(tag) }
-                            });
-                            let block_name = block.name().cloned().unwrap_or_else(|| "minecraft:air".to_string());
-                            if block_name != "minecraft:air" {
-                                full_block_count += 1;
-                            }
-
-                            let block_def = BLOCKS.get(&block_name);
-                            let mut block_id = 0;
-                            if block_name != "minecraft:air" {
-                                if let Some(block_type_val) = block_def {
-                                    let block_type: BlockType = serde_json::from_value(block_type_val.clone()).unwrap();
-                                    let mut default = None;
-                                    for cond in block_type.states {
-                                        if let Some(def) = cond.default {
-                                            if def {
-                                                default = Some(cond.id);
-                                            }
-                                        }
-
-                                        let mut pm = Vec::new();
-                                        if let Some(props) = cond.properties {
-                                            for (k, v) in props {
-                                                if let Some(p) = block.get_property(&k) {
-                                                    pm.push(*(p.as_string().cloned().unwrap_or_else(|_| match p.as_byte().unwrap_or_else(|_| -1i8) {
-                                                        0 => "true",
-                                                        1 => "false",
-                                                        _ => "???"
-                                                    }.to_owned())) == v);
-                                                } else {
-                                                    pm.push(false);
-                                                }
-                                            }
-                                        }
-
-                                        if pm.len() > 0 && pm.iter().all(|i| *i) {
-                                            block_id = cond.id;
-                                            break;
-                                        };
-                                    }
-
-                                    if block_id == 0 {
-                                        block_id = default.unwrap_or_else(|| 0);
-                                    }
-                                }
-                                blocks[idx as usize] = block_id as u16;
-                            }
-                        }
+                // Each palette entry is looked up once for the whole section
+                let states: Vec<i32> = section.palette.iter()
+                    .map(|block| registry::block_state_id(&Block::from_nbt(block), v))
+                    .collect();
+                let air: Vec<bool> = section.palette.iter().map(|block| Block::from_nbt(block).is_air()).collect();
+                let mut blocks = vec![0i32; 4096];
+                let mut full_block_count = 0u16;
+                for (i, block) in blocks.iter_mut().enumerate() {
+                    let idx = section.blocks.get(i).copied().unwrap_or(0) as usize;
+                    *block = states.get(idx).copied().unwrap_or(0);
+                    if !air.get(idx).copied().unwrap_or(true) {
+                        full_block_count += 1;
                     }
                 }
 
                 buf.put_u16(full_block_count);
-
-                if *blocks.iter().max().unwrap() == 0u16 {
-                    buf.put_bytes(0, 3);  // bpe, air, empty array
-                } else {
-                    let mut palette: Vec<&u16> = blocks.iter().unique().sorted().collect();
-                    // println!("palette: {:?}", palette);
-                    let bpe = ((palette.len() as f32).log2().ceil() as u32).max(4);
-                    println!("{} for {}", bpe, palette.len());
-                    println!("{:?}", palette);
-                    // println!("bpe={bpe} for {}", palette.len());
-                    if bpe > 15 {
-                        panic!("tried to serialize a chunk with a bpe > 15");
-                    }
-                    let elems_per_num = 64 / bpe;
-                    // println!("elems_per_num={elems_per_num}");
-                    let num_elems = (4096f32 / (elems_per_num as f32)).ceil() as u32;
-                    // println!("num_elems={num_elems}");
-                    let mut data = vec![0u64; num_elems as usize];
-                    for i in 0..num_elems {
-                        let mut e = 0;
-                        for j in 0..elems_per_num {
-                            if i * elems_per_num + j >= 4096 {
-                                break;
-                            }
-
-                            e |= ((palette.iter().position(|&b| *b == blocks[(i * elems_per_num + j) as usize]).unwrap() as u64) << (bpe * j));
-                        }
-                        data[i as usize] = e;
-                    }
-
-                    buf.put_u8(bpe as u8);
-                    write_varint(&mut buf, palette.len() as i32);
-                    for p in palette {
-                        write_varint(&mut buf, *p as i32);
-                    }
-                    write_varint(&mut buf, data.len() as i32);
-                    for l in data {
-                        buf.put_u64(l);
-                    }
-                }
-
-                buf.put_u8(0);  // biomes NYI, TODO
-                write_varint(&mut buf, 39);  // plains
-                write_varint(&mut buf, 0);  // empty array
+                write_paletted_container(&mut buf, &blocks, 4, 8, bits_for(block_states.len()));
+                section.write_biomes(&mut buf, biome_ids);
             } else {
                 blocklight_empty |= 1u64 << ((cy as u64) + 4);
                 skylight_empty |= 1u64 << ((cy as u64) + 4);
@@ -384,7 +512,16 @@ impl Chunk {
         write_varint(output, buf.len() as i32);
         output.put(buf);
 
-        write_varint(output, 0);  // no block entities
+        let block_entities: Vec<(u8, i16, i32, Tag)> = self.block_entities.iter()
+            .filter_map(|entity| network_block_entity(entity, v))
+            .collect();
+        write_varint(output, block_entities.len() as i32);
+        for (xz, y, type_id, data) in block_entities {
+            output.put_u8(xz);
+            output.put_i16(y);
+            write_varint(output, type_id);
+            data.serialize(output, v >= ProtocolVersion::V1_20_2);
+        }
 
         if v <= ProtocolVersion::V1_19_4 {
             output.put_u8(1);  // trust edges
@@ -422,8 +559,10 @@ impl Chunk {
 mod tests {
     use std::io::Cursor;
     use std::path::Path;
+    use std::collections::HashMap;
     use bytes::BytesMut;
-    use crate::Region;
+    use crate::{Region, Tag};
+    use crate::chunk::{Chunk, SubChunk};
     use crate::server::version::ProtocolVersion;
 
     #[test]
@@ -440,4 +579,59 @@ mod tests {
         }
         std::fs::write(Path::new("r.0.0.chunks"), output).unwrap();
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn section_biomes() {
+        let mut biomes = HashMap::new();
+        biomes.insert("palette".to_owned(), Tag::List(vec![
+            Tag::String("minecraft:plains".to_owned()),
+            Tag::String("minecraft:desert".to_owned())
+        ]));
+        // One bit per cell; only the first cell is desert
+        biomes.insert("data".to_owned(), Tag::LongArray(vec![1]));
+        let mut section = HashMap::new();
+        section.insert("biomes".to_owned(), Tag::Compound(biomes));
+        section.insert("block_states".to_owned(), Tag::Compound(HashMap::new()));
+        let section = SubChunk::new(&Tag::Compound(section));
+        assert_eq!(section.get_biome(0, 0, 0), "minecraft:desert");
+        assert_eq!(section.get_biome(1, 0, 0), "minecraft:plains");
+
+        let ids = HashMap::from([("minecraft:desert".to_owned(), 14), ("minecraft:plains".to_owned(), 39)]);
+        let mut buf = BytesMut::new();
+        section.write_biomes(&mut buf, &ids);
+        // The network palette is sorted by ID, so desert comes first this time
+        assert_eq!(&buf[..], &[1, 2, 14, 39, 1, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfe]);
+    }
+
+    #[test]
+    fn computed_heightmaps() {
+        // Section 0 is all stone and topped by a torch at x=0, z=0
+        let mut states = HashMap::new();
+        states.insert("palette".to_owned(), Tag::List(vec![
+            Tag::Compound(HashMap::from([("Name".to_owned(), Tag::String("minecraft:stone".to_owned()))]))
+        ]));
+        let stone = HashMap::from([("Y".to_owned(), Tag::Byte(0)), ("block_states".to_owned(), Tag::Compound(states))]);
+        let mut states = HashMap::new();
+        states.insert("palette".to_owned(), Tag::List(vec![
+            Tag::Compound(HashMap::from([("Name".to_owned(), Tag::String("minecraft:air".to_owned()))])),
+            Tag::Compound(HashMap::from([("Name".to_owned(), Tag::String("minecraft:torch".to_owned()))]))
+        ]));
+        // Four bits per block, so the torch is the lowest nibble of the first long
+        let mut data = vec![0i64; 256];
+        data[0] = 1;
+        states.insert("data".to_owned(), Tag::LongArray(data));
+        let torch = HashMap::from([("Y".to_owned(), Tag::Byte(1)), ("block_states".to_owned(), Tag::Compound(states))]);
+        let chunk = Chunk::new(Tag::Compound(HashMap::from([
+            ("sections".to_owned(), Tag::List(vec![Tag::Compound(stone), Tag::Compound(torch)]))
+        ])));
+
+        let heightmaps = chunk.heightmaps();
+        let motion = heightmaps.get("MOTION_BLOCKING").unwrap().as_long_array().unwrap();
+        let surface = heightmaps.get("WORLD_SURFACE").unwrap().as_long_array().unwrap();
+        assert_eq!(motion.len(), 37);
+        // Heights count from y=-64, and a column's first entry is in the lowest bits
+        assert_eq!(motion[0] & 0x1ff, 80);
+        assert_eq!(surface[0] & 0x1ff, 81);
+        assert_eq!((surface[0] >> 9) & 0x1ff, 80);
+    }
+}
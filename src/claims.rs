@@ -1,16 +1,451 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::UNIX_EPOCH;
+use anyhow::{anyhow, Context};
+use log::{info, warn};
 use serde_derive::{Serialize, Deserialize};
 use uuid::Uuid;
+use crate::server::world::{region_dir, WorldPreview};
 
+
+/// Who may export a claim
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportPolicy {
+    /// The owner and anyone with build trust
+    #[default]
+    AllowTrusted,
+    OwnerOnly,
+    /// Nobody, not even the owner
+    Deny
+}
+
+impl ExportPolicy {
+    pub fn from_name(name: &str) -> Option<ExportPolicy> {
+        match name {
+            "allow_trusted" => Some(ExportPolicy::AllowTrusted),
+            "owner_only" => Some(ExportPolicy::OwnerOnly),
+            "deny" => Some(ExportPolicy::Deny),
+            _ => None
+        }
+    }
+}
+
+/// GriefPrevention-style trust lists
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TrustLists {
+    #[serde(default)]
+    pub(crate) builders: Vec<Uuid>,
+    #[serde(default)]
+    pub(crate) containers: Vec<Uuid>,
+    #[serde(default)]
+    pub(crate) accessors: Vec<Uuid>,
+    #[serde(default)]
+    pub(crate) managers: Vec<Uuid>
+}
+
+/// A rectangular claim; corners are inclusive block coordinates
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Claim {
+    pub(crate) id: i64,
+    pub(crate) owner: Uuid,
+    pub(crate) world: String,
+    pub(crate) dimension: String,
     pub(crate) x1: i32,
     pub(crate) z1: i32,
     pub(crate) x2: i32,
     pub(crate) z2: i32,
-    pub(crate) timestamp: u64
+    #[serde(default)]
+    pub(crate) timestamp: u64,
+    #[serde(default)]
+    pub(crate) trust: TrustLists,
+    #[serde(default)]
+    pub(crate) export_policy: ExportPolicy,
+    /// Subclaims share the owner of their parent
+    #[serde(default)]
+    pub(crate) subclaims: Vec<Claim>,
+    /// Subclaims only: don't inherit the parent's trust lists
+    #[serde(default)]
+    pub(crate) inherit_nothing: bool
+}
+
+impl Claim {
+    /// Whether a player has build trust here, optionally through the parent claim
+    pub fn is_builder(&self, player: Uuid, parent: Option<&Claim>) -> bool {
+        if self.trust.builders.contains(&player) {
+            return true;
+        }
+        match parent {
+            Some(parent) if !self.inherit_nothing => parent.trust.builders.contains(&player),
+            _ => false
+        }
+    }
+
+    pub fn can_export(&self, player: Uuid, parent: Option<&Claim>) -> bool {
+        match self.export_policy {
+            ExportPolicy::Deny => false,
+            ExportPolicy::OwnerOnly => self.owner == player,
+            ExportPolicy::AllowTrusted => self.owner == player || self.is_builder(player, parent)
+        }
+    }
+
+    /// This claim if the player can export it, otherwise any subclaims they can export.
+    /// A returned claim may still contain subclaims the player can't export; see `can_export_chunk`.
+    pub fn exportable_by(&self, player: Uuid) -> Vec<Claim> {
+        if self.can_export(player, None) {
+            return vec![self.clone()];
+        }
+        self.subclaims.iter().filter(|c| c.can_export(player, Some(self))).cloned().collect()
+    }
+
+    /// Whether a player may export a chunk of this claim. Being able to export the claim isn't enough
+    /// if the chunk touches a subclaim they can't export, since its policy wins inside the subclaim.
+    pub fn can_export_chunk(&self, player: Uuid, world: &str, chunk_x: i32, chunk_z: i32) -> bool {
+        if !self.intersects_chunk(world, chunk_x, chunk_z) {
+            return false;
+        }
+        let mut allowed = self.can_export(player, None);
+        for subclaim in self.subclaims.iter().filter(|c| c.intersects_chunk(world, chunk_x, chunk_z)) {
+            if !subclaim.can_export(player, Some(self)) {
+                return false;
+            }
+            allowed = true;
+        }
+        allowed
+    }
+
+    /// Whether any part of a chunk lies inside this claim
+    pub fn intersects_chunk(&self, world: &str, chunk_x: i32, chunk_z: i32) -> bool {
+        let (x, z) = (chunk_x * 16, chunk_z * 16);
+        self.world == world
+            && x <= self.x1.max(self.x2) && x + 15 >= self.x1.min(self.x2)
+            && z <= self.z1.max(self.z2) && z + 15 >= self.z1.min(self.z2)
+    }
+
+    /// The claim's chunks the player may export, for an in-game preview. The whole claim is included;
+    /// only the chunks within the player's view distance are streamed at a time.
+    /// Chunks are checked against all of `claims`, so ones covered by a subclaim the player can't export are left out.
+    pub fn preview(&self, server_dir: &Path, player: Uuid, claims: &dyn ClaimProvider) -> WorldPreview {
+        let (center_x, center_z) = ((self.x1 + self.x2) / 2, (self.z1 + self.z2) / 2);
+        let (min_x, max_x) = (self.x1.min(self.x2) >> 4, self.x1.max(self.x2) >> 4);
+        let (min_z, max_z) = (self.z1.min(self.z2) >> 4, self.z1.max(self.z2) >> 4);
+        WorldPreview {
+            region_dir: region_dir(&server_dir.join(&self.world), &self.dimension),
+            // Spectators can fly through anything, so above most terrain is fine
+            spawn: (center_x as f64 + 0.5, 128.0, center_z as f64 + 0.5),
+            chunks: (min_x..=max_x)
+                .flat_map(|x| (min_z..=max_z).map(move |z| (x, z)))
+                .filter(|(x, z)| claims.can_export_chunk(player, &self.world, *x, *z))
+                .collect()
+        }
+    }
+
+    fn find_mut(&mut self, id: i64) -> Option<&mut Claim> {
+        if self.id == id {
+            return Some(self);
+        }
+        self.subclaims.iter_mut().find(|c| c.id == id)
+    }
+}
+
+pub trait ClaimProvider {
+    /// Gets all claims owned by a player
+    fn get_claims(&self, owner: Uuid) -> Vec<Claim>;
+    /// Gets all claims and subclaims a player is allowed to export
+    fn get_exportable_claims(&self, player: Uuid) -> Vec<Claim>;
+    /// Whether a chunk is part of a claim the player may export, leaving out subclaims they can't
+    fn can_export_chunk(&self, player: Uuid, world: &str, chunk_x: i32, chunk_z: i32) -> bool;
+    /// Sets the export policy of a claim or subclaim, which only its owner may do
+    fn set_export_policy(&self, owner: Uuid, id: i64, policy: ExportPolicy) -> anyhow::Result<()>;
+}
+
+pub type SharedClaimProvider = Arc<dyn ClaimProvider + Send + Sync>;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ClaimFile {
+    #[serde(default)]
+    claims: Vec<Claim>
+}
+
+/// Claims kept in memory, loaded from a flat JSON or TOML file.
+/// Without a file, policy changes only last until a restart.
+pub struct FileClaimProvider {
+    claims: RwLock<Vec<Claim>>,
+    path: Option<PathBuf>
+}
+
+impl FileClaimProvider {
+    pub fn new(claims: Vec<Claim>) -> FileClaimProvider {
+        FileClaimProvider { claims: RwLock::new(claims), path: None }
+    }
+
+    fn is_toml(path: &Path) -> bool {
+        path.extension().and_then(|e| e.to_str()) == Some("toml")
+    }
+
+    /// Loads a `claims` list from a file, as TOML if it has a `.toml` extension and JSON otherwise
+    pub fn load(path: &Path) -> anyhow::Result<FileClaimProvider> {
+        let data = fs::read_to_string(path).with_context(|| format!("could not read {}", path.display()))?;
+        let file: ClaimFile = if Self::is_toml(path) {
+            toml::from_str(&data)?
+        } else {
+            serde_json::from_str(&data)?
+        };
+        Ok(FileClaimProvider { claims: RwLock::new(file.claims), path: Some(path.to_owned()) })
+    }
+
+    fn save(&self, claims: &[Claim]) -> anyhow::Result<()> {
+        if let Some(path) = &self.path {
+            let file = ClaimFile { claims: claims.to_vec() };
+            let data = if Self::is_toml(path) {
+                toml::to_string(&file)?
+            } else {
+                serde_json::to_string_pretty(&file)?
+            };
+            fs::write(path, data).with_context(|| format!("could not write {}", path.display()))?;
+        }
+        Ok(())
+    }
+}
+
+impl ClaimProvider for FileClaimProvider {
+    fn get_claims(&self, owner: Uuid) -> Vec<Claim> {
+        self.claims.read().unwrap().iter().filter(|c| c.owner == owner).cloned().collect()
+    }
+
+    fn get_exportable_claims(&self, player: Uuid) -> Vec<Claim> {
+        self.claims.read().unwrap().iter().flat_map(|c| c.exportable_by(player)).collect()
+    }
+
+    fn can_export_chunk(&self, player: Uuid, world: &str, chunk_x: i32, chunk_z: i32) -> bool {
+        self.claims.read().unwrap().iter().any(|c| c.can_export_chunk(player, world, chunk_x, chunk_z))
+    }
+
+    fn set_export_policy(&self, owner: Uuid, id: i64, policy: ExportPolicy) -> anyhow::Result<()> {
+        let mut claims = self.claims.write().unwrap();
+        let claim = claims.iter_mut()
+            .filter(|c| c.owner == owner)
+            .find_map(|c| c.find_mut(id))
+            .ok_or(anyhow!("you don't own a claim with ID {}", id))?;
+        claim.export_policy = policy;
+        self.save(&claims)
+    }
+}
+
+/// Bukkit names other dimensions after the main world, e.g. `world_nether`
+fn dimension_for_world(world: &str) -> String {
+    if world.ends_with("_nether") {
+        "minecraft:the_nether".to_owned()
+    } else if world.ends_with("_the_end") {
+        "minecraft:the_end".to_owned()
+    } else {
+        "minecraft:overworld".to_owned()
+    }
+}
+
+/// Parses the small YAML subset GriefPrevention writes: `Key: value` pairs, where a value
+/// can also be an inline empty list or a list of `- item` lines
+fn parse_claim_yaml(data: &str) -> HashMap<String, Vec<String>> {
+    let unquote = |s: &str| s.trim().trim_matches(|c| c == '\'' || c == '"').to_owned();
+
+    let mut values: HashMap<String, Vec<String>> = HashMap::new();
+    let mut current = None;
+    for line in data.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(item) = line.strip_prefix('-') {
+            if let Some(key) = &current {
+                values.get_mut(key).unwrap().push(unquote(item));
+            }
+        } else if let Some((key, value)) = line.split_once(':') {
+            let key = key.trim().to_owned();
+            let value = unquote(value);
+            values.insert(key.clone(), if value.is_empty() || value == "[]" { vec![] } else { vec![value] });
+            current = Some(key);
+        }
+    }
+    values
+}
+
+/// Parses a `world;x;y;z` corner into the world and horizontal coordinates
+fn parse_corner(corner: &str) -> anyhow::Result<(String, i32, i32)> {
+    let parts: Vec<&str> = corner.split(';').collect();
+    if parts.len() != 4 {
+        return Err(anyhow!("invalid corner {}", corner));
+    }
+    Ok((parts[0].to_owned(), parts[1].trim().parse()?, parts[3].trim().parse()?))
+}
+
+/// Reads a GriefPrevention claim file, returning the claim and its parent's ID (-1 if none).
+/// Subclaims get their owner from the parent; admin claims have no owner, so they're skipped.
+pub fn parse_griefprevention_claim(id: i64, data: &str, timestamp: u64) -> anyhow::Result<Option<(Claim, i64)>> {
+    let values = parse_claim_yaml(data);
+    let get = |key: &str| values.get(key).and_then(|v| v.first()).map(|v| v.as_str());
+    // Entries like "public" or permission groups can't be matched to a player
+    let get_players = |key: &str| values.get(key)
+        .map(|v| v.iter().filter_map(|p| Uuid::parse_str(p).ok()).collect())
+        .unwrap_or_default();
+
+    let parent: i64 = get("Parent Claim ID").unwrap_or("-1").parse()?;
+    let owner = match get("Owner") {
+        Some(owner) => Uuid::parse_str(owner)?,
+        None if parent != -1 => Uuid::nil(),
+        None => return Ok(None)
+    };
+
+    let (world, x1, z1) = parse_corner(get("Lesser Boundary Corner").ok_or(anyhow!("missing lesser corner"))?)?;
+    let (_, x2, z2) = parse_corner(get("Greater Boundary Corner").ok_or(anyhow!("missing greater corner"))?)?;
+
+    Ok(Some((Claim {
+        id,
+        owner,
+        dimension: dimension_for_world(&world),
+        world,
+        x1,
+        z1,
+        x2,
+        z2,
+        timestamp,
+        trust: TrustLists {
+            builders: get_players("Builders"),
+            containers: get_players("Containers"),
+            accessors: get_players("Accessors"),
+            managers: get_players("Managers")
+        },
+        export_policy: ExportPolicy::default(),
+        subclaims: vec![],
+        inherit_nothing: get("inheritNothing") == Some("true")
+    }, parent)))
 }
 
-pub fn get_claims(_: Uuid) -> Vec<Claim> {
-    // TODO: this is a demo value
-    vec![Claim {x1: 0, z1: 13, x2: 17, z2: 54, timestamp: 0}, Claim {x1: -20, z1: -30, x2: -4, z2: -7, timestamp: 0}]
+/// Imports all claims from GriefPrevention's flat-file storage in a server directory
+pub fn import_griefprevention(server_dir: &Path) -> anyhow::Result<Vec<Claim>> {
+    let dir = server_dir.join("plugins/GriefPreventionData/ClaimData");
+    let mut claims = Vec::new();
+    let mut subclaims = Vec::new();
+    for entry in fs::read_dir(&dir).with_context(|| format!("could not read {}", dir.display()))? {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("yml") {
+            continue;
+        }
+        // Claim files are named after their ID; anything else (like _nextClaimID) isn't a claim
+        let id = match path.file_stem().and_then(|s| s.to_str()).and_then(|s| s.parse().ok()) {
+            Some(id) => id,
+            None => continue
+        };
+
+        let timestamp = fs::metadata(&path)?.modified()?.duration_since(UNIX_EPOCH)?.as_secs();
+        match parse_griefprevention_claim(id, &fs::read_to_string(&path)?, timestamp) {
+            Ok(Some((claim, -1))) => claims.push(claim),
+            Ok(Some((subclaim, parent))) => subclaims.push((subclaim, parent)),
+            Ok(None) => {},
+            Err(e) => warn!("skipping claim {}: {}", path.display(), e)
+        }
+    }
+
+    for (mut subclaim, parent_id) in subclaims {
+        match claims.iter_mut().find(|c| c.id == parent_id) {
+            Some(parent) => {
+                subclaim.owner = parent.owner;
+                parent.subclaims.push(subclaim);
+            },
+            None => warn!("skipping subclaim {} of missing claim {}", subclaim.id, parent_id)
+        }
+    }
+    info!("Imported {} claims from GriefPrevention", claims.len());
+    Ok(claims)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use uuid::Uuid;
+    use crate::claims::{parse_griefprevention_claim, ClaimProvider, ExportPolicy, FileClaimProvider};
+
+    #[test]
+    fn test_griefprevention_claim() {
+        let data = "Lesser Boundary Corner: world_nether;-226;0;-230\n\
+                    Greater Boundary Corner: world_nether;-212;319;-210\n\
+                    Owner: 069a79f4-44e9-4726-a5be-fca90e38aaf5\n\
+                    Builders:\n\
+                    - 853c80ef-3c37-49fd-aa49-938b674adae6\n\
+                    Containers: []\n\
+                    Accessors: []\n\
+                    Managers: []\n\
+                    Parent Claim ID: -1\n\
+                    inheritNothing: false\n";
+        let (mut claim, parent) = parse_griefprevention_claim(12, data, 0).unwrap().unwrap();
+        assert_eq!(parent, -1);
+        assert_eq!(claim.owner, Uuid::parse_str("069a79f4-44e9-4726-a5be-fca90e38aaf5").unwrap());
+        assert_eq!(claim.dimension, "minecraft:the_nether");
+        assert_eq!((claim.x1, claim.z1, claim.x2, claim.z2), (-226, -230, -212, -210));
+
+        let builder = Uuid::parse_str("853c80ef-3c37-49fd-aa49-938b674adae6").unwrap();
+        assert!(claim.can_export(builder, None));
+        claim.export_policy = ExportPolicy::OwnerOnly;
+        assert!(!claim.can_export(builder, None));
+        assert!(claim.can_export(claim.owner, None));
+
+        let subclaim = data.replace("Parent Claim ID: -1", "Parent Claim ID: 4").replace("Owner: 069a79f4-44e9-4726-a5be-fca90e38aaf5\n", "");
+        let (subclaim, parent) = parse_griefprevention_claim(13, &subclaim, 0).unwrap().unwrap();
+        assert_eq!(parent, 4);
+        assert!(subclaim.owner.is_nil());
+    }
+
+    #[test]
+    fn denied_subclaims_are_left_out_of_exports() {
+        let data = "Lesser Boundary Corner: world;0;0;0\n\
+                    Greater Boundary Corner: world;63;319;63\n\
+                    Owner: 069a79f4-44e9-4726-a5be-fca90e38aaf5\n\
+                    Builders:\n\
+                    - 853c80ef-3c37-49fd-aa49-938b674adae6\n\
+                    Parent Claim ID: -1\n";
+        let (mut claim, _) = parse_griefprevention_claim(1, data, 0).unwrap().unwrap();
+        let owner = claim.owner;
+        let builder = Uuid::parse_str("853c80ef-3c37-49fd-aa49-938b674adae6").unwrap();
+        // A subclaim covering chunk (0, 0)
+        let mut subclaim = claim.clone();
+        (subclaim.id, subclaim.x2, subclaim.z2) = (2, 15, 15);
+        claim.subclaims.push(subclaim);
+        let claims = FileClaimProvider::new(vec![claim]);
+
+        assert!(claims.can_export_chunk(builder, "world", 0, 0));
+        claims.set_export_policy(owner, 2, ExportPolicy::OwnerOnly).unwrap();
+        assert!(!claims.can_export_chunk(builder, "world", 0, 0));
+        assert!(claims.can_export_chunk(builder, "world", 1, 1));
+        assert!(claims.can_export_chunk(owner, "world", 0, 0));
+        // The preview shows the same chunks, without anything around the claim
+        let preview = claims.get_claims(owner)[0].preview(Path::new("server"), builder, &claims);
+        assert_eq!(preview.chunks.len(), 15);
+        assert!(!preview.chunks.contains(&(0, 0)) && preview.chunks.contains(&(3, 3)));
+
+        claims.set_export_policy(owner, 2, ExportPolicy::Deny).unwrap();
+        assert!(!claims.can_export_chunk(owner, "world", 0, 0));
+        assert!(claims.can_export_chunk(owner, "world", 0, 1));
+        assert!(!claims.can_export_chunk(owner, "world", 4, 0));
+
+        // Only the subclaim is exportable
+        claims.set_export_policy(owner, 1, ExportPolicy::Deny).unwrap();
+        claims.set_export_policy(owner, 2, ExportPolicy::AllowTrusted).unwrap();
+        assert!(claims.can_export_chunk(builder, "world", 0, 0));
+        assert!(!claims.can_export_chunk(builder, "world", 1, 1));
+    }
+
+    #[test]
+    fn previews_cover_the_whole_claim() {
+        let data = "Lesser Boundary Corner: world;-512;0;-512\n\
+                    Greater Boundary Corner: world;511;319;511\n\
+                    Owner: 069a79f4-44e9-4726-a5be-fca90e38aaf5\n\
+                    Parent Claim ID: -1\n";
+        let (claim, _) = parse_griefprevention_claim(1, data, 0).unwrap().unwrap();
+        let owner = claim.owner;
+        let claims = FileClaimProvider::new(vec![claim.clone()]);
+        let preview = claim.preview(Path::new("server"), owner, &claims);
+        assert_eq!(preview.chunks.len(), 64 * 64);
+        assert!(preview.chunks.contains(&(-32, -32)) && preview.chunks.contains(&(31, 31)));
+    }
 }
\ No newline at end of file
@@ -3,15 +3,20 @@ use warp::{Filter, Rejection, Reply};
 use warp::http::{HeaderMap, HeaderValue, Response, StatusCode};
 use warp::hyper::Body;
 use crate::handlers;
-use crate::models::{ExportOptions, SharedAuthManager};
+use crate::claims::SharedClaimProvider;
+use crate::models::{CreateCodeQuery, ExportOptions, SelectionOptions, SharedAuthManager};
+use crate::roles::{SharedAuditLog, SharedRoles};
+use uuid::Uuid;
 
-pub fn routes(manager: SharedAuthManager) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+pub fn routes(manager: SharedAuthManager, claims: SharedClaimProvider, roles: SharedRoles, audit: SharedAuditLog) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
     let mut headers = HeaderMap::new();
     headers.insert("Access-Control-Allow-Origin", HeaderValue::from_static("*"));
 
     preflight_options()
-        .or(export())
-        .or(poll_login(manager.clone()))
+        .or(export(manager.clone(), claims.clone()))
+        .or(admin_export(manager.clone(), roles.clone(), audit.clone()))
+        .or(admin_claims(manager.clone(), claims.clone(), roles.clone(), audit))
+        .or(poll_login(manager.clone(), claims, roles))
         .or(create_code(manager))
 
         .with(warp::reply::with::headers(headers))
@@ -21,6 +26,28 @@ fn with_manager(manager: SharedAuthManager) -> impl Filter<Extract = (SharedAuth
     warp::any().map(move || manager.clone())
 }
 
+fn with_claims(claims: SharedClaimProvider) -> impl Filter<Extract = (SharedClaimProvider,), Error = Infallible> + Clone {
+    warp::any().map(move || claims.clone())
+}
+
+fn with_roles(roles: SharedRoles) -> impl Filter<Extract = (SharedRoles,), Error = Infallible> + Clone {
+    warp::any().map(move || roles.clone())
+}
+
+fn with_audit(audit: SharedAuditLog) -> impl Filter<Extract = (SharedAuditLog,), Error = Infallible> + Clone {
+    warp::any().map(move || audit.clone())
+}
+
+/// The code a staff member verified with, sent as a bearer token so it stays out of URLs and request logs
+fn staff_code() -> impl Filter<Extract = (String,), Error = Rejection> + Clone {
+    warp::header::<String>("authorization").and_then(|auth: String| async move {
+        match auth.strip_prefix("Bearer ") {
+            Some(code) => Ok(code.to_owned()),
+            None => Err(warp::reject())
+        }
+    })
+}
+
 /// For CORS handling
 pub fn preflight_options() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
     warp::any()
@@ -34,24 +61,52 @@ pub fn preflight_options() -> impl Filter<Extract = (impl Reply,), Error = Rejec
         })
 }
 
-pub fn export() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+pub fn export(manager: SharedAuthManager, claims: SharedClaimProvider) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
     warp::path!("export")
         .and(warp::post())
         .and(warp::body::content_length_limit(1024 * 1024))
         .and(warp::body::json::<ExportOptions>())
+        .and(with_manager(manager))
+        .and(with_claims(claims))
         .and_then(handlers::export_chunks)
 }
 
+pub fn admin_export(manager: SharedAuthManager, roles: SharedRoles, audit: SharedAuditLog) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("admin" / "export")
+        .and(warp::post())
+        .and(staff_code())
+        .and(warp::body::content_length_limit(1024 * 1024))
+        .and(warp::body::json::<SelectionOptions>())
+        .and(with_manager(manager))
+        .and(with_roles(roles))
+        .and(with_audit(audit))
+        .and_then(handlers::admin_export)
+}
+
+pub fn admin_claims(manager: SharedAuthManager, claims: SharedClaimProvider, roles: SharedRoles, audit: SharedAuditLog) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("admin" / "claims" / Uuid)
+        .and(warp::get())
+        .and(staff_code())
+        .and(with_manager(manager))
+        .and(with_claims(claims))
+        .and(with_roles(roles))
+        .and(with_audit(audit))
+        .and_then(handlers::admin_claims)
+}
+
 pub fn create_code(manager: SharedAuthManager) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
     warp::path!("code" / "create")
         .and(warp::get())
+        .and(warp::query::<CreateCodeQuery>())
         .and(with_manager(manager))
         .and_then(handlers::create_code)
 }
 
-pub fn poll_login(manager: SharedAuthManager) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+pub fn poll_login(manager: SharedAuthManager, claims: SharedClaimProvider, roles: SharedRoles) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
     warp::path!("code" / String / "poll")
         .and(warp::get())
         .and(with_manager(manager))
+        .and(with_claims(claims))
+        .and(with_roles(roles))
         .and_then(handlers::poll_login)
 }
@@ -1,27 +1,28 @@
 use std::collections::HashMap;
-use crate::models::{ExportOptions, SharedAuthManager};
+use crate::models::{AuthEvent, CreateCodeQuery, ExportOptions, SelectionOptions, SharedAuthManager};
 use clap::Parser;
 use std::convert::Infallible;
 use std::fs;
 use std::fs::File;
 use std::io::{Cursor, Seek, Write};
 use std::path::{Path, PathBuf};
-use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::Duration;
 use bytes::BytesMut;
-use serde_json::Value;
+use serde_json::{json, Value};
 use tokio::time::interval;
 use tokio_stream::StreamExt;
-use tokio_stream::wrappers::{IntervalStream, ReceiverStream};
+use tokio_stream::wrappers::{IntervalStream, UnboundedReceiverStream};
 use tracing_subscriber::fmt::FormatFields;
 use uuid::Uuid;
 use warp::http::{Response, StatusCode};
-use warp::Reply;
+use warp::{reply, Reply};
 use warp::sse::Event;
 use zip::{ZipWriter, write::FileOptions};
 use crate::{Cli, Region};
-use crate::claims::get_claims;
+use crate::claims::SharedClaimProvider;
 use crate::region::RegionWriter;
+use crate::roles::{Role, SharedAuditLog, SharedRoles};
+use crate::server::common::Profile;
 
 macro_rules! tri_resp {
     ($($t:tt)+) => {
@@ -117,7 +118,31 @@ fn add_anvil<W: Write + Seek>(zip: &mut ZipWriter<W>, chunks: &Vec<Vec<i32>>, ta
     Ok(())
 }
 
-pub async fn export_chunks(opts: ExportOptions) -> Result<impl Reply, Infallible> {
+pub async fn export_chunks(opts: ExportOptions, manager: SharedAuthManager, claims: SharedClaimProvider) -> Result<impl Reply, Infallible> {
+    // Players may only export chunks touching claims they're allowed to export
+    let profile = match manager.lock().await.get_verified_profile(&opts.code) {
+        Some(p) => p,
+        None => return Ok(Response::builder().status(StatusCode::UNAUTHORIZED)
+            .body("code has not been verified").into_response())
+    };
+    for coords in &opts.chunks {
+        // Anything else would be exported by its first two coordinates without being checked
+        let (x, z) = match coords[..] {
+            [x, z] => (x, z),
+            _ => return Ok(Response::builder().status(StatusCode::BAD_REQUEST)
+                .body("invalid coordinate provided").into_response())
+        };
+        if !claims.can_export_chunk(profile.id, &opts.world, x, z) {
+            return Ok(Response::builder().status(StatusCode::FORBIDDEN)
+                .body(format!("chunk ({}, {}) is outside of your claims", x, z)).into_response())
+        }
+    }
+
+    write_export(&opts.world, &opts.chunks)
+}
+
+/// Zips up the requested chunks of a world along with its level.dat
+fn write_export(world: &str, chunks: &Vec<Vec<i32>>) -> Result<reply::Response, Infallible> {
     // check for world
     let dir = Cli::parse().path;
     let server_path = Path::new(&dir);
@@ -126,7 +151,7 @@ pub async fn export_chunks(opts: ExportOptions) -> Result<impl Reply, Infallible
             .body("configured server directory does not exist").into_response())
     }
 
-    let world_path = server_path.join(&opts.world);
+    let world_path = server_path.join(world);
     if !world_path.exists() {
         return Ok(Response::builder().status(StatusCode::BAD_REQUEST)
             .body("provided world does not exist").into_response())
@@ -139,9 +164,9 @@ pub async fn export_chunks(opts: ExportOptions) -> Result<impl Reply, Infallible
     zip.add_directory("entities/", FileOptions::default()).unwrap();
     zip.add_directory("poi/", FileOptions::default()).unwrap();
 
-    tri_resp!(add_anvil(&mut zip, &opts.chunks, "region", &world_path));
-    tri_resp!(add_anvil(&mut zip, &opts.chunks, "entities", &world_path));
-    tri_resp!(add_anvil(&mut zip, &opts.chunks, "poi", &world_path));
+    tri_resp!(add_anvil(&mut zip, chunks, "region", &world_path));
+    tri_resp!(add_anvil(&mut zip, chunks, "entities", &world_path));
+    tri_resp!(add_anvil(&mut zip, chunks, "poi", &world_path));
 
     // level.dat
     let options = FileOptions::default()
@@ -159,41 +184,83 @@ pub async fn export_chunks(opts: ExportOptions) -> Result<impl Reply, Infallible
     Ok(Response::builder().status(StatusCode::OK).body(zip.finish().unwrap().into_inner()).into_response())
 }
 
+/// Checks that a code was verified by someone with at least the given role.
+/// Every attempt is audit-logged, including denied ones.
+async fn authorize_staff(code: &String, min_role: Role, action: &str, details: Value, manager: &SharedAuthManager,
+                         roles: &SharedRoles, audit: &SharedAuditLog) -> Result<Profile, reply::Response> {
+    let profile = match manager.lock().await.get_verified_profile(code) {
+        Some(p) => p,
+        None => return Err(Response::builder().status(StatusCode::UNAUTHORIZED)
+            .body("code has not been verified").into_response())
+    };
+    let role = roles.get_role(profile.id);
+    let allowed = role >= min_role;
+    audit.record(&profile, role, action, allowed, details);
+    if !allowed {
+        return Err(Response::builder().status(StatusCode::FORBIDDEN)
+            .body(format!("this requires the {:?} role", min_role)).into_response())
+    }
+    Ok(profile)
+}
+
+/// Exports any selection, regardless of claims
+pub async fn admin_export(code: String, opts: SelectionOptions, manager: SharedAuthManager, roles: SharedRoles,
+                          audit: SharedAuditLog) -> Result<impl Reply, Infallible> {
+    let details = json!({ "world": opts.world, "chunks": opts.chunks });
+    if let Err(resp) = authorize_staff(&code, Role::Moderator, "export", details, &manager, &roles, &audit).await {
+        return Ok(resp);
+    }
+    write_export(&opts.world, &opts.chunks)
+}
+
+/// Lists another player's claims
+pub async fn admin_claims(player: Uuid, code: String, manager: SharedAuthManager, claims: SharedClaimProvider,
+                          roles: SharedRoles, audit: SharedAuditLog) -> Result<impl Reply, Infallible> {
+    let details = json!({ "player": player });
+    if let Err(resp) = authorize_staff(&code, Role::Moderator, "view_claims", details, &manager, &roles, &audit).await {
+        return Ok(resp);
+    }
+    Ok(reply::json(&claims.get_claims(player)).into_response())
+}
+
 
-pub async fn create_code(manager: SharedAuthManager) -> Result<impl Reply, Infallible> {
-    let code = manager.lock().await.create_code();
+pub async fn create_code(query: CreateCodeQuery, manager: SharedAuthManager) -> Result<impl Reply, Infallible> {
+    let code = manager.lock().await.create_code(query.browser.as_deref());
     Ok(Response::builder().status(StatusCode::OK).body(code).into_response())
 }
 
-pub async fn poll_login(code: String, manager: SharedAuthManager) -> Result<impl Reply, Infallible> {
-    let receiver = {
-        match manager.lock().await.get_stream(&code) {
-            Some(s) => s,
+pub async fn poll_login(code: String, manager: SharedAuthManager, claims: SharedClaimProvider, roles: SharedRoles) -> Result<impl Reply, Infallible> {
+    let (receiver, sender, time_left) = {
+        let mut manager = manager.lock().await;
+        match manager.get_stream(&code) {
+            Some(s) => (s, manager.get_sender(&code).unwrap(), manager.get_time_left(&code).unwrap()),
             None => return Ok(Response::builder().status(StatusCode::NOT_FOUND).body("Invalid one-time code!").into_response())
         }
     };
 
-    let cancel = AtomicBool::new(false);
+    // Let the webmap know if nobody verified with the code in time
+    let expiry_manager = manager.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(time_left).await;
+        if !expiry_manager.lock().await.is_code_used(&code) {
+            let _ = sender.send(AuthEvent::Expired);
+        }
+    });
 
     let keepalive_stream = IntervalStream::new(interval(Duration::from_millis(2000))).map(move |_| {
         Ok::<Event, Infallible>(Event::default().comment("keepalive"))
     });
 
-    let event_stream = ReceiverStream::new(receiver).map(move |v| {
-        let data = if !cancel.load(Ordering::Relaxed) {
-            if let Some(profile) = v {
-                cancel.store(true, Ordering::Relaxed);
-                let mut val = serde_json::to_value(&profile).unwrap();
-                val.as_object_mut().unwrap().insert("claims".to_owned(), serde_json::to_value(&get_claims(Uuid::from_u128(0u128))).unwrap());
-                serde_json::to_string(&val).unwrap()
-            } else {
-                "{}".to_owned()
-            }
-        } else {
-            "{}".to_owned()
-        };
+    let event_stream = UnboundedReceiverStream::new(receiver).map(move |event| {
+        let mut data = event.data();
+        if let AuthEvent::Verified { profile, .. } = &event {
+            let data = data.as_object_mut().unwrap();
+            data.insert("claims".to_owned(), serde_json::to_value(claims.get_exportable_claims(profile.id)).unwrap());
+            // So the webmap knows whether to offer staff tools
+            data.insert("role".to_owned(), serde_json::to_value(roles.get_role(profile.id)).unwrap());
+        }
 
-        Ok::<Event, Infallible>(Event::default().data(data))
+        Ok::<Event, Infallible>(Event::default().event(event.name()).data(data.to_string()))
     });
 
     let stream = event_stream.merge(keepalive_stream);
@@ -9,13 +9,16 @@ mod models;
 mod config;
 mod server;
 mod claims;
+mod roles;
 
 use std::collections::HashMap;
 use std::{env, fs};
 use std::fs::File;
+use std::path::{Path, PathBuf};
 use std::io::prelude::*;
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use anyhow::anyhow;
 use async_trait::async_trait;
 use bytes::{Bytes, BytesMut, Buf, BufMut};
 use clap::{Subcommand, Args};
@@ -23,44 +26,133 @@ use clap::Parser as _;
 use clap_derive::Parser;
 use crc32fast::Hasher;
 use flate2::read::ZlibDecoder;
-use log::info;
-use tokio::sync::mpsc::{channel, UnboundedSender, Sender};
+use log::{info, warn};
+use tokio::sync::mpsc::UnboundedSender;
 use tokio::sync::{mpsc, Mutex};
 use uuid::Uuid;
 use zip::ZipArchive;
 use warp::Filter;
-use crate::models::{AuthManager, SharedAuthManager};
+use crate::models::{AuthEvent, AuthManager, SharedAuthManager, VerifiedCookie, REMEMBER_DURATION, VERIFIED_COOKIE};
 use crate::nbt::Tag;
 use crate::region::Region;
+use crate::server::access::AccessLists;
 use crate::server::base::Server;
+use crate::claims::{import_griefprevention, ExportPolicy, FileClaimProvider, SharedClaimProvider};
+use crate::roles::{AuditLog, RoleConfig, Roles, SharedAuditLog, SharedRoles};
+use crate::server::command::CommandSpec;
+use crate::server::common::ClientInfo;
+use crate::server::cookie::{read_signed_cookie, sign_cookie};
 use crate::server::common::Profile;
+use crate::server::forwarding::ForwardingMode;
 use crate::server::handler::{PacketHandler, SendError};
 use crate::server::packets::c2s::handshake::HandshakeC2S;
 use crate::server::packets::c2s::play::ChatC2S;
 use crate::server::packets::c2s::status::{PingRequestC2S, StatusRequestC2S};
 use crate::server::packets::packet::PacketS2C;
+use crate::server::packets::s2c::config::{CustomPayloadS2C, Link, LinkLabel, LinksS2C};
 use crate::server::packets::stage::Stage;
-use crate::server::text::{ChatColor, TextComponent};
+use crate::server::status::StatusConfig;
+use crate::server::text::{ChatColor, ClickEvent, HoverEvent, TextComponent};
+use crate::server::utils::write_string;
+use crate::server::version::ProtocolVersion;
+use crate::server::world::WorldPreview;
 
 #[derive(Parser)]
 #[command(about="SwanCraft Map Distribution Server")]
 pub struct Cli {
     #[clap(long,short)]
     pub path: String,
+    /// Velocity modern forwarding secret; enables Velocity forwarding when set
+    #[clap(long)]
+    pub velocity_secret: Option<String>,
+    /// Accept BungeeCord legacy forwarding from the handshake address
+    #[clap(long)]
+    pub bungeecord: bool,
+    /// JSON or TOML claims file; claims are imported from GriefPrevention if not set
+    #[clap(long)]
+    pub claims: Option<String>,
+    /// JSON or TOML file with `admins` and `moderators` UUID lists, on top of ops.json
+    #[clap(long)]
+    pub roles: Option<String>,
+    /// Packets at least this many bytes are compressed; -1 disables compression
+    #[clap(long, default_value_t = 256, allow_negative_numbers = true)]
+    pub compression_threshold: i32,
+    /// Where staff actions are recorded
+    #[clap(long, default_value = "audit.log")]
+    pub audit_log: String,
+    /// JSON or TOML file with the MOTD, favicon, version label and player sample for the server list
+    #[clap(long)]
+    pub status: Option<String>,
+    /// `host[:port]` of the main server to transfer 1.20.5+ players back to after verification
+    #[clap(long)]
+    pub transfer: Option<String>,
+    /// Secret shared with the main server for signing the verification cookie
+    #[clap(long)]
+    pub cookie_secret: Option<String>,
+    /// The most chunks in each direction sent around a player
+    #[clap(long, default_value_t = 10)]
+    pub view_distance: u8,
+    /// Skip authentication and encryption, giving players offline UUIDs; only for testing
+    #[clap(long)]
+    pub offline: bool,
+    /// Base URL of the session server players are authenticated with
+    #[clap(long)]
+    pub session_server: Option<String>,
+}
+
+/// Where verified players are sent, if their client supports transfers
+#[derive(Debug, Clone)]
+pub struct TransferTarget {
+    pub host: String,
+    pub port: u16
+}
+
+impl TransferTarget {
+    /// Parses `host`, `host:port`, `[addr]` or `[addr]:port`, defaulting to the vanilla port.
+    /// A bare IPv6 address has too many colons to carry a port, so it's taken as a whole.
+    pub fn parse(address: &str) -> anyhow::Result<TransferTarget> {
+        if let Some(rest) = address.strip_prefix('[') {
+            let (host, port) = rest.split_once(']').ok_or(anyhow!("missing ] in {}", address))?;
+            let port = match port {
+                "" => 25565,
+                _ => port.strip_prefix(':').ok_or(anyhow!("expected a port after ] in {}", address))?.parse()?
+            };
+            return Ok(TransferTarget { host: host.to_owned(), port });
+        }
+        match address.split_once(':') {
+            Some((host, port)) if !port.contains(':') => Ok(TransferTarget { host: host.to_owned(), port: port.parse()? }),
+            _ => Ok(TransferTarget { host: address.to_owned(), port: 25565 })
+        }
+    }
 }
 
 pub struct AuthPacketHandler {
     pub stage: Stage,
+    pub version: ProtocolVersion,
     pub channel: UnboundedSender<Box<dyn PacketS2C + Send>>,
     pub profile: Profile,
     pub manager: SharedAuthManager,
-    pub stream: Sender<Option<Profile>>
+    pub claims: SharedClaimProvider,
+    /// Events for the code this player is using, if any
+    pub events: Option<UnboundedSender<AuthEvent>>,
+    pub transfer: Option<TransferTarget>,
+    pub cookie_secret: Option<Vec<u8>>,
+    /// Whether the player came back with a valid verification cookie
+    pub remembered: bool,
+    /// The server directory claims' worlds are read from
+    pub server_dir: PathBuf,
+    /// The claim shown to the player in-game
+    pub previewing: Option<i64>,
+    /// A preview picked with /preview, for the connection to switch to
+    pub preview_change: Option<WorldPreview>
 }
 
 impl AuthPacketHandler {
-    fn new(manager: SharedAuthManager) -> AuthPacketHandler {
+    fn new(manager: SharedAuthManager, claims: SharedClaimProvider, transfer: Option<TransferTarget>, cookie_secret: Option<Vec<u8>>,
+           server_dir: PathBuf) -> AuthPacketHandler {
         AuthPacketHandler {
             stage: Stage::Handshake,
+            version: ProtocolVersion::Unknown,
             channel: mpsc::unbounded_channel().0,  // to be set later
             profile: Profile {
                 id: Uuid::from_u128(0u128),
@@ -68,48 +160,76 @@ impl AuthPacketHandler {
                 properties: vec![]
             },
             manager,
-            stream: channel(4).0  // placeholder
+            claims,
+            events: None,
+            transfer,
+            cookie_secret,
+            remembered: false,
+            server_dir,
+            previewing: None,
+            preview_change: None
         }
     }
-}
-
-#[async_trait]
-impl PacketHandler for AuthPacketHandler {
-    fn set_channel(&mut self, sender: UnboundedSender<Box<dyn PacketS2C + Send>>) {
-        self.channel = sender;
-    }
 
-    fn set_stage(&mut self, new_stage: Stage) {
-        self.stage = new_stage;
+    /// Previews a claim or subclaim the player may export, or the first one if no ID is given
+    fn preview_claim(&mut self, id: Option<i64>) -> Option<WorldPreview> {
+        let claim = self.claims.get_exportable_claims(self.profile.id).into_iter()
+            .flat_map(|c| std::iter::once(c.clone()).chain(c.subclaims))
+            .find(|c| id.is_none() || id == Some(c.id))?;
+        self.previewing = Some(claim.id);
+        Some(claim.preview(&self.server_dir, self.profile.id, &*self.claims))
     }
 
-    fn get_stage(&self) -> &Stage {
-        &self.stage
+    /// Leaves a signed cookie on 1.20.5+ clients, so the main server can confirm the verification
+    /// and we can remember the player next time
+    fn remember(&mut self, profile: &Profile) -> anyhow::Result<bool> {
+        let secret = match &self.cookie_secret {
+            Some(secret) => secret.clone(),
+            None => return Ok(false)
+        };
+        let cookie = VerifiedCookie {
+            id: profile.id,
+            name: profile.name.clone(),
+            verified_at: SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs()
+        };
+        self.store_cookie(VERIFIED_COOKIE, sign_cookie(&secret, &serde_json::to_vec(&cookie)?))
     }
 
-    fn send_packet(&mut self, packet: Box<dyn PacketS2C + Send>) -> anyhow::Result<bool> {
-        match self.channel.send(packet) {
-            Ok(_) => Ok(true),
-            Err(_) => Err(SendError::new().into())
+    /// Checks a verification cookie the client sent back, which has to be ours, recent and for this player
+    fn is_remembered(&self, payload: &Bytes) -> bool {
+        let cookie = self.cookie_secret.as_ref()
+            .and_then(|secret| read_signed_cookie(secret, payload))
+            .and_then(|data| serde_json::from_slice::<VerifiedCookie>(&data).ok());
+        match cookie {
+            Some(cookie) => {
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+                cookie.id == self.profile.id && now.saturating_sub(cookie.verified_at) < REMEMBER_DURATION.as_secs()
+            },
+            None => false
         }
     }
 
-    async fn on_handshake(&mut self, packet: HandshakeC2S) -> anyhow::Result<bool> {
-        Ok(true)
-    }
-
-    async fn on_status_request(&mut self, packet: StatusRequestC2S) -> anyhow::Result<bool> {
-        Ok(true)
+    /// Transfers a verified player back to the main server.
+    /// Returns false if they have to be kicked instead.
+    fn send_back(&mut self) -> anyhow::Result<bool> {
+        match self.transfer.clone() {
+            Some(target) => self.transfer(&target.host, target.port),
+            None => Ok(false)
+        }
     }
 
-    async fn on_ping_request(&mut self, packet: PingRequestC2S) -> anyhow::Result<bool> {
-        Ok(true)
+    fn push_event(&self, event: AuthEvent) {
+        if let Some(events) = &self.events {
+            // Nobody may be listening, which is fine
+            let _ = events.send(event);
+        }
     }
 
-    async fn on_chat(&mut self, packet: ChatC2S) -> anyhow::Result<bool> {
+    /// Checks a one-time code and authorizes the player's profile with it
+    async fn verify(&mut self, code: &String) -> anyhow::Result<()> {
         let mut manager_arc = self.manager.clone();
         let mut manager = manager_arc.lock().await;
-        if !manager.has_code(&packet.message) {
+        if !manager.has_code(code) {
             let mut msg1 = TextComponent::plain("This code does not exist! ");
             msg1.set_bold(true);
             msg1.set_color(ChatColor::Red);
@@ -118,7 +238,10 @@ impl PacketHandler for AuthPacketHandler {
             msg2.set_color(ChatColor::DarkRed);
             msg1.add_component(msg2);
             self.send_game_message(msg1, false).unwrap();
-        } else if manager.is_code_used(&packet.message) {
+        } else if manager.is_code_used(code) {
+            if let Some(sender) = manager.get_sender(code) {
+                let _ = sender.send(AuthEvent::CodeAlreadyUsed);
+            }
             let mut msg1 = TextComponent::plain("This code has already been used! ");
             msg1.set_bold(true);
             msg1.set_color(ChatColor::Red);
@@ -127,13 +250,41 @@ impl PacketHandler for AuthPacketHandler {
             msg2.set_color(ChatColor::DarkRed);
             msg1.add_component(msg2);
             self.send_game_message(msg1, false).unwrap();
+        } else if manager.is_code_expired(code) {
+            if let Some(sender) = manager.get_sender(code) {
+                let _ = sender.send(AuthEvent::Expired);
+            }
+            let mut msg1 = TextComponent::plain("This code has expired! ");
+            msg1.set_bold(true);
+            msg1.set_color(ChatColor::Red);
+            let mut msg2 = TextComponent::plain("Please generate a new code and try again.");
+            msg2.set_bold(false);
+            msg2.set_color(ChatColor::DarkRed);
+            msg1.add_component(msg2);
+            self.send_game_message(msg1, false).unwrap();
         } else {
-            manager.use_code(&packet.message);
+            self.events = manager.get_sender(code);
+            self.push_event(AuthEvent::PlayerConnected { name: self.profile.name.clone() });
+
+            if self.profile.id.is_nil() {
+                // We never got a real profile for this player
+                let reason = "Your account could not be authenticated.".to_owned();
+                self.push_event(AuthEvent::CodeRejected { reason: reason.clone() });
+                let mut msg = TextComponent::plain(&reason);
+                msg.set_color(ChatColor::Red);
+                self.send_game_message(msg, false).unwrap();
+                return Ok(());
+            }
+
+            let profile = self.get_profile().await.clone();
+            manager.use_code(code, profile.clone());
 
-            let profile = self.get_profile().await;
-            info!("User {} ({}) authorized with code {}", profile.name, profile.id, packet.message);
+            info!("User {} ({}) authorized with code {}", profile.name, profile.id, code);
 
-            manager.get_sender(&packet.message).unwrap().send(Some(profile.clone())).await.unwrap();
+            let browser = manager.remember_browser(profile.id);
+            self.push_event(AuthEvent::Verified { profile: profile.clone(), browser });
+            // We're done with this code, so the kick below isn't reported
+            self.events = None;
 
             let mut msg1 = TextComponent::plain("Authorization successful! ");
             msg1.set_bold(true);
@@ -142,11 +293,346 @@ impl PacketHandler for AuthPacketHandler {
             msg2.set_bold(false);
             msg2.set_color(ChatColor::Gray);
             msg1.add_component(msg2);
-            self.kick(msg1).unwrap();
+            self.remember(&profile)?;
+            // Nothing sent after a transfer is shown, and the kick screen shows the message anyway
+            self.send_game_message(msg1.clone(), false)?;
+            if !self.send_back()? {
+                self.kick(msg1)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl PacketHandler for AuthPacketHandler {
+    fn set_channel(&mut self, sender: UnboundedSender<Box<dyn PacketS2C + Send>>) {
+        self.channel = sender;
+    }
+
+    fn set_stage(&mut self, new_stage: Stage) {
+        self.stage = new_stage;
+    }
+
+    fn get_stage(&self) -> &Stage {
+        &self.stage
+    }
+
+    fn set_version(&mut self, version: ProtocolVersion) {
+        self.version = version;
+    }
+
+    fn get_version(&self) -> ProtocolVersion {
+        self.version
+    }
+
+    fn send_packet(&mut self, packet: Box<dyn PacketS2C + Send>) -> anyhow::Result<bool> {
+        match self.channel.send(packet) {
+            Ok(_) => Ok(true),
+            Err(_) => Err(SendError::new().into())
+        }
+    }
+
+    async fn on_handshake(&mut self, packet: HandshakeC2S) -> anyhow::Result<bool> {
+        Ok(true)
+    }
+
+    async fn on_status_request(&mut self, packet: StatusRequestC2S) -> anyhow::Result<bool> {
+        Ok(true)
+    }
+
+    async fn on_ping_request(&mut self, packet: PingRequestC2S) -> anyhow::Result<bool> {
+        Ok(true)
+    }
+
+    fn get_login_cookies(&self) -> Vec<String> {
+        match self.cookie_secret {
+            Some(_) => vec![VERIFIED_COOKIE.to_owned()],
+            None => vec![]
+        }
+    }
+
+    async fn on_cookie(&mut self, key: String, payload: Option<Bytes>) -> anyhow::Result<bool> {
+        if key == VERIFIED_COOKIE {
+            self.remembered = payload.map(|p| self.is_remembered(&p)).unwrap_or(false);
         }
         Ok(true)
     }
 
+    async fn on_client_info(&mut self, info: ClientInfo) -> anyhow::Result<bool> {
+        // Send our server brand
+        let mut brand_buf = BytesMut::new();
+        write_string(&mut brand_buf, "WorldFreezer");
+        self.send_packet(Box::new(CustomPayloadS2C {
+            key: "minecraft:brand".to_owned(),
+            payload: brand_buf.into()
+        }))
+    }
+
+    async fn on_config_ready(&mut self) -> anyhow::Result<bool> {
+        // Server links were added in 1.21
+        if self.version >= ProtocolVersion::V1_21 {
+            let mut left = TextComponent::new();
+            let mut right = TextComponent::new();
+            left.set_text("៚ ");
+            right.set_text("");
+
+            let mut website = TextComponent::new();
+            website.set_text("Website");
+            website.set_gradient(&[ChatColor::Aqua, ChatColor::White]);
+            website.prepend_component(left.clone());
+            website.add_component(right.clone());
+            let mut store = TextComponent::new();
+            store.set_text("Store");
+            store.set_gradient(&[ChatColor::DarkGreen, ChatColor::Green]);
+            store.prepend_component(left.clone());
+            store.add_component(right.clone());
+            let mut vote = TextComponent::new();
+            vote.set_text("Vote!");
+            vote.set_gradient(&[ChatColor::DarkCyan, ChatColor::Aqua]);
+            vote.prepend_component(left.clone());
+            vote.add_component(right.clone());
+
+            // let mut topmc = TextComponent::new();
+            // topmc.set_text("Top MC Servers");
+            // topmc.set_gradient(&[ChatColor::Blue, ChatColor::Gray]);
+            // let mut mcsl = TextComponent::new();
+            // mcsl.set_text("Minecraft SL");
+            // mcsl.set_gradient(&[ChatColor::White, ChatColor::Gray]);
+            // let mut mcs = TextComponent::new();
+            // mcs.set_text("Minecraft Servers");
+            // mcs.set_gradient(&[ChatColor::LightPurple, ChatColor::Purple]);
+            // let mut pmc = TextComponent::new();
+            // pmc.set_text("Planet Minecraft");
+            // pmc.set_gradient(&[ChatColor::DarkGreen, ChatColor::DarkCyan]);
+            // let mut mmp = TextComponent::new();
+            // mmp.set_text("Minecraft MP");
+            // mmp.set_color(ChatColor::DarkGreen);
+            // let mut topg = TextComponent::new();
+            // topg.set_text("TopG");
+            // topg.set_gradient(&[ChatColor::Gold, ChatColor::Gray]);
+            // let mut buzz = TextComponent::new();
+            // buzz.set_text("Buzz");
+            // buzz.set_gradient(&[ChatColor::Yellow, ChatColor::Gold]);
+
+            self.send_packet(Box::new(LinksS2C {
+                links: vec![Link {
+                    label: LinkLabel::Custom(website),
+                    url: "https://swancraft.guildtag.com/".to_owned()
+                }, Link {
+                    label: LinkLabel::Custom(store),
+                    url: "https://swancraft.buycraft.net/".to_owned()
+                }, Link {
+                    label: LinkLabel::Custom(vote),
+                    url: "https://swancraft.guildtag.com/vote".to_owned()
+                }], // Link {
+                //     label: LinkLabel::Custom(topmc),
+                //     url: "https://topminecraftservers.org/vote/4455".to_owned()
+                // }, Link {
+                //     label: LinkLabel::Custom(mcsl),
+                //     url: "https://minecraft-server-list.com/server/389267/vote/".to_owned()
+                // }, Link {
+                //     label: LinkLabel::Custom(mcs),
+                //     url: "https://minecraftservers.org/vote/410424".to_owned()
+                // }, Link {
+                //     label: LinkLabel::Custom(pmc),
+                //     url: "https://www.planetminecraft.com/server/swancraft-3882768/vote/".to_owned()
+                // }, Link {
+                //     label: LinkLabel::Custom(mmp),
+                //     url: "https://minecraft-mp.com/server/145239/vote/".to_owned()
+                // }, Link {
+                //     label: LinkLabel::Custom(topg),
+                //     url: "https://topg.org/minecraft-servers/server-449700".to_owned()
+                // }, Link {
+                //     label: LinkLabel::Custom(buzz),
+                //     url: "https://minecraft.buzz/vote/5680".to_owned()
+                // }]
+            }))?;
+        }
+        Ok(true)
+    }
+
+    async fn get_preview(&mut self) -> Option<WorldPreview> {
+        self.preview_claim(None)
+    }
+
+    async fn take_preview_change(&mut self) -> Option<WorldPreview> {
+        self.preview_change.take()
+    }
+
+    async fn on_play_ready(&mut self) -> anyhow::Result<bool> {
+        let mut decor = TextComponent::new();
+        decor.set_text("៚ ");
+
+        let mut title = TextComponent::plain("Welcome!");
+        title.set_bold(true);
+        title.set_gradient(&[ChatColor::DarkCyan, ChatColor::Aqua]);
+        title.prepend_component(decor.clone());
+        self.send_game_message(title, false)?;
+
+        let mut instructions = TextComponent::plain("To verify your account, please run /verify with your one-time code. It will not be shared with others.");
+        instructions.set_color(ChatColor::Gold);
+        self.send_game_message(instructions, false)?;
+
+        if let Some(id) = self.previewing {
+            let mut msg = TextComponent::plain(&format!("You're previewing claim #{} in spectator mode. ", id));
+            msg.set_color(ChatColor::Aqua);
+            let mut hint = TextComponent::plain("This is what will be exported once you verify. Use /preview <claim> to look at another one.");
+            hint.set_color(ChatColor::Gray);
+            msg.add_component(hint);
+            self.send_game_message(msg, false)?;
+        }
+        if !self.remembered {
+            return Ok(true);
+        }
+        let code = self.manager.lock().await.find_remembered_code(self.profile.id);
+        let mut msg = TextComponent::plain(&format!("Welcome back, {}! ", self.profile.name));
+        msg.set_color(ChatColor::Gold);
+        match code {
+            Some(code) => {
+                self.events = self.manager.lock().await.get_sender(&code);
+                self.push_event(AuthEvent::PlayerConnected { name: self.profile.name.clone() });
+                let mut link = TextComponent::plain("[Link to this browser session]");
+                link.set_color(ChatColor::Green);
+                link.set_bold(true);
+                link.set_click_event(ClickEvent::run_command("/link"));
+                link.set_hover_event(HoverEvent::show_text(TextComponent::plain("Verify without typing a code")));
+                msg.add_component(link);
+            },
+            None => {
+                let mut hint = TextComponent::plain("Open the webmap in a browser you've verified in before to link it in one click.");
+                hint.set_color(ChatColor::Gray);
+                msg.add_component(hint);
+            }
+        }
+        self.send_game_message(msg, false)
+    }
+
+    async fn on_chat(&mut self, packet: ChatC2S) -> anyhow::Result<bool> {
+        // Codes used to be sent as plain chat, so point people to the command instead
+        let mut msg = TextComponent::plain("Chat is disabled here. Use /verify <code> to verify your account.");
+        msg.set_color(ChatColor::Gray);
+        self.send_game_message(msg, false)
+    }
+
+    async fn on_command(&mut self, name: String, args: Vec<String>) -> anyhow::Result<bool> {
+        match name.as_str() {
+            "verify" => {
+                match args.first() {
+                    Some(code) => self.verify(code).await?,
+                    None => {
+                        let mut msg = TextComponent::plain("Usage: /verify <code>");
+                        msg.set_color(ChatColor::Red);
+                        self.send_game_message(msg, false)?;
+                    }
+                }
+            },
+            "link" => {
+                let code = if self.remembered {
+                    self.manager.lock().await.find_remembered_code(self.profile.id)
+                } else {
+                    None
+                };
+                match code {
+                    Some(code) => self.verify(&code).await?,
+                    None => {
+                        let mut msg = TextComponent::plain("There is no browser session to link. Use /verify <code> instead.");
+                        msg.set_color(ChatColor::Red);
+                        self.send_game_message(msg, false)?;
+                    }
+                }
+            },
+            "claims" => {
+                let claims = self.claims.get_claims(self.profile.id);
+                let mut msg = TextComponent::plain(&format!("You have {} claim(s):", claims.len()));
+                msg.set_color(ChatColor::Gold);
+                self.send_game_message(msg, false)?;
+                for claim in claims.iter().flat_map(|c| std::iter::once(c).chain(c.subclaims.iter())) {
+                    let mut line = TextComponent::plain(&format!("  #{} {}: ({}, {}) to ({}, {}), export: {:?}",
+                                                                 claim.id, claim.world, claim.x1, claim.z1, claim.x2, claim.z2, claim.export_policy));
+                    line.set_color(ChatColor::Gray);
+                    self.send_game_message(line, false)?;
+                }
+            },
+            "preview" => {
+                let id = args.first().and_then(|id| id.trim_start_matches('#').parse().ok());
+                let msg = match id {
+                    Some(id) => match self.preview_claim(Some(id)) {
+                        Some(preview) => {
+                            self.preview_change = Some(preview);
+                            let mut msg = TextComponent::plain(&format!("Now previewing claim #{}.", id));
+                            msg.set_color(ChatColor::Green);
+                            msg
+                        },
+                        None => {
+                            let mut msg = TextComponent::plain(&format!("You can't export a claim with ID {}.", id));
+                            msg.set_color(ChatColor::Red);
+                            msg
+                        }
+                    },
+                    None => {
+                        let mut msg = TextComponent::plain("Usage: /preview <claim>");
+                        msg.set_color(ChatColor::Red);
+                        msg
+                    }
+                };
+                self.send_game_message(msg, false)?;
+            },
+            "exportpolicy" => {
+                let id = args.first().and_then(|id| id.trim_start_matches('#').parse().ok());
+                let policy = args.get(1).and_then(|p| ExportPolicy::from_name(p));
+                let mut msg = match (id, policy) {
+                    (Some(id), Some(policy)) => match self.claims.set_export_policy(self.profile.id, id, policy) {
+                        Ok(_) => {
+                            let mut msg = TextComponent::plain(&format!("Export policy of claim #{} set to {:?}.", id, policy));
+                            msg.set_color(ChatColor::Green);
+                            msg
+                        },
+                        Err(e) => {
+                            let mut msg = TextComponent::plain(&format!("Could not set export policy: {}", e));
+                            msg.set_color(ChatColor::Red);
+                            msg
+                        }
+                    },
+                    _ => {
+                        let mut msg = TextComponent::plain("Usage: /exportpolicy <claim> <allow_trusted|owner_only|deny>");
+                        msg.set_color(ChatColor::Red);
+                        msg
+                    }
+                };
+                msg.set_bold(false);
+                self.send_game_message(msg, false)?;
+            },
+            "help" => {
+                for command in self.get_commands() {
+                    let mut usage = TextComponent::plain(&command.usage());
+                    usage.set_color(ChatColor::Gold);
+                    let mut description = TextComponent::plain(&format!(" - {}", command.description));
+                    description.set_color(ChatColor::Gray);
+                    usage.add_component(description);
+                    self.send_game_message(usage, false)?;
+                }
+            },
+            _ => return Ok(false)
+        }
+        Ok(true)
+    }
+
+    fn get_commands(&self) -> Vec<CommandSpec> {
+        vec![
+            CommandSpec::new("verify", &["code"], "Verify your account with a one-time code"),
+            CommandSpec::new("link", &[], "Verify the browser session you verified in last time"),
+            CommandSpec::new("claims", &[], "List your claims"),
+            CommandSpec::new("preview", &["claim"], "Look at another claim you can export"),
+            CommandSpec::new("exportpolicy", &["claim", "policy"], "Set who may export one of your claims"),
+            CommandSpec::new("help", &[], "Show this list")
+        ]
+    }
+
+    fn on_kick(&mut self, reason: &TextComponent) {
+        self.push_event(AuthEvent::Kicked { reason: reason.to_plain() });
+    }
+
     async fn set_profile(&mut self, profile: Profile) {
         self.profile = profile;
     }
@@ -158,7 +644,7 @@ impl PacketHandler for AuthPacketHandler {
 
 #[tokio::main]
 async fn main() {
-    Cli::parse();
+    let cli = Cli::parse();
 
     if env::var_os("RUST_LOG").is_none() {
         env::set_var("RUST_LOG", "swandist=info");
@@ -167,15 +653,332 @@ async fn main() {
 
     let mut manager = Arc::new(Mutex::new(AuthManager::new()));
 
-    let api = filters::routes(manager.clone());
+    let forwarding = if let Some(secret) = cli.velocity_secret {
+        ForwardingMode::Velocity { secret: secret.into_bytes() }
+    } else if cli.bungeecord {
+        ForwardingMode::BungeeCord
+    } else {
+        ForwardingMode::None
+    };
+
+    let claims: SharedClaimProvider = match &cli.claims {
+        Some(path) => Arc::new(FileClaimProvider::load(Path::new(path)).expect("failed to load claims")),
+        None => Arc::new(FileClaimProvider::new(import_griefprevention(Path::new(&cli.path)).unwrap_or_else(|e| {
+            warn!("could not import GriefPrevention claims: {e}");
+            vec![]
+        })))
+    };
+
+    let role_config = match &cli.roles {
+        Some(path) => RoleConfig::load(Path::new(path)).expect("failed to load roles"),
+        None => RoleConfig::default()
+    };
+    let roles: SharedRoles = Arc::new(Roles::new(Path::new(&cli.path), role_config));
+    let audit: SharedAuditLog = Arc::new(AuditLog::open(Path::new(&cli.audit_log)).unwrap_or_else(|e| {
+        warn!("audit log will only go to the console: {e}");
+        AuditLog::console()
+    }));
+
+    let status = match &cli.status {
+        Some(path) => StatusConfig::load(Path::new(path)).expect("failed to load status config"),
+        None => StatusConfig {
+            motd: "SwanCraft World Download".to_owned(),
+            motd_colors: vec!["aqua".to_owned(), "light_purple".to_owned()],
+            favicon: Some(PathBuf::from("favicon.png")),
+            ..Default::default()
+        }
+    };
+
+    let transfer = cli.transfer.as_deref().map(|t| TransferTarget::parse(t).expect("invalid transfer address"));
+    let cookie_secret = cli.cookie_secret.map(|s| s.into_bytes());
+    let server_dir = PathBuf::from(&cli.path);
+
+    let api = filters::routes(manager.clone(), claims.clone(), roles, audit);
 
     let routes = api.with(warp::log("swandist"));
 
     tokio::spawn(async move {
         let mut server = Server::new();
-        server.set_handler_factory(move || Box::new(AuthPacketHandler::new(manager.clone())));
+        server.set_forwarding(forwarding);
+        server.set_status(status);
+        server.set_compression_threshold(cli.compression_threshold);
+        server.set_view_distance(cli.view_distance);
+        server.set_online_mode(!cli.offline);
+        if let Some(url) = &cli.session_server {
+            server.set_session_server(url);
+        }
+        server.set_access_lists(AccessLists::new(Path::new(&cli.path)));
+        server.set_handler_factory(move || Box::new(AuthPacketHandler::new(manager.clone(), claims.clone(), transfer.clone(), cookie_secret.clone(),
+                                                                         server_dir.clone())));
         server.start("127.0.0.1:25565").await.expect("failed to start server");
     });
 
     warp::serve(routes).run(([127, 0, 0, 1], 7650)).await;
 }
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use anyhow::anyhow;
+    use serde_json::json;
+    use tokio::net::TcpListener;
+    use tokio::sync::Mutex;
+    use uuid::Uuid;
+    use warp::{Filter, Reply};
+    use warp::hyper::Body;
+    use warp::hyper::body::HttpBody;
+    use crate::{handlers, AuthPacketHandler, TransferTarget};
+    use crate::claims::{Claim, FileClaimProvider, SharedClaimProvider};
+    use crate::models::{AuthManager, SharedAuthManager, CODE_LIFETIME, VERIFIED_COOKIE};
+    use crate::roles::{RoleConfig, Roles};
+    use crate::server::base::Server;
+    use crate::server::client::Client;
+    use crate::server::common::Profile;
+    use crate::server::packets::stage::Stage;
+    use crate::server::version::ProtocolVersion;
+
+    /// Starts a server for the verification flow on an ephemeral port, returning its address
+    async fn start_server(manager: SharedAuthManager, session_server: Option<String>, transfer: Option<TransferTarget>,
+                          cookie_secret: Option<Vec<u8>>, claims: Vec<Claim>) -> String {
+        let claims: SharedClaimProvider = Arc::new(FileClaimProvider::new(claims));
+        let mut server = Server::new();
+        match session_server {
+            Some(url) => server.set_session_server(&url),
+            None => server.set_online_mode(false)
+        }
+        server.set_handler_factory(move || Box::new(AuthPacketHandler::new(manager.clone(), claims.clone(), transfer.clone(), cookie_secret.clone(),
+                                                                             PathBuf::new())));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(server.serve(listener));
+        addr
+    }
+
+    /// Reads chat until a message contains `text`
+    async fn wait_for_message(client: &mut Client, text: &str) -> anyhow::Result<String> {
+        loop {
+            let message = client.next_message().await?.to_plain();
+            if message.contains(text) {
+                return Ok(message);
+            }
+        }
+    }
+
+    /// Polls a code the way the webmap does
+    async fn poll(manager: &SharedAuthManager, code: &str) -> Body {
+        let claims: SharedClaimProvider = Arc::new(FileClaimProvider::new(vec![]));
+        let roles = Arc::new(Roles::new(Path::new(""), RoleConfig::default()));
+        let reply = handlers::poll_login(code.to_owned(), manager.clone(), claims, roles).await.unwrap();
+        reply.into_response().into_body()
+    }
+
+    /// Reads server-sent events until the next named one, skipping keepalives
+    async fn next_event(body: &mut Body, buffer: &mut String) -> anyhow::Result<String> {
+        loop {
+            if let Some(end) = buffer.find("\n\n") {
+                let event: String = buffer.drain(..end + 2).collect();
+                if let Some(name) = event.lines().find_map(|l| l.strip_prefix("event:")) {
+                    return Ok(name.to_owned());
+                }
+                continue;
+            }
+            let chunk = body.data().await.ok_or(anyhow!("event stream ended"))??;
+            buffer.push_str(std::str::from_utf8(&chunk)?);
+        }
+    }
+
+    #[tokio::test]
+    async fn offline_player_verifies_code() {
+        // 1.20.4 gets the whole registry codec, 1.20.6 and 1.21 agree on known packs with their own registries
+        for version in [ProtocolVersion::V1_20_4, ProtocolVersion::V1_20_6, ProtocolVersion::V1_21] {
+            let manager = Arc::new(Mutex::new(AuthManager::new()));
+            let addr = start_server(manager.clone(), None, None, None, vec![]).await;
+            tokio::time::timeout(Duration::from_secs(60), async {
+                let mut client = Client::connect(&addr, version).await.unwrap();
+                assert!(client.status().await.unwrap().contains(&format!("\"protocol\":{}", version.get_id())));
+                assert_eq!(client.ping(42).await.unwrap(), 42);
+
+                let mut client = Client::connect(&addr, version).await.unwrap();
+                let profile = client.login("Swan").await.unwrap();
+                assert_eq!(profile.id, Profile::offline("Swan").id);
+                client.configure().await.unwrap();
+                assert_eq!(client.get_stage(), Stage::Play);
+                wait_for_message(&mut client, "Welcome!").await.unwrap();
+
+                client.command("verify nope").await.unwrap();
+                wait_for_message(&mut client, "This code does not exist!").await.unwrap();
+                client.chat("hello").await.unwrap();
+                wait_for_message(&mut client, "Chat is disabled here.").await.unwrap();
+
+                let code = manager.lock().await.create_code(None);
+                client.command(&format!("verify {code}")).await.unwrap();
+                // Without a transfer target, verified players are kicked
+                assert!(wait_for_message(&mut client, "never sent").await.is_err());
+                assert!(client.disconnect_reason.unwrap().to_plain().starts_with("Authorization successful!"));
+                assert_eq!(manager.lock().await.get_verified_profile(&code).unwrap().id, profile.id);
+            }).await.unwrap_or_else(|_| panic!("timed out on {}", version.get_name()));
+        }
+    }
+
+    #[tokio::test]
+    async fn online_login_asks_session_server() {
+        let id = Uuid::from_u128(0x5ca1ab1e);
+        let session = warp::path!("session" / "minecraft" / "hasJoined")
+            .and(warp::query::<std::collections::HashMap<String, String>>())
+            .map(move |query: std::collections::HashMap<String, String>| {
+                if query.get("username").map(|s| s.as_str()) == Some("Swan") && query.contains_key("serverId") {
+                    warp::reply::with_status(warp::reply::json(&json!({
+                        "id": id.simple().to_string(), "name": "Swan", "properties": []
+                    })), warp::http::StatusCode::OK)
+                } else {
+                    warp::reply::with_status(warp::reply::json(&json!({})), warp::http::StatusCode::NO_CONTENT)
+                }
+            });
+        let (session_addr, session_server) = warp::serve(session).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(session_server);
+
+        let manager = Arc::new(Mutex::new(AuthManager::new()));
+        let addr = start_server(manager, Some(format!("http://{session_addr}/")), None, None, vec![]).await;
+        tokio::time::timeout(Duration::from_secs(60), async {
+            let mut client = Client::connect(&addr, ProtocolVersion::V1_21).await.unwrap();
+            assert_eq!(client.login("Swan").await.unwrap().id, id);
+            client.configure().await.unwrap();
+            wait_for_message(&mut client, "Welcome!").await.unwrap();
+
+            // The session server doesn't know this player, so the (encrypted) login fails
+            let mut client = Client::connect(&addr, ProtocolVersion::V1_21).await.unwrap();
+            assert!(client.login("Nobody").await.is_err());
+            assert_eq!(client.disconnect_reason.unwrap().to_plain(), "Failed to retrieve Mojang profile");
+        }).await.expect("timed out");
+    }
+
+    #[tokio::test]
+    async fn verification_events_are_streamed() {
+        let manager = Arc::new(Mutex::new(AuthManager::new()));
+        let addr = start_server(manager.clone(), None, None, None, vec![]).await;
+        tokio::time::timeout(Duration::from_secs(60), async {
+            let code = manager.lock().await.create_code(None);
+            let mut events = poll(&manager, &code).await;
+            let mut buffer = String::new();
+
+            let mut client = Client::connect(&addr, ProtocolVersion::V1_21).await.unwrap();
+            client.login("Swan").await.unwrap();
+            client.configure().await.unwrap();
+            wait_for_message(&mut client, "Welcome!").await.unwrap();
+            client.command(&format!("verify {code}")).await.unwrap();
+            assert_eq!(next_event(&mut events, &mut buffer).await.unwrap(), "player_connected");
+            assert_eq!(next_event(&mut events, &mut buffer).await.unwrap(), "verified");
+
+            // Someone else tries the same code
+            let mut client = Client::connect(&addr, ProtocolVersion::V1_21).await.unwrap();
+            client.login("Goose").await.unwrap();
+            client.configure().await.unwrap();
+            wait_for_message(&mut client, "Welcome!").await.unwrap();
+            client.command(&format!("verify {code}")).await.unwrap();
+            assert_eq!(next_event(&mut events, &mut buffer).await.unwrap(), "code_already_used");
+        }).await.expect("timed out");
+    }
+
+    #[tokio::test]
+    async fn unused_codes_expire() {
+        let manager = Arc::new(Mutex::new(AuthManager::new()));
+        let code = manager.lock().await.create_code(None);
+        // The clock may not go back far enough right after boot
+        let created = match Instant::now().checked_sub(CODE_LIFETIME) {
+            Some(created) => created,
+            None => return
+        };
+        manager.lock().await.one_time_codes.get_mut(&code).unwrap().created = created;
+        let mut events = poll(&manager, &code).await;
+        let event = tokio::time::timeout(Duration::from_secs(10), next_event(&mut events, &mut String::new())).await;
+        assert_eq!(event.expect("timed out").unwrap(), "expired");
+    }
+
+    #[tokio::test]
+    async fn verified_player_is_transferred() {
+        let manager = Arc::new(Mutex::new(AuthManager::new()));
+        let transfer = TransferTarget::parse("play.example.com:25566").unwrap();
+        let addr = start_server(manager.clone(), None, Some(transfer), None, vec![]).await;
+        tokio::time::timeout(Duration::from_secs(60), async {
+            let mut client = Client::connect(&addr, ProtocolVersion::V1_21).await.unwrap();
+            client.login("Swan").await.unwrap();
+            client.configure().await.unwrap();
+            wait_for_message(&mut client, "Welcome!").await.unwrap();
+
+            let code = manager.lock().await.create_code(None);
+            client.command(&format!("verify {code}")).await.unwrap();
+            wait_for_message(&mut client, "Authorization successful!").await.unwrap();
+            assert!(client.next_message().await.is_err());
+            assert_eq!(client.transfer, Some(("play.example.com".to_owned(), 25566)));
+        }).await.expect("timed out");
+    }
+
+    #[test]
+    fn transfer_target_addresses() {
+        let parse = |address| TransferTarget::parse(address).map(|t| (t.host, t.port)).ok();
+        assert_eq!(parse("example.com"), Some(("example.com".to_owned(), 25565)));
+        assert_eq!(parse("example.com:25566"), Some(("example.com".to_owned(), 25566)));
+        assert_eq!(parse("::1"), Some(("::1".to_owned(), 25565)));
+        assert_eq!(parse("[::1]"), Some(("::1".to_owned(), 25565)));
+        assert_eq!(parse("[2001:db8::1]:25566"), Some(("2001:db8::1".to_owned(), 25566)));
+        assert_eq!(parse("[::1]25566"), None);
+        assert_eq!(parse("example.com:port"), None);
+    }
+
+    #[tokio::test]
+    async fn remembered_player_links_in_one_click() {
+        let manager = Arc::new(Mutex::new(AuthManager::new()));
+        let addr = start_server(manager.clone(), None, None, Some(b"secret".to_vec()), vec![]).await;
+        tokio::time::timeout(Duration::from_secs(60), async {
+            let mut client = Client::connect(&addr, ProtocolVersion::V1_21).await.unwrap();
+            client.login("Swan").await.unwrap();
+            client.configure().await.unwrap();
+            wait_for_message(&mut client, "Welcome!").await.unwrap();
+            let code = manager.lock().await.create_code(None);
+            client.command(&format!("verify {code}")).await.unwrap();
+            assert!(wait_for_message(&mut client, "never sent").await.is_err());
+            let cookie = client.get_cookie(VERIFIED_COOKIE).cloned().expect("no verification cookie");
+            drop(client);
+
+            // The cookie is asked for during the (compressed) login
+            let browser = manager.lock().await.browsers.keys().next().cloned().unwrap();
+            let code = manager.lock().await.create_code(Some(&browser));
+            let mut client = Client::connect(&addr, ProtocolVersion::V1_21).await.unwrap();
+            client.set_cookie(VERIFIED_COOKIE, cookie);
+            client.login("Swan").await.unwrap();
+            client.configure().await.unwrap();
+            wait_for_message(&mut client, "Welcome back, Swan!").await.unwrap();
+            client.command("link").await.unwrap();
+            assert!(wait_for_message(&mut client, "never sent").await.is_err());
+            assert!(client.disconnect_reason.unwrap().to_plain().starts_with("Authorization successful!"));
+            assert_eq!(manager.lock().await.get_verified_profile(&code).unwrap().name, "Swan");
+        }).await.expect("timed out");
+    }
+
+    #[tokio::test]
+    async fn player_picks_claim_to_preview() {
+        let owner = Profile::offline("Swan").id;
+        let claim = |id: i64, x: i32| -> Claim {
+            serde_json::from_value(json!({
+                "id": id, "owner": owner, "world": "world", "dimension": "minecraft:overworld",
+                "x1": x, "z1": 0, "x2": x + 31, "z2": 31
+            })).unwrap()
+        };
+        let manager = Arc::new(Mutex::new(AuthManager::new()));
+        let addr = start_server(manager, None, None, None, vec![claim(1, 0), claim(2, 1000)]).await;
+        tokio::time::timeout(Duration::from_secs(60), async {
+            let mut client = Client::connect(&addr, ProtocolVersion::V1_21).await.unwrap();
+            client.login("Swan").await.unwrap();
+            client.configure().await.unwrap();
+            wait_for_message(&mut client, "You're previewing claim #1").await.unwrap();
+            client.command("preview #2").await.unwrap();
+            wait_for_message(&mut client, "Now previewing claim #2.").await.unwrap();
+            client.command("preview 3").await.unwrap();
+            wait_for_message(&mut client, "You can't export a claim with ID 3.").await.unwrap();
+            client.command("preview").await.unwrap();
+            wait_for_message(&mut client, "Usage: /preview <claim>").await.unwrap();
+        }).await.expect("timed out");
+    }
+}
\ No newline at end of file
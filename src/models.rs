@@ -1,22 +1,101 @@
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use rand::distributions::DistString;
 use rand::prelude::Distribution;
 use rand::Rng;
 use serde_derive::{Deserialize, Serialize};
-use tokio::sync::mpsc::{Sender, Receiver};
+use serde_json::{json, Value};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
 use tokio::sync::{mpsc, Mutex};
+use uuid::Uuid;
 use crate::Profile;
 
 // Query params for an export request
 #[derive(Debug, Deserialize)]
 pub struct ExportOptions {
+    /// The one-time code the player verified with
+    pub code: String,
     pub world: String,
     pub chunks: Vec<Vec<i32>>,
 }
 
+/// Body of a staff export, whose code comes from the Authorization header
+#[derive(Debug, Deserialize)]
+pub struct SelectionOptions {
+    pub world: String,
+    pub chunks: Vec<Vec<i32>>,
+}
+
+/// Query params for creating a code
+#[derive(Debug, Deserialize)]
+pub struct CreateCodeQuery {
+    /// Token from an earlier verification in this browser, so a remembered player can link it in one click
+    pub browser: Option<String>
+}
+
+/// Cookie key for the proof of verification left on transferred clients
+pub const VERIFIED_COOKIE: &str = "swandist:verified";
+
+/// How long a verification cookie lets a player skip typing a code
+pub const REMEMBER_DURATION: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// Signed and stored on the client after verification, so the main server can show a confirmation
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VerifiedCookie {
+    pub id: Uuid,
+    pub name: String,
+    /// Unix time of the verification
+    pub verified_at: u64
+}
+
 pub type SharedAuthManager = Arc<Mutex<AuthManager>>;
 
+/// How long a one-time code can be used for after it's created
+pub const CODE_LIFETIME: Duration = Duration::from_secs(10 * 60);
+
+/// How long a code can be used to export after the player verifies with it
+pub const VERIFIED_LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+/// Things that happen in-game for a one-time code, streamed to the webmap
+#[derive(Debug, Clone)]
+pub enum AuthEvent {
+    PlayerConnected { name: String },
+    CodeRejected { reason: String },
+    CodeAlreadyUsed,
+    Kicked { reason: String },
+    Expired,
+    /// The browser token lets a later code from the same browser be linked without typing it
+    Verified { profile: Profile, browser: String }
+}
+
+impl AuthEvent {
+    /// The SSE event name
+    pub fn name(&self) -> &'static str {
+        match self {
+            AuthEvent::PlayerConnected { .. } => "player_connected",
+            AuthEvent::CodeRejected { .. } => "code_rejected",
+            AuthEvent::CodeAlreadyUsed => "code_already_used",
+            AuthEvent::Kicked { .. } => "kicked",
+            AuthEvent::Expired => "expired",
+            AuthEvent::Verified { .. } => "verified"
+        }
+    }
+
+    pub fn data(&self) -> Value {
+        match self {
+            AuthEvent::PlayerConnected { name } => json!({ "name": name }),
+            AuthEvent::CodeRejected { reason } | AuthEvent::Kicked { reason } => json!({ "reason": reason }),
+            AuthEvent::Verified { profile, browser } => {
+                let mut data = serde_json::to_value(profile).unwrap();
+                data.as_object_mut().unwrap().insert("browser".to_owned(), json!(browser));
+                data
+            },
+            _ => json!({})
+        }
+    }
+}
+
 struct CodeDist;
 
 impl Distribution<u8> for CodeDist {
@@ -47,46 +126,108 @@ impl DistString for CodeDist {
 #[derive(Debug, Clone)]
 pub struct OneTimeCode {
     pub(crate) used: bool,
-    pub(crate) sender: Sender<Option<Profile>>
+    pub(crate) created: Instant,
+    /// When the player verified with this code
+    pub(crate) verified: Option<Instant>,
+    /// The player who verified with this code
+    pub(crate) profile: Option<Profile>,
+    /// Token of the remembered browser that asked for this code
+    pub(crate) browser: Option<String>,
+    pub(crate) sender: UnboundedSender<AuthEvent>
 }
 
 impl OneTimeCode {
     pub fn new() -> OneTimeCode {
         OneTimeCode {
             used: false,
-            sender: mpsc::channel(1).0  // placeholder
+            created: Instant::now(),
+            verified: None,
+            profile: None,
+            browser: None,
+            sender: mpsc::unbounded_channel().0  // placeholder
         }
     }
 
-    pub fn get_stream(&mut self) -> Receiver<Option<Profile>> {
-        let (sender, receiver) = mpsc::channel(4);
+    /// Events are few per code, and dropping one could leave the webmap waiting forever
+    pub fn get_stream(&mut self) -> UnboundedReceiver<AuthEvent> {
+        let (sender, receiver) = mpsc::unbounded_channel();
         self.sender = sender;
         receiver
     }
 
-    pub fn invalidate(&mut self) {
+    pub fn invalidate(&mut self, profile: Profile) {
         self.used = true;
+        self.verified = Some(Instant::now());
+        self.profile = Some(profile);
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.created.elapsed() > CODE_LIFETIME
+    }
+
+    /// How long until the code expires
+    pub fn time_left(&self) -> Duration {
+        CODE_LIFETIME.saturating_sub(self.created.elapsed())
+    }
+}
+
+/// A browser a player verified in, which can link later codes in one click
+#[derive(Debug, Clone)]
+pub struct RememberedBrowser {
+    pub(crate) player: Uuid,
+    pub(crate) created: Instant
+}
+
+impl RememberedBrowser {
+    pub fn is_expired(&self) -> bool {
+        self.created.elapsed() > REMEMBER_DURATION
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct AuthManager {
-    one_time_codes: HashMap<String, OneTimeCode>
+    pub(crate) one_time_codes: HashMap<String, OneTimeCode>,
+    /// Browsers by their token
+    pub(crate) browsers: HashMap<String, RememberedBrowser>
 }
 
 impl AuthManager {
     pub fn new() -> AuthManager {
         AuthManager {
-            one_time_codes: HashMap::new()
+            one_time_codes: HashMap::new(),
+            browsers: HashMap::new()
         }
     }
 
-    pub fn create_code(&mut self) -> String {
+    /// Creates a code, tied to the player who verified in this browser before if the token is known
+    pub fn create_code(&mut self, browser: Option<&str>) -> String {
         let code = CodeDist.sample_string(&mut rand::thread_rng(), 16);
-        self.one_time_codes.insert(code.clone(), OneTimeCode::new());
+        let mut otc = OneTimeCode::new();
+        self.browsers.retain(|_, b| !b.is_expired());
+        otc.browser = browser.filter(|b| self.browsers.contains_key(*b)).map(|b| b.to_owned());
+        self.one_time_codes.insert(code.clone(), otc);
         code
     }
 
+    /// Hands out a token for a browser a player just verified in
+    pub fn remember_browser(&mut self, player: Uuid) -> String {
+        let token = CodeDist.sample_string(&mut rand::thread_rng(), 32);
+        self.browsers.insert(token.clone(), RememberedBrowser { player, created: Instant::now() });
+        token
+    }
+
+    /// The newest usable code a remembered browser of this player is waiting on
+    pub fn find_remembered_code(&self, player: Uuid) -> Option<String> {
+        self.one_time_codes.iter()
+            .filter(|(_, otc)| !otc.used && !otc.is_expired())
+            .filter(|(_, otc)| match otc.browser.as_ref().and_then(|b| self.browsers.get(b)) {
+                Some(browser) => browser.player == player && !browser.is_expired(),
+                None => false
+            })
+            .max_by_key(|(_, otc)| otc.created)
+            .map(|(code, _)| code.clone())
+    }
+
     pub fn has_code(&self, code: &String) -> bool {
         self.one_time_codes.contains_key(code)
     }
@@ -95,16 +236,77 @@ impl AuthManager {
         self.has_code(code) && self.one_time_codes.get(code).unwrap().used
     }
 
-    pub fn use_code(&mut self, code: &String) -> Option<()> {
-        self.one_time_codes.get_mut(code)?.invalidate();
+    pub fn is_code_expired(&self, code: &String) -> bool {
+        self.has_code(code) && self.one_time_codes.get(code).unwrap().is_expired()
+    }
+
+    pub fn use_code(&mut self, code: &String, profile: Profile) -> Option<()> {
+        self.one_time_codes.get_mut(code)?.invalidate(profile);
         Some(())
     }
 
-    pub fn get_stream(&mut self, code: &String) -> Option<Receiver<Option<Profile>>> {
+    /// Gets the profile of the player who verified with a code, unless that was too long ago
+    pub fn get_verified_profile(&self, code: &String) -> Option<Profile> {
+        let otc = self.one_time_codes.get(code)?;
+        match otc.verified {
+            Some(verified) if verified.elapsed() <= VERIFIED_LIFETIME => otc.profile.clone(),
+            _ => None
+        }
+    }
+
+    pub fn get_stream(&mut self, code: &String) -> Option<UnboundedReceiver<AuthEvent>> {
         Some(self.one_time_codes.get_mut(code)?.get_stream())
     }
 
-    pub fn get_sender(&mut self, code: &String) -> Option<Sender<Option<Profile>>> {
+    pub fn get_time_left(&self, code: &String) -> Option<Duration> {
+        Some(self.one_time_codes.get(code)?.time_left())
+    }
+
+    pub fn get_sender(&mut self, code: &String) -> Option<UnboundedSender<AuthEvent>> {
         Some(self.one_time_codes.get(code)?.sender.clone())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use uuid::Uuid;
+    use crate::models::{AuthManager, REMEMBER_DURATION, VERIFIED_LIFETIME};
+    use crate::server::common::Profile;
+
+    #[test]
+    fn remembered_browser_codes() {
+        let player = Uuid::from_u128(1);
+        let mut manager = AuthManager::new();
+        let browser = manager.remember_browser(player);
+        manager.create_code(None);
+        manager.create_code(Some("unknown"));
+        assert_eq!(manager.find_remembered_code(player), None);
+        let code = manager.create_code(Some(&browser));
+        assert_eq!(manager.find_remembered_code(player), Some(code));
+        assert_eq!(manager.find_remembered_code(Uuid::from_u128(2)), None);
+
+        // The clock may not go back far enough right after boot
+        if let Some(created) = Instant::now().checked_sub(REMEMBER_DURATION + Duration::from_secs(1)) {
+            manager.browsers.get_mut(&browser).unwrap().created = created;
+            assert_eq!(manager.find_remembered_code(player), None);
+            manager.create_code(Some(&browser));
+            assert!(manager.browsers.is_empty());
+        }
+    }
+
+    #[test]
+    fn verified_codes_expire() {
+        let mut manager = AuthManager::new();
+        let code = manager.create_code(None);
+        assert!(manager.get_verified_profile(&code).is_none());
+        manager.use_code(&code, Profile::offline("Swan"));
+        assert_eq!(manager.get_verified_profile(&code).unwrap().name, "Swan");
+
+        // The clock may not go back far enough right after boot
+        if let Some(verified) = Instant::now().checked_sub(VERIFIED_LIFETIME + Duration::from_secs(1)) {
+            manager.one_time_codes.get_mut(&code).unwrap().verified = Some(verified);
+            assert!(manager.get_verified_profile(&code).is_none());
+        }
+    }
+}
\ No newline at end of file
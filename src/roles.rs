@@ -0,0 +1,148 @@
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use anyhow::Context;
+use log::{info, warn};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use uuid::Uuid;
+use crate::server::access::{parse_json, WatchedFile};
+use crate::server::common::Profile;
+
+/// What a player may do through the web API, from least to most privileged
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// Can only export their own claims
+    User,
+    /// Can export anything and look up other players' claims
+    Moderator,
+    Admin
+}
+
+impl Role {
+    /// Maps a vanilla op permission level to a role
+    pub fn from_op_level(level: u8) -> Role {
+        match level {
+            4.. => Role::Admin,
+            3 => Role::Moderator,  // can kick and ban
+            _ => Role::User
+        }
+    }
+}
+
+/// Roles handed out by UUID, on top of whatever `ops.json` says
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct RoleConfig {
+    #[serde(default)]
+    pub(crate) admins: Vec<Uuid>,
+    #[serde(default)]
+    pub(crate) moderators: Vec<Uuid>
+}
+
+impl RoleConfig {
+    /// Loads a config as TOML if it has a `.toml` extension and JSON otherwise
+    pub fn load(path: &Path) -> anyhow::Result<RoleConfig> {
+        let data = fs::read_to_string(path).with_context(|| format!("could not read {}", path.display()))?;
+        if path.extension().and_then(|e| e.to_str()) == Some("toml") {
+            Ok(toml::from_str(&data)?)
+        } else {
+            Ok(serde_json::from_str(&data)?)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct OpEntry {
+    uuid: Uuid,
+    #[serde(default)]
+    level: u8
+}
+
+pub struct Roles {
+    config: RoleConfig,
+    ops: Mutex<WatchedFile<Vec<OpEntry>>>
+}
+
+pub type SharedRoles = Arc<Roles>;
+
+impl Roles {
+    pub fn new(server_dir: &Path, config: RoleConfig) -> Roles {
+        Roles {
+            config,
+            ops: Mutex::new(WatchedFile::new(server_dir.join("ops.json"), parse_json))
+        }
+    }
+
+    /// The highest role a player has from either the config or `ops.json`
+    pub fn get_role(&self, player: Uuid) -> Role {
+        let configured = if self.config.admins.contains(&player) {
+            Role::Admin
+        } else if self.config.moderators.contains(&player) {
+            Role::Moderator
+        } else {
+            Role::User
+        };
+        let op = self.ops.lock().unwrap().get().iter()
+            .find(|o| o.uuid == player)
+            .map(|o| Role::from_op_level(o.level))
+            .unwrap_or(Role::User);
+        configured.max(op)
+    }
+}
+
+/// Append-only log of staff actions, one JSON object per line
+pub struct AuditLog {
+    file: Option<Mutex<File>>
+}
+
+pub type SharedAuditLog = Arc<AuditLog>;
+
+impl AuditLog {
+    pub fn open(path: &Path) -> anyhow::Result<AuditLog> {
+        let file = OpenOptions::new().create(true).append(true).open(path)
+            .with_context(|| format!("could not open {}", path.display()))?;
+        Ok(AuditLog { file: Some(Mutex::new(file)) })
+    }
+
+    /// An audit log that only goes to the console
+    pub fn console() -> AuditLog {
+        AuditLog { file: None }
+    }
+
+    pub fn record(&self, actor: &Profile, role: Role, action: &str, allowed: bool, details: Value) {
+        info!("[audit] {} ({}, {:?}) {} {}: {}", actor.name, actor.id, role,
+              if allowed { "used" } else { "was denied" }, action, details);
+
+        if let Some(file) = &self.file {
+            let entry = json!({
+                "time": SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+                "actor": { "id": actor.id, "name": actor.name },
+                "role": role,
+                "action": action,
+                "allowed": allowed,
+                "details": details
+            });
+            if let Err(e) = writeln!(file.lock().unwrap(), "{}", entry) {
+                warn!("failed to write audit log: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::roles::Role;
+
+    #[test]
+    fn test_role_from_op_level() {
+        assert_eq!(Role::from_op_level(0), Role::User);
+        assert_eq!(Role::from_op_level(2), Role::User);
+        assert_eq!(Role::from_op_level(3), Role::Moderator);
+        assert_eq!(Role::from_op_level(4), Role::Admin);
+        assert!(Role::Admin > Role::Moderator && Role::Moderator > Role::User);
+    }
+}
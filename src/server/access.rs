@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use log::{info, warn};
+use serde_derive::Deserialize;
+use uuid::Uuid;
+use crate::server::common::Profile;
+
+/// A file that's parsed again whenever its modification time changes
+pub(crate) struct WatchedFile<T> {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    value: T,
+    parse: fn(&str) -> anyhow::Result<T>
+}
+
+impl<T: Default> WatchedFile<T> {
+    pub(crate) fn new(path: PathBuf, parse: fn(&str) -> anyhow::Result<T>) -> WatchedFile<T> {
+        WatchedFile { path, modified: None, value: T::default(), parse }
+    }
+
+    pub(crate) fn get(&mut self) -> &T {
+        let modified = fs::metadata(&self.path).and_then(|m| m.modified()).ok();
+        if modified != self.modified {
+            self.modified = modified;
+            // A missing file is the same as an empty one
+            let data = fs::read_to_string(&self.path).unwrap_or_default();
+            match (self.parse)(&data) {
+                Ok(value) => {
+                    info!("Loaded {}", self.path.display());
+                    self.value = value;
+                },
+                Err(e) => warn!("failed to parse {}, keeping the old version: {}", self.path.display(), e)
+            }
+        }
+        &self.value
+    }
+}
+
+pub(crate) fn parse_json<T: serde::de::DeserializeOwned + Default>(data: &str) -> anyhow::Result<T> {
+    if data.trim().is_empty() {
+        return Ok(T::default());
+    }
+    Ok(serde_json::from_str(data)?)
+}
+
+fn parse_properties(data: &str) -> anyhow::Result<HashMap<String, String>> {
+    Ok(data.lines()
+        .filter(|l| !l.starts_with('#'))
+        .filter_map(|l| l.split_once('='))
+        .map(|(k, v)| (k.trim().to_owned(), v.trim().to_owned()))
+        .collect())
+}
+
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// Parses the `yyyy-MM-dd HH:mm:ss Z` dates vanilla uses in ban lists
+fn parse_date(date: &str) -> Option<SystemTime> {
+    let mut parts = date.split_whitespace();
+    let ymd: Vec<i64> = parts.next()?.split('-').map(|p| p.parse().ok()).collect::<Option<_>>()?;
+    let hms: Vec<i64> = parts.next()?.split(':').map(|p| p.parse().ok()).collect::<Option<_>>()?;
+    let zone = parts.next().unwrap_or("+0000");
+    if ymd.len() != 3 || hms.len() != 3 || zone.len() != 5 {
+        return None;
+    }
+    let sign = if zone.starts_with('-') { -1 } else { 1 };
+    let offset = sign * (zone[1..3].parse::<i64>().ok()? * 3600 + zone[3..5].parse::<i64>().ok()? * 60);
+
+    let secs = days_from_civil(ymd[0], ymd[1], ymd[2]) * 86400 + hms[0] * 3600 + hms[1] * 60 + hms[2] - offset;
+    Some(UNIX_EPOCH + Duration::from_secs(u64::try_from(secs).ok()?))
+}
+
+fn forever() -> String {
+    "forever".to_owned()
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct WhitelistEntry {
+    uuid: Uuid
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct BanEntry {
+    #[serde(default)]
+    uuid: Option<Uuid>,
+    #[serde(default)]
+    ip: Option<String>,
+    #[serde(default = "forever")]
+    expires: String,
+    #[serde(default)]
+    reason: Option<String>
+}
+
+impl BanEntry {
+    fn is_active(&self) -> bool {
+        match parse_date(&self.expires) {
+            Some(expires) => expires > SystemTime::now(),
+            None => true  // "forever"
+        }
+    }
+
+    fn message(&self, prefix: &str) -> String {
+        let mut message = format!("{}\nReason: {}", prefix, self.reason.clone().unwrap_or_else(|| "Banned by an operator.".to_owned()));
+        if self.expires != "forever" {
+            message.push_str(&format!("\nYour ban will be removed on {}", self.expires));
+        }
+        message
+    }
+}
+
+/// The whitelist and ban lists of a vanilla-style server directory
+pub struct AccessLists {
+    properties: WatchedFile<HashMap<String, String>>,
+    whitelist: WatchedFile<Vec<WhitelistEntry>>,
+    banned_players: WatchedFile<Vec<BanEntry>>,
+    banned_ips: WatchedFile<Vec<BanEntry>>
+}
+
+impl AccessLists {
+    pub fn new(server_dir: &Path) -> AccessLists {
+        AccessLists {
+            properties: WatchedFile::new(server_dir.join("server.properties"), parse_properties),
+            whitelist: WatchedFile::new(server_dir.join("whitelist.json"), parse_json),
+            banned_players: WatchedFile::new(server_dir.join("banned-players.json"), parse_json),
+            banned_ips: WatchedFile::new(server_dir.join("banned-ips.json"), parse_json)
+        }
+    }
+
+    /// Returns the disconnect reason if a player isn't allowed in
+    pub fn check(&mut self, profile: &Profile, address: Option<IpAddr>) -> Option<String> {
+        if let Some(ban) = self.banned_players.get().iter().find(|b| b.uuid == Some(profile.id) && b.is_active()) {
+            return Some(ban.message("You are banned from this server."));
+        }
+        if let Some(address) = address {
+            let address = address.to_string();
+            if let Some(ban) = self.banned_ips.get().iter().find(|b| b.ip.as_ref() == Some(&address) && b.is_active()) {
+                return Some(ban.message("Your IP address is banned from this server."));
+            }
+        }
+        let whitelist_enabled = self.properties.get().get("white-list").map(|v| v == "true").unwrap_or(false);
+        if whitelist_enabled && !self.whitelist.get().iter().any(|w| w.uuid == profile.id) {
+            return Some("You are not white-listed on this server!".to_owned());
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+    use crate::server::access::parse_date;
+
+    #[test]
+    fn test_parse_date() {
+        assert_eq!(parse_date("1970-01-02 00:00:00 +0000"), Some(UNIX_EPOCH + Duration::from_secs(86400)));
+        assert_eq!(parse_date("2024-03-01 12:30:00 +0100"), Some(UNIX_EPOCH + Duration::from_secs(1709292600)));
+        assert_eq!(parse_date("forever"), None);
+    }
+}
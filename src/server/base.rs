@@ -3,15 +3,34 @@ use bytes::Bytes;
 use rsa::RsaPrivateKey;
 use tokio::net::TcpListener;
 use tokio::sync::{mpsc, Mutex};
+use uuid::Uuid;
+use crate::server::access::AccessLists;
+use crate::server::common::Profile;
 use crate::server::connection::ClientConnection;
+use crate::server::forwarding::ForwardingMode;
 use crate::server::handler::{DefaultPacketHandler, PacketHandler};
+use crate::server::players::PlayerRegistry;
+use crate::server::status::{LegacyPingFormat, StatusCache, StatusConfig};
 use crate::server::text::TextComponent;
+use crate::server::version::ProtocolVersion;
 use crate::Tag;
 
+pub const MOJANG_SESSION_SERVER: &str = "https://sessionserver.mojang.com";
+
 pub struct Server {
-    pub(crate) max_players: i32,
-    pub(crate) motd: TextComponent,
+    pub(crate) status: StatusCache,
+    /// Everyone who has finished logging in
+    pub(crate) players: PlayerRegistry,
     pub(crate) key: RsaPrivateKey,
+    pub(crate) forwarding: ForwardingMode,
+    pub(crate) access_lists: Option<AccessLists>,
+    /// Packets at least this large are compressed; negative disables compression
+    pub(crate) compression_threshold: i32,
+    /// The most chunks in each direction sent around a player, who may ask for fewer
+    pub(crate) view_distance: u8,
+    /// Whether players are authenticated with the session server and the connection encrypted
+    pub(crate) online_mode: bool,
+    pub(crate) session_server: String,
     handler_factory: Box<dyn Fn() -> Box<dyn PacketHandler + Send>>,
 }
 
@@ -22,24 +41,87 @@ unsafe impl Send for Server {
 impl Server {
     pub fn new() -> Server {
         Server {
-            max_players: 0,
-            motd: TextComponent::plain("A Minecraft Server"),
+            status: StatusCache::new(StatusConfig::default()),
+            players: PlayerRegistry::new(),
             key: RsaPrivateKey::new(&mut rand::thread_rng(), 2048).expect("failed to generate a key"),
+            forwarding: ForwardingMode::None,
+            access_lists: None,
+            compression_threshold: 256,
+            view_distance: 10,
+            online_mode: true,
+            session_server: MOJANG_SESSION_SERVER.to_owned(),
             handler_factory: Box::new(|| Box::new(DefaultPacketHandler::new()))
         }
     }
 
     pub fn set_motd(&mut self, motd: TextComponent) {
-        self.motd = motd;
+        self.status.set_motd(motd);
+    }
+
+    /// Replaces the server list entry, loading its favicon once
+    pub fn set_status(&mut self, config: StatusConfig) {
+        self.status = StatusCache::new(config);
+    }
+
+    /// The status JSON for a client, rebuilt only when something in it changes
+    pub fn status_response(&mut self, v: ProtocolVersion) -> String {
+        self.status.get(v, &self.players)
+    }
+
+    /// The kick message answering a legacy ping, which always shows our newest version
+    pub fn legacy_status_response(&self, format: LegacyPingFormat) -> String {
+        self.status.build(ProtocolVersion::latest_supported(), &self.players).finish_legacy(format)
+    }
+
+    pub fn add_player(&mut self, profile: &Profile) {
+        self.players.add(profile);
+        self.status.invalidate();
+    }
+
+    pub fn remove_player(&mut self, id: Uuid) {
+        if self.players.remove(id) {
+            self.status.invalidate();
+        }
+    }
+
+    pub fn set_forwarding(&mut self, forwarding: ForwardingMode) {
+        self.forwarding = forwarding;
+    }
+
+    /// Checks players against a server directory's whitelist and ban lists during login
+    pub fn set_access_lists(&mut self, access_lists: AccessLists) {
+        self.access_lists = Some(access_lists);
+    }
+
+    pub fn set_compression_threshold(&mut self, threshold: i32) {
+        self.compression_threshold = threshold;
+    }
+
+    pub fn set_view_distance(&mut self, view_distance: u8) {
+        self.view_distance = view_distance;
+    }
+
+    /// Offline mode skips authentication and gives players vanilla's offline UUIDs
+    pub fn set_online_mode(&mut self, online_mode: bool) {
+        self.online_mode = online_mode;
+    }
+
+    /// Where joins are checked in online mode, e.g. a mock session server in tests
+    pub fn set_session_server(&mut self, url: &str) {
+        self.session_server = url.trim_end_matches('/').to_owned();
     }
 
     pub fn set_handler_factory(&mut self, factory: impl Fn() -> Box<dyn PacketHandler + Send> + 'static) {
         self.handler_factory = Box::new(factory);
     }
 
-    pub async fn start(mut self, addr: &str) -> anyhow::Result<()> {
+    pub async fn start(self, addr: &str) -> anyhow::Result<()> {
+        self.serve(TcpListener::bind(addr).await?).await
+    }
+
+    /// Accepts players on a listener that's already bound, like one on an ephemeral port
+    pub async fn serve(self, listener: TcpListener) -> anyhow::Result<()> {
         let self_arc = Arc::new(Mutex::new(self));
-        let listener = TcpListener::bind(addr).await?;
 
         loop {
             // Asynchronously wait for an inbound socket.
@@ -0,0 +1,293 @@
+use std::collections::HashMap;
+use anyhow::{anyhow, bail};
+use bytes::Bytes;
+use rand::RngCore;
+use rsa::{BigUint, Pkcs1v15Encrypt, RsaPublicKey};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use crate::server::codec::{FrameDecoder, FrameEncoder};
+use crate::server::common::{ClientInfo, Profile};
+use crate::server::enums::{Arm, ChatVisibility};
+use crate::server::packets::c2s::config::{ClientInfoC2S, CookieResponseC2S as ConfigCookieResponseC2S, KeepAliveC2S, PongC2S, ReadyC2S, SelectKnownPacksC2S};
+use crate::server::packets::c2s::handshake::HandshakeC2S;
+use crate::server::packets::c2s::login::{CookieResponseC2S, EnterConfigurationC2S, LoginHelloC2S, LoginKeyC2S, LoginQueryResponseC2S};
+use crate::server::packets::c2s::play::{ChatC2S, ChatCommandC2S, PlayKeepAliveC2S};
+use crate::server::packets::c2s::status::{PingRequestC2S, StatusRequestC2S};
+use crate::server::packets::packet::{DecodeS2C, PacketC2S};
+use crate::server::packets::s2c::config::{ConfigDisconnectS2C, CookieRequestS2C, KeepAliveS2C, PingS2C, ReadyS2C, SelectKnownPacksS2C, ServerTransferS2C, StoreCookieS2C};
+use crate::server::packets::s2c::login::{LoginCompressionS2C, LoginCookieRequestS2C, LoginDisconnectS2C, LoginHelloS2C, LoginQueryRequestS2C, LoginSuccessS2C};
+use crate::server::packets::s2c::play::{GameMessageS2C, KeepAliveS2C as PlayKeepAliveS2C, PlayDisconnectS2C, PlayStoreCookieS2C, PlayTransferS2C};
+use crate::server::packets::s2c::status::{PingResponseS2C, StatusResponseS2C};
+use crate::server::packets::stage::Stage;
+use crate::server::text::TextComponent;
+use crate::server::utils::read_varint;
+use crate::server::version::ProtocolVersion;
+
+/// A headless client for driving a `Server` without the game, e.g. in tests.
+/// It never contacts a session server itself, so the server has to be in offline mode
+/// or ask a mock session server that vouches for anyone.
+pub struct Client {
+    stream: TcpStream,
+    decoder: FrameDecoder,
+    encoder: FrameEncoder,
+    version: ProtocolVersion,
+    stage: Stage,
+    /// Cookies the server stored, sent back whenever it asks for them
+    cookies: HashMap<String, Bytes>,
+    /// Why the server last disconnected us
+    pub(crate) disconnect_reason: Option<TextComponent>,
+    /// Where the server last sent us
+    pub(crate) transfer: Option<(String, u16)>
+}
+
+impl Client {
+    pub async fn connect(addr: &str, version: ProtocolVersion) -> anyhow::Result<Client> {
+        Ok(Client {
+            stream: TcpStream::connect(addr).await?,
+            decoder: FrameDecoder::new(),
+            encoder: FrameEncoder::new(),
+            version,
+            stage: Stage::Handshake,
+            cookies: HashMap::new(),
+            disconnect_reason: None,
+            transfer: None
+        })
+    }
+
+    pub fn get_stage(&self) -> Stage {
+        self.stage
+    }
+
+    pub fn set_cookie(&mut self, key: &str, payload: Bytes) {
+        self.cookies.insert(key.to_owned(), payload);
+    }
+
+    pub fn get_cookie(&self, key: &str) -> Option<&Bytes> {
+        self.cookies.get(key)
+    }
+
+    pub async fn send<P: PacketC2S>(&mut self, packet: &P) -> anyhow::Result<()> {
+        let frame = self.encoder.encode(P::id(self.version), &packet.encode(self.version))?;
+        self.stream.write_all(&frame[..]).await?;
+        Ok(())
+    }
+
+    /// Waits for the next packet, returning its ID and body
+    pub async fn receive(&mut self) -> anyhow::Result<(i32, Bytes)> {
+        let mut read_buf = [0u8; 4096];
+        loop {
+            if let Some(mut frame) = self.decoder.try_next_frame()? {
+                let id = read_varint(&mut frame)?;
+                return Ok((id, frame));
+            }
+            let num_read = self.stream.read(&mut read_buf).await?;
+            if num_read == 0 {
+                bail!("connection closed");
+            }
+            self.decoder.queue_bytes(&read_buf[..num_read]);
+        }
+    }
+
+    fn is<P: DecodeS2C>(&self, id: i32) -> bool {
+        id == P::id(self.version)
+    }
+
+    fn decode<P: DecodeS2C>(&self, mut body: Bytes) -> anyhow::Result<P> {
+        Ok(P::decode(&mut body, self.version)?)
+    }
+
+    fn disconnected(&mut self, reason: TextComponent) -> anyhow::Error {
+        let error = anyhow!("disconnected: {}", reason.to_plain());
+        self.disconnect_reason = Some(reason);
+        error
+    }
+
+    async fn handshake(&mut self, next_stage: Stage) -> anyhow::Result<()> {
+        let address = self.stream.peer_addr()?;
+        self.send(&HandshakeC2S {
+            version: self.version,
+            address: address.ip().to_string(),
+            port: address.port(),
+            next_stage
+        }).await?;
+        self.stage = next_stage;
+        Ok(())
+    }
+
+    /// Asks for the server list entry, returning its JSON
+    pub async fn status(&mut self) -> anyhow::Result<String> {
+        self.handshake(Stage::Status).await?;
+        self.send(&StatusRequestC2S {}).await?;
+        loop {
+            let (id, body) = self.receive().await?;
+            if self.is::<StatusResponseS2C>(id) {
+                return Ok(self.decode::<StatusResponseS2C>(body)?.response);
+            }
+        }
+    }
+
+    /// Pings after a status request, returning the payload the server echoed
+    pub async fn ping(&mut self, payload: u64) -> anyhow::Result<u64> {
+        self.send(&PingRequestC2S { payload }).await?;
+        loop {
+            let (id, body) = self.receive().await?;
+            if self.is::<PingResponseS2C>(id) {
+                return Ok(self.decode::<PingResponseS2C>(body)?.payload);
+            }
+        }
+    }
+
+    /// Logs in as a player, returning the profile the server gave us.
+    /// Encryption, compression, cookies and plugin queries are handled along the way.
+    pub async fn login(&mut self, name: &str) -> anyhow::Result<Profile> {
+        self.handshake(Stage::Login).await?;
+        self.send(&LoginHelloC2S {
+            name: name.to_owned(),
+            uuid: Some(Profile::offline(name).id),
+            expires_at: None,
+            public_key: None,
+            signature: None
+        }).await?;
+
+        loop {
+            let (id, body) = self.receive().await?;
+            if self.is::<LoginDisconnectS2C>(id) {
+                let packet = self.decode::<LoginDisconnectS2C>(body)?;
+                return Err(self.disconnected(packet.reason));
+            } else if self.is::<LoginHelloS2C>(id) {
+                let packet = self.decode::<LoginHelloS2C>(body)?;
+                let (n, e) = rsa_der::public_key_from_der(&packet.public_key)
+                    .map_err(|e| anyhow!("invalid server key: {e:?}"))?;
+                let key = RsaPublicKey::new(BigUint::from_bytes_be(&n), BigUint::from_bytes_be(&e))?;
+                let mut secret = [0u8; 16];
+                rand::thread_rng().fill_bytes(&mut secret);
+                self.send(&LoginKeyC2S {
+                    shared_secret: key.encrypt(&mut rand::thread_rng(), Pkcs1v15Encrypt, &secret)?.into(),
+                    nonce: Some(key.encrypt(&mut rand::thread_rng(), Pkcs1v15Encrypt, &packet.nonce)?.into()),
+                    salt: None,
+                    message_signature: None
+                }).await?;
+                self.encoder.enable_encryption(&secret);
+                self.decoder.enable_encryption(&secret);
+            } else if self.is::<LoginCompressionS2C>(id) {
+                let packet = self.decode::<LoginCompressionS2C>(body)?;
+                self.encoder.set_compression(if packet.threshold >= 0 { Some(packet.threshold as usize) } else { None });
+                self.decoder.set_compression(packet.threshold >= 0);
+            } else if self.is::<LoginQueryRequestS2C>(id) {
+                // We don't understand any plugin channels
+                let packet = self.decode::<LoginQueryRequestS2C>(body)?;
+                self.send(&LoginQueryResponseC2S {
+                    query_id: packet.query_id,
+                    successful: false,
+                    response: Bytes::new()
+                }).await?;
+            } else if self.is::<LoginCookieRequestS2C>(id) {
+                let packet = self.decode::<LoginCookieRequestS2C>(body)?;
+                let payload = self.cookies.get(&packet.key).cloned();
+                self.send(&CookieResponseC2S { key: packet.key, payload }).await?;
+            } else if self.is::<LoginSuccessS2C>(id) {
+                let packet = self.decode::<LoginSuccessS2C>(body)?;
+                // Before 1.20.2, this switches the stage to Play
+                if self.version >= ProtocolVersion::V1_20_2 {
+                    self.send(&EnterConfigurationC2S {}).await?;
+                    self.stage = Stage::Config;
+                } else {
+                    self.stage = Stage::Play;
+                }
+                return Ok(packet.profile);
+            }
+        }
+    }
+
+    /// Goes through the configuration stage (1.20.2+) until the server lets us play
+    pub async fn configure(&mut self) -> anyhow::Result<()> {
+        if self.stage != Stage::Config {
+            return Ok(());
+        }
+        self.send(&ClientInfoC2S {
+            client_info: ClientInfo {
+                lang: "en_us".to_owned(),
+                view_distance: 2,
+                chat_visibility: ChatVisibility::Full,
+                chat_colors_enabled: true,
+                player_model_parts: 0,
+                main_arm: Arm::Right,
+                filters_text: false,
+                allows_server_listing: true
+            }
+        }).await?;
+
+        loop {
+            let (id, body) = self.receive().await?;
+            if self.is::<ConfigDisconnectS2C>(id) {
+                let packet = self.decode::<ConfigDisconnectS2C>(body)?;
+                return Err(self.disconnected(packet.reason));
+            } else if self.is::<KeepAliveS2C>(id) {
+                let packet = self.decode::<KeepAliveS2C>(body)?;
+                self.send(&KeepAliveC2S { id: packet.payload }).await?;
+            } else if self.is::<PingS2C>(id) {
+                let packet = self.decode::<PingS2C>(body)?;
+                self.send(&PongC2S { id: packet.parameter }).await?;
+            } else if self.is::<SelectKnownPacksS2C>(id) {
+                // We have whatever the server has
+                let packet = self.decode::<SelectKnownPacksS2C>(body)?;
+                self.send(&SelectKnownPacksC2S { known_packs: packet.known_packs }).await?;
+            } else if self.is::<CookieRequestS2C>(id) {
+                let packet = self.decode::<CookieRequestS2C>(body)?;
+                let payload = self.cookies.get(&packet.key).cloned();
+                self.send(&ConfigCookieResponseC2S { key: packet.key, payload }).await?;
+            } else if self.is::<StoreCookieS2C>(id) {
+                let packet = self.decode::<StoreCookieS2C>(body)?;
+                self.cookies.insert(packet.key, packet.payload);
+            } else if self.is::<ServerTransferS2C>(id) {
+                let packet = self.decode::<ServerTransferS2C>(body)?;
+                self.transfer = Some((packet.host.clone(), packet.port));
+                bail!("transferred to {}:{}", packet.host, packet.port);
+            } else if self.is::<ReadyS2C>(id) {
+                self.send(&ReadyC2S {}).await?;
+                self.stage = Stage::Play;
+                return Ok(());
+            }
+        }
+    }
+
+    pub async fn chat(&mut self, message: &str) -> anyhow::Result<()> {
+        self.send(&ChatC2S {
+            message: message.to_owned(),
+            timestamp: 0,
+            salt: 0,
+            signature: None,
+            message_count: 0,
+            acknowledged: 0
+        }).await
+    }
+
+    /// Runs a command, which is given without the leading slash
+    pub async fn command(&mut self, command: &str) -> anyhow::Result<()> {
+        self.send(&ChatCommandC2S { command: command.to_owned() }).await
+    }
+
+    /// Waits for the next system chat message, answering keepalives and skipping everything else
+    /// the server sends while playing
+    pub async fn next_message(&mut self) -> anyhow::Result<TextComponent> {
+        loop {
+            let (id, body) = self.receive().await?;
+            if self.is::<PlayDisconnectS2C>(id) {
+                let packet = self.decode::<PlayDisconnectS2C>(body)?;
+                return Err(self.disconnected(packet.reason));
+            } else if self.is::<GameMessageS2C>(id) {
+                return Ok(self.decode::<GameMessageS2C>(body)?.text);
+            } else if self.is::<PlayKeepAliveS2C>(id) {
+                let packet = self.decode::<PlayKeepAliveS2C>(body)?;
+                self.send(&PlayKeepAliveC2S { id: packet.payload }).await?;
+            } else if self.is::<PlayStoreCookieS2C>(id) {
+                let packet = self.decode::<PlayStoreCookieS2C>(body)?;
+                self.cookies.insert(packet.key, packet.payload);
+            } else if self.is::<PlayTransferS2C>(id) {
+                let packet = self.decode::<PlayTransferS2C>(body)?;
+                self.transfer = Some((packet.host.clone(), packet.port));
+                bail!("transferred to {}:{}", packet.host, packet.port);
+            }
+        }
+    }
+}
@@ -0,0 +1,192 @@
+use aes::cipher::{BlockDecryptMut, BlockEncryptMut, BlockSizeUser, KeyIvInit, generic_array::GenericArray};
+use anyhow::anyhow;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use crate::server::compression::{compress_packet, decompress_packet};
+use crate::server::utils::write_varint;
+
+pub type EncCipher = cfb8::Encryptor<aes::Aes128>;
+pub type DecCipher = cfb8::Decryptor<aes::Aes128>;
+
+/// Frame lengths are limited to 3-byte VarInts
+pub const MAX_FRAME_LENGTH: usize = (1 << 21) - 1;
+
+/// Splits a stream of (possibly encrypted and compressed) bytes into packets.
+/// Bytes can be queued in whatever pieces they arrive in.
+pub struct FrameDecoder {
+    buf: BytesMut,
+    cipher: Option<DecCipher>,
+    compressed: bool
+}
+
+impl FrameDecoder {
+    pub fn new() -> FrameDecoder {
+        FrameDecoder {
+            buf: BytesMut::new(),
+            cipher: None,
+            compressed: false
+        }
+    }
+
+    pub fn enable_encryption(&mut self, secret: &[u8]) {
+        let mut cipher = DecCipher::new_from_slices(secret, secret).unwrap();
+        // Whatever is still buffered was sent after the client turned encryption on
+        for chunk in self.buf.chunks_mut(DecCipher::block_size()) {
+            cipher.decrypt_block_mut(GenericArray::from_mut_slice(chunk));
+        }
+        self.cipher = Some(cipher);
+    }
+
+    pub fn set_compression(&mut self, compressed: bool) {
+        self.compressed = compressed;
+    }
+
+    pub fn queue_bytes(&mut self, bytes: &[u8]) {
+        let start = self.buf.len();
+        self.buf.extend_from_slice(bytes);
+        if let Some(cipher) = &mut self.cipher {
+            for chunk in self.buf[start..].chunks_mut(DecCipher::block_size()) {
+                cipher.decrypt_block_mut(GenericArray::from_mut_slice(chunk));
+            }
+        }
+    }
+
+    /// The next buffered byte, without consuming it
+    pub fn peek(&self) -> Option<u8> {
+        self.buf.first().copied()
+    }
+
+    /// Everything buffered but not yet consumed as a frame
+    pub fn buffered(&self) -> &[u8] {
+        &self.buf[..]
+    }
+
+    /// Returns the next complete packet (ID and body), or None if more bytes are needed
+    pub fn try_next_frame(&mut self) -> anyhow::Result<Option<Bytes>> {
+        let mut length = 0usize;
+        let mut header = 0;
+        loop {
+            if header == 3 {
+                return Err(anyhow!("frame length is longer than 3 bytes"));
+            }
+            let byte = match self.buf.get(header) {
+                Some(b) => *b,
+                None => return Ok(None)
+            };
+            length |= ((byte & 0b01111111) as usize) << (header * 7);
+            header += 1;
+            if byte & 0b10000000 == 0 {
+                break;
+            }
+        }
+
+        if length == 0 {
+            return Err(anyhow!("empty frame"));
+        }
+        if self.buf.len() < header + length {
+            return Ok(None);
+        }
+
+        self.buf.advance(header);
+        let frame = self.buf.split_to(length).freeze();
+        if self.compressed {
+            Ok(Some(decompress_packet(frame)?))
+        } else {
+            Ok(Some(frame))
+        }
+    }
+}
+
+/// Turns packets into length-prefixed frames, compressing and encrypting them when enabled
+pub struct FrameEncoder {
+    cipher: Option<EncCipher>,
+    threshold: Option<usize>
+}
+
+impl FrameEncoder {
+    pub fn new() -> FrameEncoder {
+        FrameEncoder {
+            cipher: None,
+            threshold: None
+        }
+    }
+
+    pub fn enable_encryption(&mut self, secret: &[u8]) {
+        self.cipher = Some(EncCipher::new_from_slices(secret, secret).unwrap());
+    }
+
+    pub fn set_compression(&mut self, threshold: Option<usize>) {
+        self.threshold = threshold;
+    }
+
+    pub fn encode(&mut self, id: i32, body: &[u8]) -> anyhow::Result<BytesMut> {
+        let mut data = BytesMut::new();
+        write_varint(&mut data, id);
+        data.put(body);
+        if let Some(threshold) = self.threshold {
+            data = compress_packet(&data, threshold);
+        }
+        if data.len() > MAX_FRAME_LENGTH {
+            return Err(anyhow!("packet {} is too large ({} bytes)", id, data.len()));
+        }
+
+        let mut frame = BytesMut::new();
+        write_varint(&mut frame, data.len() as i32);
+        frame.put(data);
+
+        if let Some(cipher) = &mut self.cipher {
+            for chunk in frame.chunks_mut(EncCipher::block_size()) {
+                cipher.encrypt_block_mut(GenericArray::from_mut_slice(chunk));
+            }
+        }
+        Ok(frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server::codec::{FrameDecoder, FrameEncoder};
+
+    #[test]
+    fn test_partial_and_coalesced_frames() {
+        let mut encoder = FrameEncoder::new();
+        let mut data = encoder.encode(1, b"first").unwrap();
+        data.extend_from_slice(&encoder.encode(2, &[0u8; 300]).unwrap());
+
+        let mut decoder = FrameDecoder::new();
+        // Split the second frame's length prefix across reads
+        decoder.queue_bytes(&data[..8]);
+        assert_eq!(&decoder.try_next_frame().unwrap().unwrap()[..], b"\x01first");
+        assert!(decoder.try_next_frame().unwrap().is_none());
+        decoder.queue_bytes(&data[8..]);
+        assert_eq!(decoder.try_next_frame().unwrap().unwrap().len(), 301);
+        assert!(decoder.try_next_frame().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_encrypted_compressed_frames() {
+        let secret = [7u8; 16];
+        let mut encoder = FrameEncoder::new();
+        encoder.enable_encryption(&secret);
+        encoder.set_compression(Some(64));
+        let mut decoder = FrameDecoder::new();
+        decoder.enable_encryption(&secret);
+        decoder.set_compression(true);
+
+        for body in [vec![1u8; 10], vec![2u8; 1000]] {
+            let frame = encoder.encode(5, &body).unwrap();
+            for byte in frame.iter() {
+                decoder.queue_bytes(&[*byte]);
+            }
+            let packet = decoder.try_next_frame().unwrap().unwrap();
+            assert_eq!(packet[0], 5);
+            assert_eq!(&packet[1..], &body[..]);
+        }
+    }
+
+    #[test]
+    fn test_length_limit() {
+        let mut decoder = FrameDecoder::new();
+        decoder.queue_bytes(&[0xff, 0xff, 0xff, 0x01]);
+        assert!(decoder.try_next_frame().is_err());
+    }
+}
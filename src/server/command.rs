@@ -0,0 +1,124 @@
+use bytes::{BufMut, BytesMut};
+use crate::server::utils::{write_string, write_varint};
+use crate::server::version::ProtocolVersion;
+
+/// A simple command with single-word string arguments
+#[derive(Debug, Clone)]
+pub struct CommandSpec {
+    pub(crate) name: String,
+    pub(crate) arguments: Vec<String>,
+    pub(crate) description: String
+}
+
+impl CommandSpec {
+    pub fn new(name: &str, arguments: &[&str], description: &str) -> CommandSpec {
+        CommandSpec {
+            name: name.to_owned(),
+            arguments: arguments.iter().map(|a| a.to_string()).collect(),
+            description: description.to_owned()
+        }
+    }
+
+    /// Usage string, e.g. `/verify <code>`
+    pub fn usage(&self) -> String {
+        let mut usage = format!("/{}", self.name);
+        for arg in &self.arguments {
+            usage.push_str(&format!(" <{}>", arg));
+        }
+        usage
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum CommandNodeType {
+    Root,
+    Literal(String),
+    /// A `brigadier:string` argument; we only use single words
+    Argument(String)
+}
+
+/// A node in the Brigadier command graph
+#[derive(Debug, Clone)]
+pub struct CommandNode {
+    pub(crate) node_type: CommandNodeType,
+    pub(crate) executable: bool,
+    pub(crate) children: Vec<i32>,
+    pub(crate) redirect: Option<i32>
+}
+
+impl CommandNode {
+    pub fn write_to(&self, buf: &mut BytesMut, v: ProtocolVersion) {
+        let mut flags = match self.node_type {
+            CommandNodeType::Root => 0u8,
+            CommandNodeType::Literal(_) => 1,
+            CommandNodeType::Argument(_) => 2
+        };
+        if self.executable {
+            flags |= 0x04;
+        }
+        if self.redirect.is_some() {
+            flags |= 0x08;
+        }
+        buf.put_u8(flags);
+
+        write_varint(buf, self.children.len() as i32);
+        for child in &self.children {
+            write_varint(buf, *child);
+        }
+        if let Some(redirect) = self.redirect {
+            write_varint(buf, redirect);
+        }
+
+        match &self.node_type {
+            CommandNodeType::Root => {},
+            CommandNodeType::Literal(name) => write_string(buf, name),
+            CommandNodeType::Argument(name) => {
+                write_string(buf, name);
+                // 1.19 switched parsers from identifiers to registry IDs
+                if v >= ProtocolVersion::V1_19 {
+                    write_varint(buf, 5);  // brigadier:string
+                } else {
+                    write_string(buf, "brigadier:string");
+                }
+                write_varint(buf, 0);  // single word
+            }
+        }
+    }
+
+    /// Flattens a list of commands into a graph, with the root at index 0
+    pub fn build_graph(commands: &[CommandSpec]) -> Vec<CommandNode> {
+        let mut nodes = vec![CommandNode {
+            node_type: CommandNodeType::Root,
+            executable: false,
+            children: vec![],
+            redirect: None
+        }];
+
+        for command in commands {
+            let literal = nodes.len() as i32;
+            nodes[0].children.push(literal);
+            nodes.push(CommandNode {
+                node_type: CommandNodeType::Literal(command.name.clone()),
+                executable: command.arguments.is_empty(),
+                children: vec![],
+                redirect: None
+            });
+
+            // Each argument is the only child of the one before it
+            let mut parent = literal as usize;
+            for (i, arg) in command.arguments.iter().enumerate() {
+                let index = nodes.len();
+                nodes[parent].children.push(index as i32);
+                nodes.push(CommandNode {
+                    node_type: CommandNodeType::Argument(arg.clone()),
+                    executable: i == command.arguments.len() - 1,
+                    children: vec![],
+                    redirect: None
+                });
+                parent = index;
+            }
+        }
+
+        nodes
+    }
+}
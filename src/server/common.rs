@@ -64,3 +64,18 @@ pub struct Profile {
     pub(crate) name: String,
     pub(crate) properties: Vec<ProfileProperty>  // 1.19+ (759)
 }
+
+impl Profile {
+    /// The profile an offline-mode server gives a player, with vanilla's name-based UUID
+    pub fn offline(name: &str) -> Profile {
+        let digest = openssl::hash::hash(openssl::hash::MessageDigest::md5(), format!("OfflinePlayer:{name}").as_bytes())
+            .expect("MD5 is always available");
+        let mut bytes = [0u8; 16];
+        bytes.copy_from_slice(&digest[..]);
+        Profile {
+            id: uuid::Builder::from_md5_bytes(bytes).into_uuid(),
+            name: name.to_owned(),
+            properties: vec![]
+        }
+    }
+}
@@ -0,0 +1,62 @@
+use std::io::{Read, Write};
+use anyhow::anyhow;
+use bytes::{BufMut, Bytes, BytesMut};
+use flate2::Compression;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use crate::server::utils::{read_varint, write_varint};
+
+/// The largest uncompressed packet vanilla will accept
+pub const MAX_UNCOMPRESSED_SIZE: usize = 8 * 1024 * 1024;
+
+/// Wraps a packet (ID and body) in the compressed format. Packets under the
+/// threshold are sent as-is behind a data length of 0.
+pub fn compress_packet(data: &[u8], threshold: usize) -> BytesMut {
+    let mut buf = BytesMut::new();
+    if data.len() < threshold {
+        write_varint(&mut buf, 0);
+        buf.put(data);
+    } else {
+        write_varint(&mut buf, data.len() as i32);
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        buf.put(&encoder.finish().unwrap()[..]);
+    }
+    buf
+}
+
+/// Unwraps a packet in the compressed format, returning its ID and body
+pub fn decompress_packet(mut data: Bytes) -> anyhow::Result<Bytes> {
+    let data_length = read_varint(&mut data).map_err(|e| anyhow!("invalid data length: {e}"))? as usize;
+    if data_length == 0 {
+        return Ok(data);
+    }
+    if data_length > MAX_UNCOMPRESSED_SIZE {
+        return Err(anyhow!("packet too large ({} bytes)", data_length));
+    }
+
+    let mut out = Vec::with_capacity(data_length);
+    ZlibDecoder::new(&data[..]).take(data_length as u64).read_to_end(&mut out)?;
+    if out.len() != data_length {
+        return Err(anyhow!("expected {} bytes after decompression, got {}", data_length, out.len()));
+    }
+    Ok(Bytes::from(out))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server::compression::{compress_packet, decompress_packet};
+
+    #[test]
+    fn test_round_trip() {
+        let small = b"\x01hello".to_vec();
+        let compressed = compress_packet(&small, 256);
+        assert_eq!(compressed[0], 0);
+        assert_eq!(&decompress_packet(compressed.freeze()).unwrap()[..], &small[..]);
+
+        let large = vec![7u8; 1000];
+        let compressed = compress_packet(&large, 256);
+        assert!(compressed.len() < large.len());
+        assert_eq!(&decompress_packet(compressed.freeze()).unwrap()[..], &large[..]);
+    }
+}
@@ -1,52 +1,49 @@
 use std::collections::HashMap;
 use std::io::Cursor;
+use std::net::IpAddr;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
-use bytes::{Bytes, BytesMut, BufMut};
+use bytes::{Bytes, BufMut};
 use core::time::Duration;
-use image::ImageReader;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
+use tokio::net::tcp::WriteHalf;
 use tokio::sync::{mpsc, Mutex};
 use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
 use log::{info, trace, warn};
-use aes::cipher::{BlockEncryptMut, BlockDecryptMut, BlockSizeUser, KeyIvInit, generic_array::GenericArray, AsyncStreamCipher};
 use crypto::blockmodes::{PaddingProcessor, PkcsPadding};
 use num_bigint::BigInt;
 use rand::RngCore;
 use reqwest::StatusCode;
+use rsa::RsaPrivateKey;
 use rsa::traits::PublicKeyParts;
 use serde_derive::Deserialize;
 use sha1::{Sha1, Digest};
 use tokio::{task, time};
 use uuid::Uuid;
 use crate::{Region, Server, Tag};
-use crate::chunk::Chunk;
 use crate::server::common::{ClientInfo, Profile};
+use crate::server::codec::{FrameDecoder, FrameEncoder};
+use crate::server::forwarding::{parse_bungeecord_address, read_velocity_response, ForwardedPlayer, ForwardingMode, VELOCITY_CHANNEL, VELOCITY_DEFAULT_VERSION};
 use crate::server::handler::PacketHandler;
-use crate::server::packets::c2s::config::{ClientInfoC2S, CustomPayloadC2S, KeepAliveC2S, PongC2S, ReadyC2S, ResourcePackStatus, ResourcePackStatusC2S, SelectKnownPacksC2S, CookieResponseC2S as ConfigCookieResponseC2S, VersionedIdentifier};
+use crate::server::packets::c2s::config::{ClientInfoC2S, CustomPayloadC2S, KeepAliveC2S, PongC2S, ReadyC2S, ResourcePackStatusC2S, SelectKnownPacksC2S, CookieResponseC2S as ConfigCookieResponseC2S, VersionedIdentifier};
 use crate::server::packets::c2s::handshake::HandshakeC2S;
 use crate::server::packets::c2s::login::{CookieResponseC2S, EnterConfigurationC2S, LoginHelloC2S, LoginKeyC2S, LoginQueryResponseC2S};
-use crate::server::packets::c2s::play::ChatC2S;
+use crate::server::packets::c2s::play::{ChatC2S, ChatCommandC2S, PlayKeepAliveC2S, PlayerPositionC2S, PlayerPositionRotationC2S, PlayerRotationC2S};
 use crate::server::packets::c2s::status::{PingRequestC2S, StatusRequestC2S};
 use crate::server::packets::packet::{PacketS2C, PacketC2S};
-use crate::server::packets::s2c::config::{CustomPayloadS2C, DynamicRegistriesS2C, FeaturesS2C, Link, LinkLabel, LinksS2C, ReadyS2C, RegistryEntry, SelectKnownPacksS2C};
-use crate::server::packets::s2c::login::{LoginDisconnectS2C, LoginHelloS2C, LoginSuccessS2C};
-use crate::server::packets::s2c::play::{ChunkDataS2C, EventType, GameEventS2C, GameMessageS2C, JoinGameS2C, KeepAliveS2C, SyncPlayerPositionS2C};
+use crate::server::packets::ids;
+use crate::server::packets::s2c::config::{ConfigDisconnectS2C, DynamicRegistriesS2C, FeaturesS2C, ReadyS2C, RegistryCodecS2C, RegistryEntry, SelectKnownPacksS2C};
+use crate::server::packets::s2c::login::{LoginCompressionS2C, LoginCookieRequestS2C, LoginDisconnectS2C, LoginHelloS2C, LoginQueryRequestS2C, LoginSuccessS2C};
+use crate::server::packets::s2c::play::{ChunkDataS2C, EventType, GameEventS2C, JoinGameS2C, KeepAliveS2C, PlayDisconnectS2C, SetCenterChunkS2C, SyncPlayerPositionS2C, UnloadChunkS2C};
 use crate::server::packets::s2c::status::{PingResponseS2C, StatusResponseS2C};
 use crate::server::packets::stage::Stage;
-use crate::server::status::StatusBuilder;
-use crate::server::text::{ChatColor, HoverEvent, TextComponent};
-use crate::server::utils::{read_varint, write_string, write_varint};
+use crate::server::registry::{registry_data, REGISTRY_DEFAULT};
+use crate::server::status::{legacy_kick, LegacyPingFormat};
+use crate::server::text::{HoverEvent, TextComponent};
+use crate::server::utils::{read_varint, write_varint};
 use crate::server::version::ProtocolVersion;
-
-type EncCipher = cfb8::Encryptor<aes::Aes128>;
-type DecCipher = cfb8::Decryptor<aes::Aes128>;
-
-static REGISTRY_121: &[u8] = include_bytes!("registry_1.21.nbt");
-static REGISTRY_1206: &[u8] = include_bytes!("registry_1.20.6.nbt");
-static REGISTRY_1194: &[u8] = include_bytes!("registry_1.19.4.nbt");
-static REGISTRY_DEFAULT: &[u8] = include_bytes!("registry.nbt");
+use crate::server::world::{chunk_pos, ChunkSource, ChunkView, WorldPreview};
 
 macro_rules! tri_handle {
     ($($t:tt)+) => {
@@ -75,6 +72,28 @@ macro_rules! packet_case {
     };
 }
 
+/// Unwraps a decoded packet, or disconnects the client that sent a malformed one
+macro_rules! decode_or_disconnect {
+    ($decoded:expr, $conn:ident, $write:ident, $label:lifetime) => {
+        match $decoded {
+            Ok(packet) => packet,
+            Err(e) => {
+                warn!("{e}");
+                $conn.disconnect_now(&mut $write, TextComponent::plain("Received a malformed packet")).await;
+                break $label;
+            }
+        }
+    };
+}
+
+/// The server's public key as DER. rsa_der reads the bytes as signed, so the modulus needs a
+/// leading zero byte or it's encoded as a negative number.
+pub fn public_key_der(key: &RsaPrivateKey) -> Vec<u8> {
+    let mut n = vec![0u8];
+    n.extend_from_slice(&key.n().to_bytes_be());
+    rsa_der::public_key_to_der(&n, &key.e().to_bytes_be())
+}
+
 pub fn sha_digest(sha: Sha1) -> String {
     let mut sha_bytes = sha.finalize();
     BigInt::from_signed_bytes_be(&sha_bytes).to_str_radix(16)
@@ -86,9 +105,23 @@ pub struct ClientConnection {
     auth_nonce: Mutex<Option<Bytes>>,
     secret: Option<Vec<u8>>,
     username: Mutex<String>,
-    enc_cipher: Option<EncCipher>,
-    dec_cipher: Option<DecCipher>,
+    encoder: FrameEncoder,
+    decoder: FrameDecoder,
     client_info: ClientInfo,
+    /// Player info from a BungeeCord handshake, completed at login
+    forwarded: Option<ForwardedPlayer>,
+    /// ID of our pending `velocity:player_info` query
+    velocity_query_id: Option<i32>,
+    /// The player's address, as forwarded by a proxy if there is one
+    address: Option<IpAddr>,
+    /// Threshold to switch to once our set compression packet is written
+    pending_compression: Option<usize>,
+    /// Set once the player is counted in the server's player registry
+    registered: Option<Uuid>,
+    /// Chunks the client has, shared with the task loading them
+    chunk_view: Option<Arc<std::sync::Mutex<ChunkView>>>,
+    /// Chunks for the loading task to send
+    chunk_requests: Option<UnboundedSender<(i32, i32)>>,
     parent: Arc<Mutex<Server>>  // shared globally
 }
 
@@ -104,24 +137,211 @@ impl ClientConnection {
             auth_nonce: Mutex::new(None),
             secret: None,
             username: Mutex::new("Offline".to_owned()),
-            enc_cipher: None,
-            dec_cipher: None,
+            encoder: FrameEncoder::new(),
+            decoder: FrameDecoder::new(),
             client_info: Default::default(),
+            forwarded: None,
+            velocity_query_id: None,
+            address: None,
+            pending_compression: None,
+            registered: None,
+            chunk_view: None,
+            chunk_requests: None,
             parent
         }
 
     }
 
-    fn maybe_decrypt(&mut self, block: &mut [u8]) {
-        if self.dec_cipher.is_some() {
-            for chunk in block.chunks_mut(DecCipher::block_size()) {
-                let gen_arr = GenericArray::from_mut_slice(chunk);
-                self.dec_cipher.as_mut().unwrap().decrypt_block_mut(gen_arr);
+    /// Sends the login success for an authenticated (or forwarded) profile
+    async fn finish_login(&mut self, profile: Profile, v: ProtocolVersion) {
+        let rejection = {
+            let mut parent = self.parent.lock().await;
+            match &mut parent.access_lists {
+                Some(access_lists) => access_lists.check(&profile, self.address),
+                None => None
+            }
+        };
+        if let Some(reason) = rejection {
+            info!("{} ({}) was denied access: {}", profile.name, profile.id, reason.replace('\n', " "));
+            self.handler.lock().await.kick(TextComponent::plain(&reason)).unwrap();
+            return;
+        }
+
+        // update our username if necessary
+        {
+            *self.username.lock().await = profile.name.clone();
+        }
+
+        // update our profile on the handler
+        {
+            self.handler.lock().await.set_profile(profile.clone()).await;
+        }
+
+        let threshold = {
+            self.parent.lock().await.compression_threshold
+        };
+        if threshold >= 0 {
+            self.handler.lock().await.send_packet(Box::new(LoginCompressionS2C { threshold })).unwrap();
+            self.pending_compression = Some(threshold as usize);
+            // The client compresses everything it sends after receiving it, so nothing may be asked of it
+            // before this, or its answer would arrive uncompressed
+            self.decoder.set_compression(true);
+        }
+
+        // Cookie requests have to come before the login success, which ends the login stage
+        if v >= ProtocolVersion::V1_20_5 {
+            let mut handler = self.handler.lock().await;
+            for key in handler.get_login_cookies() {
+                handler.send_packet(Box::new(LoginCookieRequestS2C { key })).unwrap();
+            }
+        }
+
+        {
+            self.parent.lock().await.add_player(&profile);
+            self.registered = Some(profile.id);
+        }
+
+        {
+            let mut handler = self.handler.lock().await;
+            handler.send_packet(Box::new(LoginSuccessS2C {
+                profile,
+                strict_error_handling: false
+            })).unwrap();
+
+            // Before 1.20.2, this switches the stage to Play
+            if v < ProtocolVersion::V1_20_2 {
+                handler.set_stage(Stage::Play);
+            }
+            tri_handle!(handler.on_login_success().await);
+        }
+        if v < ProtocolVersion::V1_20_2 {
+            self.send_game_join().await;
+        }
+    }
+
+    /// How far to send chunks: what the client asked for, up to the server's limit
+    async fn view_distance(&self) -> i32 {
+        let max = self.parent.lock().await.view_distance;
+        self.client_info.view_distance.min(max).max(2) as i32
+    }
+
+    /// Starts the task that loads and sends chunks, then sends the ones around the spawn
+    async fn start_chunk_streaming(&mut self, preview: Option<WorldPreview>, spawn: (f64, f64, f64)) {
+        let view = Arc::new(std::sync::Mutex::new(ChunkView::new(self.view_distance().await)));
+        let (requests, mut pending) = mpsc::unbounded_channel::<(i32, i32)>();
+        self.chunk_view = Some(view.clone());
+        self.chunk_requests = Some(requests);
+
+        let chunk_handler = self.handler.clone();
+        // Region files are read synchronously
+        task::spawn_blocking(move || {
+            let mut source = ChunkSource::new(preview.as_ref());
+            // Ends once the connection drops its sender
+            while let Some((x, z)) = pending.blocking_recv() {
+                // The player may have moved away while this was queued
+                if !view.lock().unwrap().is_loaded((x, z)) {
+                    continue;
+                }
+                let chunk = match source.get_chunk(x, z) {
+                    Some(chunk) => chunk,
+                    None => continue
+                };
+                let mut handler = chunk_handler.blocking_lock();
+                // Or the view was cleared while the chunk was read
+                if !view.lock().unwrap().is_loaded((x, z)) {
+                    continue;
+                }
+                if handler.send_packet(Box::new(ChunkDataS2C {
+                    x,
+                    z,
+                    heightmaps: chunk.heightmaps(),
+                    chunk
+                })).is_err() {
+                    break;  // the player left
+                }
+            }
+        });
+
+        self.move_to(spawn.0, spawn.2).await;
+    }
+
+    /// Replaces what the player sees: unloads every chunk, moves them to the new preview's spawn and streams it from there
+    async fn switch_preview(&mut self, preview: WorldPreview) {
+        // The old loading task skips whatever isn't loaded anymore, and stops once its sender is replaced
+        let unload = match &self.chunk_view {
+            Some(view) => view.lock().unwrap().clear(),
+            None => return
+        };
+        let spawn = preview.spawn;
+        {
+            let mut handler = self.handler.lock().await;
+            for (x, z) in unload {
+                tri_handle!(handler.send_packet(Box::new(UnloadChunkS2C { x, z })));
+            }
+            tri_handle!(handler.send_packet(Box::new(SyncPlayerPositionS2C {
+                x: spawn.0,
+                y: spawn.1,
+                z: spawn.2,
+                yaw: 0.0,
+                pitch: 0.0,
+                flags: 0,
+                teleport_id: 0,
+                dismount: true
+            })));
+        }
+        self.start_chunk_streaming(Some(preview), spawn).await;
+    }
+
+    /// Loads and unloads chunks as the player crosses chunk borders
+    async fn move_to(&mut self, x: f64, z: f64) {
+        let view = match &self.chunk_view {
+            Some(view) => view.clone(),
+            None => return
+        };
+        let center = chunk_pos(x, z);
+        let (load, unload) = {
+            let mut view = view.lock().unwrap();
+            if view.center() == center && view.is_loaded(center) {
+                return;
+            }
+            view.move_to(center)
+        };
+
+        {
+            let mut handler = self.handler.lock().await;
+            // The client drops chunks too far from its center chunk
+            tri_handle!(handler.send_packet(Box::new(SetCenterChunkS2C { x: center.0, z: center.1 })));
+            for (x, z) in unload {
+                tri_handle!(handler.send_packet(Box::new(UnloadChunkS2C { x, z })));
+            }
+        }
+        if let Some(requests) = &self.chunk_requests {
+            for chunk in load {
+                let _ = requests.send(chunk);
+            }
+        }
+    }
+
+    /// Writes a disconnect packet straight to the socket, for when the connection is about to close
+    async fn disconnect_now(&mut self, write_half: &mut WriteHalf<'_>, reason: TextComponent) {
+        let v = self.version.lock().await.clone();
+        let packet: Box<dyn PacketS2C + Send> = {
+            let mut handler = self.handler.lock().await;
+            handler.on_kick(&reason);
+            match handler.get_stage() {
+                Stage::Login => Box::new(LoginDisconnectS2C { reason }),
+                Stage::Config => Box::new(ConfigDisconnectS2C { reason }),
+                Stage::Play => Box::new(PlayDisconnectS2C { reason }),
+                _ => return
             }
+        };
+        if let Ok(frame) = self.encoder.encode(packet.id(v), &packet.encode(v)) {
+            let _ = write_half.write_all(&frame[..]).await;
         }
     }
 
     async fn send_game_join(&self) {
+        let view_distance = self.view_distance().await;
         let mut handler = self.handler.lock().await;
         handler.send_packet(Box::new(JoinGameS2C {
             entity_id: 123,
@@ -132,12 +352,12 @@ impl ClientConnection {
             registry_codec: Tag::parse(&mut Bytes::from(REGISTRY_DEFAULT)),
             legacy_dimension_nbt: Tag::Compound(HashMap::new()),
             max_players: 1,
-            view_distance: 0,
+            view_distance,
             simulation_distance: 1,
             reduced_debug_info: false,
             enable_respawn_screen: false,
             do_limited_crafting: false,
-            legacy_dimension_type: "".to_string(),
+            legacy_dimension_type: "minecraft:overworld".to_string(),
             legacy_dimension: 0,
             legacy_level_type: "".to_string(),
             dimension_type: 0,
@@ -150,6 +370,8 @@ impl ClientConnection {
             portal_cooldown: 20,
             enforces_secure_chat: false
         })).unwrap();
+        tri_handle!(handler.send_commands());
+        tri_handle!(handler.on_play_ready().await);
         // println!("sent join");
     }
 
@@ -162,610 +384,546 @@ impl ClientConnection {
         let key = {
             self.parent.lock().await.key.clone()
         };
+        let forwarding = {
+            self.parent.lock().await.forwarding.clone()
+        };
+        let (online_mode, session_server) = {
+            let parent = self.parent.lock().await;
+            (parent.online_mode, parent.session_server.clone())
+        };
 
         let handler_arc = self.handler.clone();
 
+        self.address = socket.peer_addr().ok().map(|a| a.ip());
+
         let (mut read_half, mut write_half) = socket.split();
 
-        loop {
-            let mut first_byte = vec![0u8; 1];
+        let mut read_buf = [0u8; 4096];
+        'conn: loop {
             tokio::select! {
                 Some(m) = rx.recv() => {
                     let v = {
                         self.version.lock().await.clone()
                     };
                     let body = m.encode(v);
-                    let mut temp_writer = BytesMut::new();
-                    write_varint(&mut temp_writer, m.id(v));
-                    temp_writer.put(body);
-                    let mut packet_writer = BytesMut::new();
-                    write_varint(&mut packet_writer, temp_writer.len() as i32);
-                    packet_writer.put(temp_writer);
-
-                    if self.enc_cipher.is_some() {
-                        for chunk in packet_writer.chunks_mut(EncCipher::block_size()) {
-                            let gen_arr = GenericArray::from_mut_slice(chunk);
-                            self.enc_cipher.as_mut().unwrap().encrypt_block_mut(gen_arr);
+                    let packet_id = m.id(v);
+                    if packet_id < 0 {
+                        warn!("skipping a packet that doesn't exist in {}", v.get_name());
+                        continue;
+                    }
+                    let frame = match self.encoder.encode(packet_id, &body) {
+                        Ok(f) => f,
+                        Err(e) => {
+                            warn!("failed to encode packet: {e}");
+                            continue;
                         }
                     };
 
                     match write_half
-                        .write_all(&packet_writer[..])
+                        .write_all(&frame[..])
                         .await {
                         Ok(_) => {},
                         Err(_) => break
                     }
-                }
-                Ok(first_byte_size) = read_half.read(&mut first_byte) => {
-                    let stage = {
-                        handler_arc.lock().await.get_stage().clone()
-                    };
 
-                    if first_byte_size != 1 {  // should be 1 unless the connection closed
-                        break;
+                    // Nothing with the same ID is queued ahead of the set compression packet,
+                    // so this is it and everything after it gets compressed
+                    if self.pending_compression.is_some() && packet_id == ids::clientbound(Stage::Login, "login_compression", v) {
+                        self.encoder.set_compression(self.pending_compression.take());
                     }
-
-                    // modified reader to read from the socket
-                    // FIXME: this is kind of a mess, but it works fine
-                    self.maybe_decrypt(&mut first_byte[..]);
-                    let mut num = first_byte[0] as i32;
-
-                    if stage == Stage::Handshake && num == 0xFE {
-                        // Legacy ping, close the connection since we aren't a legacy server
+                }
+                Ok(num_read) = read_half.read(&mut read_buf) => {
+                    if num_read == 0 {  // the connection closed
                         break;
                     }
+                    self.decoder.queue_bytes(&read_buf[..num_read]);
+
+                    // A client may send several packets at once, so handle everything that's complete
+                    loop {
+                        let stage = {
+                            handler_arc.lock().await.get_stage().clone()
+                        };
+
+                        if stage == Stage::Handshake && self.decoder.peek() == Some(0xFE) {
+                            // Legacy ping, answer with a kick message and close the connection
+                            let format = LegacyPingFormat::detect(self.decoder.buffered());
+                            let response = self.parent.lock().await.legacy_status_response(format);
+                            let _ = write_half.write_all(&legacy_kick(&response)).await;
+                            break 'conn;
+                        }
 
-                    if num & 0b10000000 != 0 {  // is the packet larger than 127 bytes?
-                        num &= 0b01111111;
-                        let mut i = 1;
-                        loop {
-                            if let Ok(n) = read_half.read(&mut first_byte).await {
-                                if n != 1 {
-                                    warn!("partial decode!");
-                                    continue;
-                                }
-                                self.maybe_decrypt(&mut first_byte[..]);
-                                num |= (i32::from(first_byte[0]) & 0b01111111) << (i * 7);
-                                if first_byte[0] & 0b10000000 == 0 {
-                                    break;
-                                }
-                                i += 1;
+                        let mut reader = match self.decoder.try_next_frame() {
+                            Ok(Some(frame)) => frame,
+                            Ok(None) => break,
+                            Err(e) => {
+                                warn!("invalid frame: {e}");
+                                break 'conn;
                             }
-                        }
-                    }
+                        };
+
+                        let v = {
+                            self.version.lock().await.clone()
+                        };
+
+                        let packet_type = decode_or_disconnect!(read_varint(&mut reader), self, write_half, 'conn);
+                        // println!("got packet {} of size {} during stage {:?}", packet_type, num, stage);
+
+
+                        match stage {
+                            // HANDSHAKE ------------------------------------------------------
+                            Stage::Handshake => {
+                                // Handled internally by default
+                                packet_case!(
+                                    packet_type = HandshakeC2S @ v => {
+                                        let mut packet = decode_or_disconnect!(HandshakeC2S::decode(&mut reader, v), self, write_half, 'conn);
+                                        *self.version.lock().await = packet.version;
+
+                                        if let ForwardingMode::BungeeCord = forwarding {
+                                            if packet.next_stage == Stage::Login {
+                                                // Players without forwarded data are kicked at login
+                                                if let Some((host, player)) = parse_bungeecord_address(&packet.address) {
+                                                    packet.address = host;
+                                                    self.forwarded = Some(player);
+                                                }
+                                            }
+                                        }
 
-                    let num = num as usize;
+                                        let mut h = handler_arc.lock().await;
+                                        h.set_stage(packet.next_stage);
+                                        h.set_version(packet.version);
+                                        tri_handle!(h.on_handshake(packet).await);
+                                    },
+                                    ?? => {
 
-                    if num == 0 {
-                        break;
-                    }
+                                    }
+                                );
+                            },
+                            // STATUS ---------------------------------------------------------
+                            Stage::Status => {
+                                packet_case!(
+                                    packet_type = StatusRequestC2S @ v => {
+                                        let packet = decode_or_disconnect!(StatusRequestC2S::decode(&mut reader, v), self, write_half, 'conn);
+                                        // Clients outside our range see the newest version we support
+                                        let status = self.parent.lock().await.status_response(if v.is_supported() { v } else { ProtocolVersion::latest_supported() });
+                                        tri_handle!(handler_arc.lock().await.send_packet(Box::new(StatusResponseS2C::new(status))));
+                                        tri_handle!(handler_arc.lock().await.on_status_request(packet).await);
+                                    },
+                                    packet_type = PingRequestC2S @ v => {
+                                        let packet = decode_or_disconnect!(PingRequestC2S::decode(&mut reader, v), self, write_half, 'conn);
+                                        tri_handle!(handler_arc.lock().await.send_packet(Box::new(PingResponseS2C::new(packet.payload))));
+                                        tri_handle!(handler_arc.lock().await.on_ping_request(packet).await);
+                                    },
+                                    ?? => {
 
-                    let mut buf = vec![0u8; num];
-                    if let Ok(num_read) = read_half.read(&mut buf[..]).await {
-                        if num_read != num {
-                            warn!("buffer size mismatch! expected {}, but got {} -- this may cause issues!", num, num_read);
-                        }
-                    } else {
-                        warn!("invalid read!");
-                        continue;
-                    };
+                                    }
+                                );
+                            },
+                            // LOGIN ----------------------------------------------------------
+                            Stage::Login => {
+                                packet_case!(
+                                    packet_type = LoginHelloC2S @ v => {
+                                        if !v.is_supported() {
+                                            self.handler.lock().await.kick(TextComponent::plain(format!("Unsupported client! Please use {}", ProtocolVersion::supported_range_name()).as_str())).unwrap();
+                                            continue;
+                                        }
+                                        let packet = decode_or_disconnect!(LoginHelloC2S::decode(&mut reader, v), self, write_half, 'conn);
+                                        // println!("{:?}", packet);
+                                        {
+                                            *self.username.lock().await = packet.name.clone();
+                                        }
 
-                    let v = {
-                        self.version.lock().await.clone()
-                    };
+                                        let proceed = match handler_arc.lock().await.on_login_start(packet.clone()).await {
+                                            Ok(proceed) => proceed,
+                                            Err(e) => {
+                                                warn!("error in packet handler: {e}");
+                                                true
+                                            }
+                                        };
+                                        if !proceed {
+                                            continue;
+                                        }
 
-                    self.maybe_decrypt(&mut buf);
-
-                    let mut reader = Bytes::from(buf);
-
-                    let packet_type = read_varint(&mut reader);
-                    // println!("got packet {} of size {} during stage {:?}", packet_type, num, stage);
-
-
-                    match stage {
-                        // HANDSHAKE ------------------------------------------------------
-                        Stage::Handshake => {
-                            // Handled internally by default
-                            packet_case!(
-                                packet_type = HandshakeC2S @ v => {
-                                    let packet = HandshakeC2S::decode(&mut reader, v);
-                                    *self.version.lock().await = packet.version;
-
-                                    let mut h = handler_arc.lock().await;
-                                    h.set_stage(packet.next_stage);
-                                    tri_handle!(h.on_handshake(packet).await);
-                                },
-                                ?? => {
-
-                                }
-                            );
-                        },
-                        // STATUS ---------------------------------------------------------
-                        Stage::Status => {
-                            packet_case!(
-                                packet_type = StatusRequestC2S @ v => {
-                                    let packet = StatusRequestC2S::decode(&mut reader, v);
-                                    let mut description = TextComponent::new();
-                                    description.set_text("SwanCraft World Download");
-                                    description.set_gradient(&[ChatColor::Aqua, ChatColor::LightPurple]);
-                                    tri_handle!(handler_arc.lock().await.send_packet(Box::new(StatusResponseS2C::new(
-                                        StatusBuilder::new(v)
-                                        .with_description(description)
-                                        .with_favicon(ImageReader::open("favicon.png").unwrap().decode().unwrap())
-                                        .finish()
-                                    ))));
-                                    tri_handle!(handler_arc.lock().await.on_status_request(packet).await);
-                                },
-                                packet_type = PingRequestC2S @ v => {
-                                    let packet = PingRequestC2S::decode(&mut reader, v);
-                                    tri_handle!(handler_arc.lock().await.send_packet(Box::new(PingResponseS2C::new(packet.payload))));
-                                    tri_handle!(handler_arc.lock().await.on_ping_request(packet).await);
-                                },
-                                ?? => {
-
-                                }
-                            );
-                        },
-                        // LOGIN ----------------------------------------------------------
-                        Stage::Login => {
-                            packet_case!(
-                                packet_type = LoginHelloC2S @ v => {
-                                    if v != ProtocolVersion::V1_21 {
-                                        self.handler.lock().await.kick(TextComponent::plain(format!("Outdated client! Please use {}", ProtocolVersion::V1_21.get_name()).as_str())).unwrap();
-                                        continue;
-                                    }
-                                    let packet = LoginHelloC2S::decode(&mut reader, v);
-                                    // println!("{:?}", packet);
-                                    {
-                                        *self.username.lock().await = packet.name;
-                                    }
-                                    // Send an encryption response
-                                    let key_bytes = {
-                                        rsa_der::public_key_to_der(&key.n().to_bytes_be(), &key.e().to_bytes_be())
-                                    };
-                                    let mut verify_token = &mut [0u8; 4];
-                                    rand::thread_rng().fill_bytes(&mut verify_token[..]);
-                                    {
-                                        *self.auth_nonce.lock().await = Some(Bytes::copy_from_slice(verify_token));
-                                    }
-                                    let packet = LoginHelloS2C {
-                                        server_id: "".to_owned(),
-                                        public_key: key_bytes.into(),
-                                        nonce: Bytes::copy_from_slice(verify_token),
-                                        needs_authentication: true
-                                    };
-                                    self.handler.lock().await.send_packet(Box::new(packet)).unwrap();
-                                },
-                                packet_type = LoginKeyC2S @ v => {
-                                    let packet = LoginKeyC2S::decode(&mut reader, v);
-                                    // Set up encryption
-                                    let (secret, sha) = {
-                                        let secret = key.decrypt(rsa::Pkcs1v15Encrypt, &packet.shared_secret).unwrap();
-                                        if let Some(encrypted_nonce) = &packet.nonce {
-                                            let nonce = key.decrypt(rsa::Pkcs1v15Encrypt, &encrypted_nonce[..]).unwrap();
-                                            {
-                                                let our_nonce = self.auth_nonce.lock().await.clone();
-                                                if let Some(check) = our_nonce {
-                                                    if nonce != check {
-                                                        warn!("failed to verify nonce");
-                                                        self.handler.lock().await.send_packet(Box::new(LoginDisconnectS2C {
-                                                            reason: TextComponent::plain("Failed to verify token")
-                                                        })).unwrap();
-                                                        continue;  // FIXME: disconnect
+                                        match &forwarding {
+                                            ForwardingMode::Velocity { .. } => {
+                                                // Ask the proxy for the player's info instead of authenticating
+                                                let query_id = (rand::thread_rng().next_u32() >> 1) as i32;
+                                                self.velocity_query_id = Some(query_id);
+                                                self.handler.lock().await.send_packet(Box::new(LoginQueryRequestS2C {
+                                                    query_id,
+                                                    channel: VELOCITY_CHANNEL.to_owned(),
+                                                    data: Bytes::from(vec![VELOCITY_DEFAULT_VERSION])
+                                                })).unwrap();
+                                                continue;
+                                            },
+                                            ForwardingMode::BungeeCord => {
+                                                match self.forwarded.take() {
+                                                    Some(mut player) => {
+                                                        player.profile.name = packet.name;
+                                                        self.address = player.address.parse().ok().or(self.address);
+                                                        info!("{} forwarded by BungeeCord from {}", player.profile.name, player.address);
+                                                        self.finish_login(player.profile, v).await;
+                                                    },
+                                                    None => {
+                                                        warn!("no BungeeCord forwarding data from {}", packet.name);
+                                                        self.handler.lock().await.kick(TextComponent::plain("If you wish to use IP forwarding, please enable it in your BungeeCord config as well!")).unwrap();
                                                     }
                                                 }
-                                            }
+                                                continue;
+                                            },
+                                            ForwardingMode::None => {}
+                                        }
+
+                                        if !online_mode {
+                                            let profile = Profile::offline(&packet.name);
+                                            self.finish_login(profile, v).await;
+                                            continue;
                                         }
 
-                                        let mut sha = Sha1::new();
-                                        sha.update(secret.clone());
-                                        sha.update(rsa_der::public_key_to_der(&key.n().to_bytes_be(), &key.e().to_bytes_be()));
-                                        (secret, sha_digest(sha))
-                                    };
-                                    // Retrieve Mojang profile
-                                    let resp = {
-                                        let username = {
-                                            self.username.lock().await.clone()
+                                        // Send an encryption response
+                                        let key_bytes = {
+                                            public_key_der(&key)
                                         };
-                                        let username_enc = urlencoding::encode(&username);
-                                        reqwest::get(
-                                            format!(
-                                                "https://sessionserver.mojang.com/session/minecraft/hasJoined?username={}&serverId={}",
-                                                username_enc,
-                                                sha
-                                            )
-                                        ).await.unwrap()
-                                    };
-
-                                    if resp.status() != StatusCode::OK {
+                                        let mut verify_token = &mut [0u8; 4];
+                                        rand::thread_rng().fill_bytes(&mut verify_token[..]);
                                         {
-                                            warn!("profile retrieval failed with code {}", resp.status());
-                                            self.handler.lock().await.kick(TextComponent::plain("Failed to retrieve Mojang profile")).unwrap();
+                                            *self.auth_nonce.lock().await = Some(Bytes::copy_from_slice(verify_token));
                                         }
-                                    }
+                                        let packet = LoginHelloS2C {
+                                            server_id: "".to_owned(),
+                                            public_key: key_bytes.into(),
+                                            nonce: Bytes::copy_from_slice(verify_token),
+                                            needs_authentication: true
+                                        };
+                                        self.handler.lock().await.send_packet(Box::new(packet)).unwrap();
+                                    },
+                                    packet_type = LoginKeyC2S @ v => {
+                                        let packet = decode_or_disconnect!(LoginKeyC2S::decode(&mut reader, v), self, write_half, 'conn);
+                                        // Set up encryption
+                                        let (secret, sha) = {
+                                            let secret = decode_or_disconnect!(key.decrypt(rsa::Pkcs1v15Encrypt, &packet.shared_secret), self, write_half, 'conn);
+                                            if let Some(encrypted_nonce) = &packet.nonce {
+                                                let nonce = decode_or_disconnect!(key.decrypt(rsa::Pkcs1v15Encrypt, &encrypted_nonce[..]), self, write_half, 'conn);
+                                                {
+                                                    let our_nonce = self.auth_nonce.lock().await.clone();
+                                                    if let Some(check) = our_nonce {
+                                                        if nonce != check {
+                                                            warn!("failed to verify nonce");
+                                                            self.disconnect_now(&mut write_half, TextComponent::plain("Failed to verify token")).await;
+                                                            break 'conn;
+                                                        }
+                                                    }
+                                                }
+                                            }
+
+                                            let mut sha = Sha1::new();
+                                            sha.update(secret.clone());
+                                            sha.update(public_key_der(&key));
+                                            (secret, sha_digest(sha))
+                                        };
+                                        // The client encrypts everything after its key response, so even a
+                                        // failed login has to be answered encrypted
+                                        self.secret = Some(secret.clone());
+                                        self.encoder.enable_encryption(&secret);
+                                        self.decoder.enable_encryption(&secret);
+
+                                        // Retrieve Mojang profile
+                                        let resp = {
+                                            let username = {
+                                                self.username.lock().await.clone()
+                                            };
+                                            let username_enc = urlencoding::encode(&username);
+                                            reqwest::get(
+                                                format!(
+                                                    "{}/session/minecraft/hasJoined?username={}&serverId={}",
+                                                    session_server,
+                                                    username_enc,
+                                                    sha
+                                                )
+                                            ).await
+                                        };
 
-                                    let profile: Profile = resp.json().await.unwrap();
+                                        let profile: Option<Profile> = match resp {
+                                            Ok(resp) if resp.status() == StatusCode::OK => resp.json().await.ok(),
+                                            Ok(resp) => {
+                                                warn!("profile retrieval failed with code {}", resp.status());
+                                                None
+                                            },
+                                            Err(e) => {
+                                                warn!("profile retrieval failed: {e}");
+                                                None
+                                            }
+                                        };
+                                        let profile = match profile {
+                                            Some(profile) => profile,
+                                            None => {
+                                                self.disconnect_now(&mut write_half, TextComponent::plain("Failed to retrieve Mojang profile")).await;
+                                                break 'conn;
+                                            }
+                                        };
 
-                                    // update our username if necessary
-                                    {
-                                        *self.username.lock().await = profile.name.clone();
-                                    }
+                                        self.finish_login(profile, v).await;
+                                    },
+                                    packet_type = LoginQueryResponseC2S @ v => {
+                                        let packet = decode_or_disconnect!(LoginQueryResponseC2S::decode(&mut reader, v), self, write_half, 'conn);
+                                        // println!("{:?}", packet);
+                                        if let ForwardingMode::Velocity { secret } = &forwarding {
+                                            if self.velocity_query_id == Some(packet.query_id) {
+                                                self.velocity_query_id = None;
+                                                let player = if packet.successful {
+                                                    read_velocity_response(secret, &packet.response)
+                                                } else {
+                                                    None
+                                                };
+                                                match player {
+                                                    Some(player) => {
+                                                        info!("{} forwarded by Velocity from {}", player.profile.name, player.address);
+                                                        self.address = player.address.parse().ok().or(self.address);
+                                                        self.finish_login(player.profile, v).await;
+                                                    },
+                                                    None => {
+                                                        warn!("invalid Velocity forwarding response");
+                                                        self.handler.lock().await.kick(TextComponent::plain("This server requires you to connect with Velocity.")).unwrap();
+                                                    }
+                                                }
+                                            }
+                                        }
+                                    },
+                                    packet_type = EnterConfigurationC2S @ v => {
+                                        let packet = decode_or_disconnect!(EnterConfigurationC2S::decode(&mut reader, v), self, write_half, 'conn);
+                                        // println!("entering configuration stage");
+                                        {
+                                            let mut h = handler_arc.lock().await;
+                                            h.set_stage(Stage::Config);
+                                        }
+                                    },
+                                    packet_type = CookieResponseC2S @ v => {
+                                        let packet = decode_or_disconnect!(CookieResponseC2S::decode(&mut reader, v), self, write_half, 'conn);
+                                        tri_handle!(handler_arc.lock().await.on_cookie(packet.key, packet.payload).await);
+                                    },
+                                    ?? => {
 
-                                    // update our profile on the handler
-                                    {
-                                        self.handler.lock().await.set_profile(profile.clone()).await;
                                     }
+                                );
+                            },
+                            // CONFIG ---------------------------------------------------------
+                            Stage::Config => {
+                                packet_case!(
+                                    packet_type = ClientInfoC2S @ v => {
+                                        let packet = decode_or_disconnect!(ClientInfoC2S::decode(&mut reader, v), self, write_half, 'conn);
+                                        // println!("{:?}", packet);
+                                        self.client_info = packet.client_info.clone();
 
-                                    // enable encryption
-                                    self.secret = Some(secret.clone());
-
-                                    self.enc_cipher = Some(EncCipher::new_from_slices(&secret[..], &secret[..]).unwrap());
-                                    self.dec_cipher = Some(DecCipher::new_from_slices(&secret[..], &secret[..]).unwrap());
-                                    {
                                         let mut handler = self.handler.lock().await;
-                                        handler.send_packet(Box::new(LoginSuccessS2C {
-                                            profile,
-                                            strict_error_handling: false
+                                        tri_handle!(handler.on_client_info(packet.client_info).await);
+
+                                        // Tell them our features
+                                        handler.send_packet(Box::new(FeaturesS2C {
+                                            features: vec!["minecraft:vanilla".to_owned()]
                                         })).unwrap();
 
-                                        // Before 1.20.2, this switches the stage to Play
-                                        if v < ProtocolVersion::V1_20_2 {
+                                        if v >= ProtocolVersion::V1_20_5 {
+                                            // Our registry data leaves out anything the vanilla pack has, so offer it
+                                            // for every release on this protocol and let the client pick its own
+                                            handler.send_packet(Box::new(SelectKnownPacksS2C {
+                                                known_packs: v.release_names().iter().map(|release| VersionedIdentifier {
+                                                    namespace: "minecraft".to_owned(),
+                                                    id: "core".to_owned(),
+                                                    version: release.to_string(),
+                                                }).collect()
+                                            })).unwrap();
+                                        } else {
+                                            // Older clients don't negotiate packs, so send everything right away
+                                            handler.send_packet(Box::new(RegistryCodecS2C {
+                                                codec: Tag::parse(&mut Bytes::from(REGISTRY_DEFAULT))
+                                            })).unwrap();
+                                            tri_handle!(handler.on_config_ready().await);
+                                            handler.send_packet(Box::new(ReadyS2C {})).unwrap();
+                                        }
+                                    },
+                                    packet_type = ConfigCookieResponseC2S @ v => {
+                                        let packet = decode_or_disconnect!(ConfigCookieResponseC2S::decode(&mut reader, v), self, write_half, 'conn);
+                                        tri_handle!(handler_arc.lock().await.on_cookie(packet.key, packet.payload).await);
+                                    },
+                                    packet_type = CustomPayloadC2S @ v => {
+                                        let packet = decode_or_disconnect!(CustomPayloadC2S::decode(&mut reader, v), self, write_half, 'conn);
+                                        tri_handle!(handler_arc.lock().await.on_custom_payload(packet).await);
+                                    },
+                                    packet_type = ReadyC2S @ v => {
+                                        let packet = decode_or_disconnect!(ReadyC2S::decode(&mut reader, v), self, write_half, 'conn);
+                                        // println!("{:?}", packet);
+                                        // Our client is ready now also, let's enter the play stage
+                                        {
+                                            let mut handler = self.handler.lock().await;
                                             handler.set_stage(Stage::Play);
                                         }
-                                    }
-                                    if v < ProtocolVersion::V1_20_2 {
+
+                                        // Picked before joining, so the handler can mention it once the player is in
+                                        let preview = {
+                                            self.handler.lock().await.get_preview().await
+                                        };
+                                        let spawn = preview.as_ref().map(|p| p.spawn).unwrap_or((0.0, 128.0, 0.0));
                                         self.send_game_join().await;
-                                    }
-                                },
-                                packet_type = LoginQueryResponseC2S @ v => {
-                                    let packet = LoginQueryResponseC2S::decode(&mut reader, v);
-                                    // println!("{:?}", packet);
-                                },
-                                packet_type = EnterConfigurationC2S @ v => {
-                                    let packet = EnterConfigurationC2S::decode(&mut reader, v);
-                                    // println!("entering configuration stage");
-                                    {
-                                        let mut h = handler_arc.lock().await;
-                                        h.set_stage(Stage::Config);
-                                    }
-                                },
-                                packet_type = CookieResponseC2S @ v => {
-                                    let packet = CookieResponseC2S::decode(&mut reader, v);
-                                    // println!("{:?}", packet);
-                                },
-                                ?? => {
-
-                                }
-                            );
-                        },
-                        // CONFIG ---------------------------------------------------------
-                        Stage::Config => {
-                            packet_case!(
-                                packet_type = ClientInfoC2S @ v => {
-                                    let packet = ClientInfoC2S::decode(&mut reader, v);
-                                    // println!("{:?}", packet);
-                                    self.client_info = packet.client_info;
-
-                                    let mut handler = self.handler.lock().await;
-                                    // Send our client brand
-                                    let mut brand_buf = BytesMut::new();
-                                    write_string(&mut brand_buf, "WorldFreezer");
-                                    handler.send_packet(Box::new(CustomPayloadS2C {
-                                        key: "minecraft:brand".to_owned(),
-                                        payload: brand_buf.into()
-                                    })).unwrap();
-
-                                    // Tell them our features
-                                    handler.send_packet(Box::new(FeaturesS2C {
-                                        features: vec!["minecraft:vanilla".to_owned()]
-                                    })).unwrap();
-
-                                    if v >= ProtocolVersion::V1_21 {
-                                        // Send our default known packs
-                                        handler.send_packet(Box::new(SelectKnownPacksS2C {
-                                            known_packs: vec![VersionedIdentifier {
-                                                namespace: "minecraft".to_owned(),
-                                                id: "core".to_owned(),
-                                                version: "1.21".to_owned(),
-                                            }]
-                                        })).unwrap();
-                                    } else if v >= ProtocolVersion::V1_20_5 {
-                                        handler.send_packet(Box::new(SelectKnownPacksS2C {
-                                            known_packs: vec![]  // 1.20.5 doesn't need any
-                                        })).unwrap();
-                                    }
-                                },
-                                packet_type = ConfigCookieResponseC2S @ v => {
-                                    let packet = ConfigCookieResponseC2S::decode(&mut reader, v);
-                                    // println!("{:?}", packet);
-                                },
-                                packet_type = CustomPayloadC2S @ v => {
-                                    let packet = CustomPayloadC2S::decode(&mut reader, v);
-                                    // println!("{:?}", packet);
-                                },
-                                packet_type = ReadyC2S @ v => {
-                                    let packet = ReadyC2S::decode(&mut reader, v);
-                                    // println!("{:?}", packet);
-                                    // Our client is ready now also, let's enter the play stage
-                                    {
-                                        let mut handler = self.handler.lock().await;
-                                        handler.set_stage(Stage::Play);
-                                    }
 
-                                    self.send_game_join().await;
+                                        // Tell them the initial chunks are coming
+                                        if v >= ProtocolVersion::V1_20_4 {
+                                            let mut handler = self.handler.lock().await;
+                                            handler.send_packet(Box::new(GameEventS2C {
+                                                event: EventType::InitialChunksComing,
+                                                value: 0.0
+                                            })).unwrap();
+                                        }
 
-                                    // Tell them the initial chunks are coming
-                                    if v >= ProtocolVersion::V1_20_4 {
-                                        let mut handler = self.handler.lock().await;
-                                        handler.send_packet(Box::new(GameEventS2C {
-                                            event: EventType::InitialChunksComing,
-                                            value: 0.0
-                                        })).unwrap();
-                                    }
+                                        // Teleport to initial pos
+                                        {
+                                            let mut handler = self.handler.lock().await;
+                                            handler.send_packet(Box::new(SyncPlayerPositionS2C {
+                                                x: spawn.0,
+                                                y: spawn.1,
+                                                z: spawn.2,
+                                                yaw: 0.0,
+                                                pitch: 0.0,
+                                                flags: 0,
+                                                teleport_id: 0,
+                                                dismount: true
+                                            })).unwrap();
+                                        }
 
-                                    // Teleport to initial pos
-                                    {
-                                        let mut handler = self.handler.lock().await;
-                                        handler.send_packet(Box::new(SyncPlayerPositionS2C {
-                                            x: 0.0,
-                                            y: 128.0,
-                                            z: 0.0,
-                                            yaw: 0.0,
-                                            pitch: 0.0,
-                                            flags: 0,
-                                            teleport_id: 0,
-                                            dismount: true
-                                        })).unwrap();
-                                    }
+                                        // Start a keepalive loop to prevent the connection from closing
+                                        let mut keepalive_handler = self.handler.clone();
+                                        task::spawn(async move {
+                                            let mut interval = time::interval(Duration::from_secs(1));
 
-                                    // Start a keepalive loop to prevent the connection from closing
-                                    let mut keepalive_handler = self.handler.clone();
-                                    task::spawn(async move {
-                                        let mut interval = time::interval(Duration::from_secs(1));
-
-                                        loop {
-                                            interval.tick().await;
-                                            {
-                                                let mut handler = keepalive_handler.lock().await;
-                                                match handler.send_packet(Box::new(KeepAliveS2C {
-                                                    payload: rand::thread_rng().next_u64()
-                                                })) {
-                                                    Ok(_) => {},
-                                                    Err(_) => break
-                                                };
+                                            loop {
+                                                interval.tick().await;
+                                                {
+                                                    let mut handler = keepalive_handler.lock().await;
+                                                    match handler.send_packet(Box::new(KeepAliveS2C {
+                                                        payload: rand::thread_rng().next_u64()
+                                                    })) {
+                                                        Ok(_) => {},
+                                                        Err(_) => break
+                                                    };
+                                                }
                                             }
-                                        }
-                                    });
-
-                                    {
-                                        // Send message
-                                        let mut decor = TextComponent::new();
-                                        decor.set_text("៚ ");
+                                        });
+
+                                        // Start sending chunks
+                                        self.start_chunk_streaming(preview, spawn).await;
+                                    },
+                                    packet_type = KeepAliveC2S @ v => {
+                                        let packet = decode_or_disconnect!(KeepAliveC2S::decode(&mut reader, v), self, write_half, 'conn);
+                                        tri_handle!(handler_arc.lock().await.on_keepalive(packet.id).await);
+                                    },
+                                    packet_type = PongC2S @ v => {
+                                        let packet = decode_or_disconnect!(PongC2S::decode(&mut reader, v), self, write_half, 'conn);
+                                        // println!("{:?}", packet);
+                                    },
+                                    packet_type = ResourcePackStatusC2S @ v => {
+                                        let packet = decode_or_disconnect!(ResourcePackStatusC2S::decode(&mut reader, v), self, write_half, 'conn);
+                                        tri_handle!(handler_arc.lock().await.on_resource_pack_status(packet.status).await);
+                                    },
+                                    packet_type = SelectKnownPacksC2S @ v => {
+                                        let packet = decode_or_disconnect!(SelectKnownPacksC2S::decode(&mut reader, v), self, write_half, 'conn);
+                                        // println!("{:?}", packet);
+                                        // Now that we've received this, let's send the registries and finish configuration
+
+                                        // println!("sending reg for {:?}", v);
+                                        let registries = Tag::parse(&mut Bytes::from(registry_data(v)));
+                                        let registries = registries.as_compound().unwrap();
 
-                                        let mut title = TextComponent::plain("Welcome!");
-                                        title.set_bold(true);
-                                        title.set_gradient(&[ChatColor::DarkCyan, ChatColor::Aqua]);
-                                        title.prepend_component(decor.clone());
                                         let mut handler = self.handler.lock().await;
-                                        handler.send_packet(Box::new(GameMessageS2C {
-                                            text: title,
-                                            overlay: false
-                                        })).unwrap();
 
-                                        let mut instructions = TextComponent::plain("To verify your account, please send your one-time code in the game chat. It will not be shared with others.");
-                                        instructions.set_color(ChatColor::Gold);
-                                        handler.send_packet(Box::new(GameMessageS2C {
-                                            text: instructions,
-                                            overlay: false
-                                        })).unwrap();
-                                    }
+                                        for (k, v) in registries {
+                                            let mut entries = Vec::new();
 
-                                    // Start sending chunks
-                                    let mut chunk_handler = self.handler.clone();
-                                    task::spawn(async move {
-                                        // let mut data = include_bytes!("../../server/world/region/r.0.0.mca");
-                                        // let mut region = Region::load(Cursor::new(&mut data));
-
-                                        let diam = 3i32;
-
-                                        let mut x = 0;
-                                        let mut z = 0;
-                                        let mut dx = 0;
-                                        let mut dz = -1;
-                                        for i in 0..diam.pow(2) {
-                                            if ((-diam / 2) < x && x <= (diam / 2)) && ((-diam / 2) < z && z <= (diam / 2)) {
-                                                // match region.get_chunk(x, z) {
-                                                //     Some(chunk) => ,
-                                                //     None => {}
-                                                // };
-                                                {
-                                                    let mut heightmaps = HashMap::new();
-                                                    heightmaps.insert("MOTION_BLOCKING".to_owned(), Tag::LongArray(vec![0i64; 37]));
-                                                    heightmaps.insert("WORLD_SURFACE".to_owned(), Tag::LongArray(vec![0i64; 37]));
-
-                                                    let mut heightmaps = Tag::Compound(heightmaps);
-                                                    let mut handler = chunk_handler.lock().await;
-                                                    handler.send_packet(Box::new(ChunkDataS2C {
-                                                        x: x,
-                                                        z: z,
-                                                        heightmaps,
-                                                        chunk: Chunk::empty()
-                                                    })).unwrap();
-                                                }
-                                            }
-                                            if x == z || (x < 0 && x == -z) || (x > 0 && x == 1 - z) {
-                                                (dx, dz) = (-dz, dx);
+                                            let mut value = v.get("value").unwrap().as_list().unwrap();
+                                            for entry in value {
+                                                entries.push(RegistryEntry {
+                                                    id: entry.get("name").unwrap().as_string().unwrap().clone(),
+                                                    data: match entry.get("element") {
+                                                        Ok(d) => Some(d.clone()),
+                                                        Err(_) => None
+                                                    }
+                                                });
                                             }
-                                            (x, z) = (x + dx, z + dz);
-                                        }
-                                        // }
-                                    });
-                                },
-                                packet_type = KeepAliveC2S @ v => {
-                                    let packet = KeepAliveC2S::decode(&mut reader, v);
-                                    // println!("{:?}", packet);
-                                },
-                                packet_type = PongC2S @ v => {
-                                    let packet = PongC2S::decode(&mut reader, v);
-                                    // println!("{:?}", packet);
-                                },
-                                packet_type = ResourcePackStatusC2S @ v => {
-                                    let packet = ResourcePackStatusC2S::decode(&mut reader, v);
-                                    // println!("{:?}", packet);
-                                },
-                                packet_type = SelectKnownPacksC2S @ v => {
-                                    let packet = SelectKnownPacksC2S::decode(&mut reader, v);
-                                    // println!("{:?}", packet);
-                                    // Now that we've received this, let's send the registries and finish configuration
-
-                                    // println!("sending reg for {:?}", v);
-                                    let registries = Tag::parse(&mut Bytes::from(match v {
-                                        ProtocolVersion::V1_21 => REGISTRY_121,
-                                        ProtocolVersion::V1_20_4 | ProtocolVersion::V1_20_5 => REGISTRY_1206,
-                                        ProtocolVersion::V1_19_4 => REGISTRY_1194,
-                                        _ => REGISTRY_DEFAULT,
-                                    }));
-                                    let registries = registries.as_compound().unwrap();
-
-                                    let mut handler = self.handler.lock().await;
-
-                                    for (k, v) in registries {
-                                        let mut entries = Vec::new();
-
-                                        let mut value = v.get("value").unwrap().as_list().unwrap();
-                                        for entry in value {
-                                            entries.push(RegistryEntry {
-                                                id: entry.get("name").unwrap().as_string().unwrap().clone(),
-                                                data: match entry.get("element") {
-                                                    Ok(d) => Some(d.clone()),
-                                                    Err(_) => None
-                                                }
-                                            });
+
+                                            handler.send_packet(Box::new(DynamicRegistriesS2C {
+                                                registry_id: k.clone(),
+                                                entries
+                                            })).unwrap();
                                         }
 
-                                        handler.send_packet(Box::new(DynamicRegistriesS2C {
-                                            registry_id: k.clone(),
-                                            entries
-                                        })).unwrap();
-                                    }
+                                        tri_handle!(handler.on_config_ready().await);
+                                        handler.send_packet(Box::new(ReadyS2C {})).unwrap();
+                                    },
+                                    ?? => {
 
-                                    if v >= ProtocolVersion::V1_21 {
-                                        let mut left = TextComponent::new();
-                                        let mut right = TextComponent::new();
-                                        left.set_text("៚ ");
-                                        right.set_text("");
-
-                                        let mut website = TextComponent::new();
-                                        website.set_text("Website");
-                                        website.set_gradient(&[ChatColor::Aqua, ChatColor::White]);
-                                        website.prepend_component(left.clone());
-                                        website.add_component(right.clone());
-                                        let mut store = TextComponent::new();
-                                        store.set_text("Store");
-                                        store.set_gradient(&[ChatColor::DarkGreen, ChatColor::Green]);
-                                        store.prepend_component(left.clone());
-                                        store.add_component(right.clone());
-                                        let mut vote = TextComponent::new();
-                                        vote.set_text("Vote!");
-                                        vote.set_gradient(&[ChatColor::DarkCyan, ChatColor::Aqua]);
-                                        vote.prepend_component(left.clone());
-                                        vote.add_component(right.clone());
-
-                                        // let mut topmc = TextComponent::new();
-                                        // topmc.set_text("Top MC Servers");
-                                        // topmc.set_gradient(&[ChatColor::Blue, ChatColor::Gray]);
-                                        // let mut mcsl = TextComponent::new();
-                                        // mcsl.set_text("Minecraft SL");
-                                        // mcsl.set_gradient(&[ChatColor::White, ChatColor::Gray]);
-                                        // let mut mcs = TextComponent::new();
-                                        // mcs.set_text("Minecraft Servers");
-                                        // mcs.set_gradient(&[ChatColor::LightPurple, ChatColor::Purple]);
-                                        // let mut pmc = TextComponent::new();
-                                        // pmc.set_text("Planet Minecraft");
-                                        // pmc.set_gradient(&[ChatColor::DarkGreen, ChatColor::DarkCyan]);
-                                        // let mut mmp = TextComponent::new();
-                                        // mmp.set_text("Minecraft MP");
-                                        // mmp.set_color(ChatColor::DarkGreen);
-                                        // let mut topg = TextComponent::new();
-                                        // topg.set_text("TopG");
-                                        // topg.set_gradient(&[ChatColor::Gold, ChatColor::Gray]);
-                                        // let mut buzz = TextComponent::new();
-                                        // buzz.set_text("Buzz");
-                                        // buzz.set_gradient(&[ChatColor::Yellow, ChatColor::Gold]);
-
-                                        handler.send_packet(Box::new(LinksS2C {
-                                            links: vec![Link {
-                                                label: LinkLabel::Custom(website),
-                                                url: "https://swancraft.guildtag.com/".to_owned()
-                                            }, Link {
-                                                label: LinkLabel::Custom(store),
-                                                url: "https://swancraft.buycraft.net/".to_owned()
-                                            }, Link {
-                                                label: LinkLabel::Custom(vote),
-                                                url: "https://swancraft.guildtag.com/vote".to_owned()
-                                            }], // Link {
-                                            //     label: LinkLabel::Custom(topmc),
-                                            //     url: "https://topminecraftservers.org/vote/4455".to_owned()
-                                            // }, Link {
-                                            //     label: LinkLabel::Custom(mcsl),
-                                            //     url: "https://minecraft-server-list.com/server/389267/vote/".to_owned()
-                                            // }, Link {
-                                            //     label: LinkLabel::Custom(mcs),
-                                            //     url: "https://minecraftservers.org/vote/410424".to_owned()
-                                            // }, Link {
-                                            //     label: LinkLabel::Custom(pmc),
-                                            //     url: "https://www.planetminecraft.com/server/swancraft-3882768/vote/".to_owned()
-                                            // }, Link {
-                                            //     label: LinkLabel::Custom(mmp),
-                                            //     url: "https://minecraft-mp.com/server/145239/vote/".to_owned()
-                                            // }, Link {
-                                            //     label: LinkLabel::Custom(topg),
-                                            //     url: "https://topg.org/minecraft-servers/server-449700".to_owned()
-                                            // }, Link {
-                                            //     label: LinkLabel::Custom(buzz),
-                                            //     url: "https://minecraft.buzz/vote/5680".to_owned()
-                                            // }]
-                                        })).unwrap();
                                     }
+                                );
+                            },
+                            Stage::Play => {
+                                packet_case!(
+                                    packet_type = ChatC2S @ v => {
+                                        let packet = decode_or_disconnect!(ChatC2S::decode(&mut reader, v), self, write_half, 'conn);
+                                        // println!("{:?}", packet);
+
+                                        // let mut resp = TextComponent::plain(&packet.message);
+                                        // resp.set_color(ChatColor::Red);
+                                        // {
+                                        //     let mut handler = self.handler.lock().await;
+                                        //     handler.send_packet(Box::new(GameMessageS2C {
+                                        //         text: resp,
+                                        //         overlay: false
+                                        //     })).unwrap();
+                                        // }
 
-                                    handler.send_packet(Box::new(ReadyS2C {})).unwrap();
-                                },
-                                ?? => {
-
-                                }
-                            );
-                        },
-                        Stage::Play => {
-                            packet_case!(
-                                packet_type = ChatC2S @ v => {
-                                    let packet = ChatC2S::decode(&mut reader, v);
-                                    // println!("{:?}", packet);
-
-                                    // let mut resp = TextComponent::plain(&packet.message);
-                                    // resp.set_color(ChatColor::Red);
-                                    // {
-                                    //     let mut handler = self.handler.lock().await;
-                                    //     handler.send_packet(Box::new(GameMessageS2C {
-                                    //         text: resp,
-                                    //         overlay: false
-                                    //     })).unwrap();
-                                    // }
-
-                                    {
-                                        let mut handler = self.handler.lock().await;
-                                        tri_handle!(handler.on_chat(packet).await);
-                                    }
-                                },
-                                ?? => {
+                                        {
+                                            let mut handler = self.handler.lock().await;
+                                            tri_handle!(handler.on_chat(packet).await);
+                                        }
+                                    },
+                                    packet_type = PlayKeepAliveC2S @ v => {
+                                        let packet = decode_or_disconnect!(PlayKeepAliveC2S::decode(&mut reader, v), self, write_half, 'conn);
+                                        tri_handle!(handler_arc.lock().await.on_keepalive(packet.id).await);
+                                    },
+                                    packet_type = PlayerPositionC2S @ v => {
+                                        let packet = decode_or_disconnect!(PlayerPositionC2S::decode(&mut reader, v), self, write_half, 'conn);
+                                        self.move_to(packet.x, packet.z).await;
+                                    },
+                                    packet_type = PlayerPositionRotationC2S @ v => {
+                                        let packet = decode_or_disconnect!(PlayerPositionRotationC2S::decode(&mut reader, v), self, write_half, 'conn);
+                                        self.move_to(packet.x, packet.z).await;
+                                    },
+                                    packet_type = PlayerRotationC2S @ v => {
+                                        // Turning doesn't change which chunks the player needs
+                                        let _ = decode_or_disconnect!(PlayerRotationC2S::decode(&mut reader, v), self, write_half, 'conn);
+                                    },
+                                    packet_type = ChatCommandC2S @ v => {
+                                        let packet = decode_or_disconnect!(ChatCommandC2S::decode(&mut reader, v), self, write_half, 'conn);
+                                        let preview = {
+                                            let mut handler = self.handler.lock().await;
+                                            tri_handle!(handler.on_chat_command(packet).await);
+                                            handler.take_preview_change().await
+                                        };
+                                        if let Some(preview) = preview {
+                                            self.switch_preview(preview).await;
+                                        }
+                                    },
+                                    ?? => {
 
-                                }
-                            )
-                        },
-                        _ => println!("unsupported stage {:?}", stage)
+                                    }
+                                )
+                            },
+                            _ => println!("unsupported stage {:?}", stage)
+                        }
                     }
                 }
             }
         }
+        if let Some(id) = self.registered.take() {
+            self.parent.lock().await.remove_player(id);
+        }
+        tri_handle!(self.handler.lock().await.on_disconnect().await);
         info!("Channel closed");
     }
 }
@@ -0,0 +1,46 @@
+use bytes::{BufMut, Bytes, BytesMut};
+use crypto::hmac::Hmac;
+use crypto::mac::{Mac, MacResult};
+use crypto::sha2::Sha256;
+
+/// The largest cookie payload a client will store
+pub const MAX_COOKIE_SIZE: usize = 5120;
+
+/// Prefixes a payload with its HMAC-SHA256, the same layout Velocity uses for forwarding.
+/// Clients hand cookies back verbatim, so anything we want to trust later has to be signed.
+pub fn sign_cookie(secret: &[u8], payload: &[u8]) -> Bytes {
+    let mut mac = Hmac::new(Sha256::new(), secret);
+    mac.input(payload);
+    let mut buf = BytesMut::with_capacity(32 + payload.len());
+    buf.put(mac.result().code());
+    buf.put(payload);
+    buf.freeze()
+}
+
+/// Returns the payload of a signed cookie, or None if it wasn't signed with our secret
+pub fn read_signed_cookie(secret: &[u8], data: &Bytes) -> Option<Bytes> {
+    if data.len() < 32 {
+        return None;
+    }
+    let signature = data.slice(0..32);
+    let payload = data.slice(32..);
+
+    let mut mac = Hmac::new(Sha256::new(), secret);
+    mac.input(&payload[..]);
+    if mac.result() != MacResult::new(&signature[..]) {
+        return None;
+    }
+    Some(payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server::cookie::{read_signed_cookie, sign_cookie};
+
+    #[test]
+    fn signed_cookie_round_trip() {
+        let cookie = sign_cookie(b"secret", b"hello");
+        assert_eq!(read_signed_cookie(b"secret", &cookie).as_deref(), Some(&b"hello"[..]));
+        assert_eq!(read_signed_cookie(b"other", &cookie), None);
+    }
+}
@@ -14,6 +14,15 @@ impl ChatVisibility {
             _ => ChatVisibility::Hidden,  // 2 or default, just in case
         }
     }
+
+    #[cfg(any(test, feature = "client"))]
+    pub fn to_i32(&self) -> i32 {
+        match self {
+            ChatVisibility::Full => 0,
+            ChatVisibility::System => 1,
+            ChatVisibility::Hidden => 2
+        }
+    }
 }
 
 
@@ -30,4 +39,12 @@ impl Arm {
             _ => Arm::Right,
         }
     }
+
+    #[cfg(any(test, feature = "client"))]
+    pub fn to_i32(&self) -> i32 {
+        match self {
+            Arm::Left => 0,
+            Arm::Right => 1
+        }
+    }
 }
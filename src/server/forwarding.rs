@@ -0,0 +1,144 @@
+use bytes::{Buf, Bytes};
+use crypto::hmac::Hmac;
+use crypto::mac::{Mac, MacResult};
+use crypto::sha2::Sha256;
+use uuid::Uuid;
+use crate::server::common::{Profile, ProfileProperty};
+use crate::server::utils::{read_string, read_uuid, read_varint};
+
+/// Login plugin channel used by Velocity's modern forwarding
+pub const VELOCITY_CHANNEL: &str = "velocity:player_info";
+/// The forwarding version we ask Velocity for (address, profile and properties only)
+pub const VELOCITY_DEFAULT_VERSION: u8 = 1;
+
+/// How player information reaches us when running behind a proxy
+#[derive(Debug, Clone)]
+pub enum ForwardingMode {
+    /// Players connect directly and are authenticated against Mojang
+    None,
+    /// Velocity modern forwarding, signed with the shared secret
+    Velocity { secret: Vec<u8> },
+    /// BungeeCord legacy forwarding, packed into the handshake address
+    BungeeCord
+}
+
+/// A player as forwarded to us by a proxy
+#[derive(Debug, Clone)]
+pub struct ForwardedPlayer {
+    pub(crate) address: String,
+    pub(crate) profile: Profile
+}
+
+/// Splits a BungeeCord handshake address (`host\0ip\0uuid[\0properties]`) into the real host and
+/// the forwarded player. The name isn't part of the address, so it has to be filled in from the
+/// login hello.
+pub fn parse_bungeecord_address(address: &str) -> Option<(String, ForwardedPlayer)> {
+    let parts: Vec<&str> = address.split('\0').collect();
+    if parts.len() < 3 {
+        return None;
+    }
+    let id = Uuid::parse_str(parts[2]).ok()?;
+    let properties = match parts.get(3) {
+        Some(json) => serde_json::from_str(json).ok()?,
+        None => vec![]
+    };
+    Some((parts[0].to_owned(), ForwardedPlayer {
+        address: parts[1].to_owned(),
+        profile: Profile {
+            id,
+            name: "Unknown".to_owned(),
+            properties
+        }
+    }))
+}
+
+/// Verifies and reads the data of a `velocity:player_info` response.
+/// Returns None if the signature doesn't match our secret.
+pub fn read_velocity_response(secret: &[u8], data: &Bytes) -> Option<ForwardedPlayer> {
+    if data.len() < 32 {
+        return None;
+    }
+    // HMAC-SHA256 of the rest of the payload
+    let signature = data.slice(0..32);
+    let mut body = data.slice(32..);
+
+    let mut mac = Hmac::new(Sha256::new(), secret);
+    mac.input(&body[..]);
+    if mac.result() != MacResult::new(&signature[..]) {
+        return None;
+    }
+
+    let version = read_varint(&mut body).ok()?;
+    if version < VELOCITY_DEFAULT_VERSION as i32 {
+        return None;
+    }
+    let address = read_string(&mut body).ok()?;
+    let id = read_uuid(&mut body).ok()?;
+    let name = read_string(&mut body).ok()?;
+    let num_properties = read_varint(&mut body).ok()?;
+    let mut properties = Vec::new();
+    for _ in 0..num_properties {
+        let name = read_string(&mut body).ok()?;
+        let value = read_string(&mut body).ok()?;
+        let has_signature = *body.first()? != 0;
+        body.advance(1);
+        properties.push(ProfileProperty {
+            name,
+            value,
+            signature: if has_signature { Some(read_string(&mut body).ok()?) } else { None }
+        });
+    }
+    // Newer forwarding versions append key data here, which we don't need
+
+    Some(ForwardedPlayer {
+        address,
+        profile: Profile { id, name, properties }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::{BufMut, BytesMut};
+    use crypto::hmac::Hmac;
+    use crypto::mac::Mac;
+    use crypto::sha2::Sha256;
+    use uuid::Uuid;
+    use crate::server::forwarding::{parse_bungeecord_address, read_velocity_response};
+    use crate::server::utils::{write_string, write_uuid, write_varint};
+
+    #[test]
+    fn test_bungeecord_address() {
+        let (host, player) = parse_bungeecord_address(
+            "play.example.com\u{0}10.0.0.2\u{0}069a79f444e94726a5befca90e38aaf5\u{0}[{\"name\":\"textures\",\"value\":\"abc\"}]"
+        ).unwrap();
+        assert_eq!(host, "play.example.com");
+        assert_eq!(player.address, "10.0.0.2");
+        assert_eq!(player.profile.id, Uuid::parse_str("069a79f4-44e9-4726-a5be-fca90e38aaf5").unwrap());
+        assert_eq!(player.profile.properties.len(), 1);
+
+        assert!(parse_bungeecord_address("play.example.com").is_none());
+    }
+
+    #[test]
+    fn test_velocity_response() {
+        let id = Uuid::new_v4();
+        let mut body = BytesMut::new();
+        write_varint(&mut body, 1);
+        write_string(&mut body, "10.0.0.2");
+        write_uuid(&mut body, id);
+        write_string(&mut body, "Notch");
+        write_varint(&mut body, 0);
+
+        let mut mac = Hmac::new(Sha256::new(), b"secret");
+        mac.input(&body[..]);
+        let mut data = BytesMut::new();
+        data.put(mac.result().code());
+        data.put(&body[..]);
+        let data = data.freeze();
+
+        let player = read_velocity_response(b"secret", &data).unwrap();
+        assert_eq!(player.profile.id, id);
+        assert_eq!(player.profile.name, "Notch");
+        assert!(read_velocity_response(b"wrong", &data).is_none());
+    }
+}
@@ -1,21 +1,27 @@
 use std::error::Error;
 use std::fmt::{Display, Formatter, Write, Debug};
 use async_trait::async_trait;
+use bytes::Bytes;
 use tokio::sync::mpsc;
 use tokio::sync::mpsc::UnboundedSender;
 use uuid::Uuid;
-use crate::server::common::Profile;
+use crate::server::command::{CommandNode, CommandSpec};
+use crate::server::common::{ClientInfo, Profile};
+use crate::server::packets::c2s::config::{CustomPayloadC2S, ResourcePackStatus};
 use crate::server::packets::c2s::handshake::HandshakeC2S;
-use crate::server::packets::c2s::play::ChatC2S;
+use crate::server::packets::c2s::login::LoginHelloC2S;
+use crate::server::packets::c2s::play::{ChatC2S, ChatCommandC2S};
 use crate::server::packets::packet::PacketS2C;
 use crate::server::packets::c2s::status::{PingRequestC2S, StatusRequestC2S};
-use crate::server::packets::s2c::config::ConfigDisconnectS2C;
+use crate::server::packets::s2c::config::{ConfigDisconnectS2C, ServerTransferS2C, StoreCookieS2C};
 use crate::server::packets::s2c::login::LoginDisconnectS2C;
-use crate::server::packets::s2c::play::{GameMessageS2C, PlayDisconnectS2C};
+use crate::server::packets::s2c::play::{DeclareCommandsS2C, GameMessageS2C, PlayDisconnectS2C, PlayStoreCookieS2C, PlayTransferS2C};
 use crate::server::packets::s2c::status::{PingResponseS2C, StatusResponseS2C};
 use crate::server::packets::stage::Stage;
 use crate::server::status::StatusBuilder;
-use crate::server::text::TextComponent;
+use crate::server::text::{ChatColor, TextComponent};
+use crate::server::version::ProtocolVersion;
+use crate::server::world::WorldPreview;
 
 #[async_trait]
 pub trait PacketHandler {
@@ -25,6 +31,9 @@ pub trait PacketHandler {
     fn set_stage(&mut self, new_stage: Stage);
     /// Get the current stage
     fn get_stage(&self) -> &Stage;
+    /// Called with the protocol version from the handshake
+    fn set_version(&mut self, version: ProtocolVersion);
+    fn get_version(&self) -> ProtocolVersion;
     fn send_packet(&mut self, packet: Box<dyn PacketS2C + Send>) -> anyhow::Result<bool>;
 
     // Handshake
@@ -35,17 +44,70 @@ pub trait PacketHandler {
     async fn on_status_request(&mut self, packet: StatusRequestC2S) -> anyhow::Result<bool> { Ok(true) }
     /// Called whenever a ping request is sent
     async fn on_ping_request(&mut self, packet: PingRequestC2S) -> anyhow::Result<bool> { Ok(true) }
+    // Login
+    /// Called when the user starts logging in, before they're authenticated.
+    /// Returning false stops the login, e.g. after kicking them.
+    async fn on_login_start(&mut self, packet: LoginHelloC2S) -> anyhow::Result<bool> { Ok(true) }
+    /// Called once the user is logged in and their profile is set
+    async fn on_login_success(&mut self) -> anyhow::Result<bool> { Ok(true) }
+    /// Keys of the cookies to ask 1.20.5+ clients for during login
+    fn get_login_cookies(&self) -> Vec<String> {
+        vec![]
+    }
+    /// Called with a cookie the client sent back, None if it didn't have one
+    async fn on_cookie(&mut self, key: String, payload: Option<Bytes>) -> anyhow::Result<bool> { Ok(true) }
+    // Configuration
+    /// Called with the user's settings, before the server starts configuring them
+    async fn on_client_info(&mut self, info: ClientInfo) -> anyhow::Result<bool> { Ok(true) }
+    /// Called whenever the user sends a plugin message
+    async fn on_custom_payload(&mut self, packet: CustomPayloadC2S) -> anyhow::Result<bool> { Ok(true) }
+    /// Called after the registries are sent, right before the user is told configuration is done;
+    /// anything else for the configuration stage (links, resource packs) goes here
+    async fn on_config_ready(&mut self) -> anyhow::Result<bool> { Ok(true) }
+    /// Called whenever the user reports on a resource pack
+    async fn on_resource_pack_status(&mut self, status: ResourcePackStatus) -> anyhow::Result<bool> { Ok(true) }
+    /// Called whenever the user answers a keepalive
+    async fn on_keepalive(&mut self, id: u64) -> anyhow::Result<bool> { Ok(true) }
     // Play
+    /// The part of a world to show the user once they join, asked for right before; an empty void if None
+    async fn get_preview(&mut self) -> Option<WorldPreview> { None }
+    /// A preview to switch to after the user has joined, e.g. once they picked another one with a command
+    async fn take_preview_change(&mut self) -> Option<WorldPreview> { None }
+    /// Called once the user has joined the world
+    async fn on_play_ready(&mut self) -> anyhow::Result<bool> { Ok(true) }
     /// Called whenever a chat message is sent by the user
     async fn on_chat(&mut self, packet: ChatC2S) -> anyhow::Result<bool> { Ok(true) }
+    /// Called whenever the user runs a command; splits it up and passes it to `on_command` by default
+    async fn on_chat_command(&mut self, packet: ChatCommandC2S) -> anyhow::Result<bool> {
+        let mut parts = packet.command.split_whitespace();
+        let name = match parts.next() {
+            Some(name) => name.to_owned(),
+            None => return Ok(false)
+        };
+        let args = parts.map(|a| a.to_owned()).collect();
+        if !self.on_command(name, args).await? {
+            let mut msg = TextComponent::plain("Unknown command. Type /help for a list of commands.");
+            msg.set_color(ChatColor::Red);
+            return self.send_game_message(msg, false);
+        }
+        Ok(true)
+    }
+    /// Called with a parsed command, should return false if the command is unknown
+    async fn on_command(&mut self, name: String, args: Vec<String>) -> anyhow::Result<bool> { Ok(false) }
 
     /// Set the user's profile
     async fn set_profile(&mut self, profile: Profile);
     /// Get the user's profile
     async fn get_profile(&mut self) -> &Profile;
 
+    /// Called right before the user is kicked
+    fn on_kick(&mut self, reason: &TextComponent) {}
+    /// Called once the connection is closed, however that happened
+    async fn on_disconnect(&mut self) -> anyhow::Result<bool> { Ok(true) }
+
     // Implemented by default
     fn kick(&mut self, reason: TextComponent) -> anyhow::Result<bool> {
+        self.on_kick(&reason);
         match self.get_stage() {
             Stage::Login => self.send_packet(Box::new(LoginDisconnectS2C { reason })),
             Stage::Config => self.send_packet(Box::new(ConfigDisconnectS2C { reason })),
@@ -54,13 +116,50 @@ pub trait PacketHandler {
         }
     }
 
+    /// Sends the user to another server, returning false if their client can't be transferred (before 1.20.5)
+    fn transfer(&mut self, host: &str, port: u16) -> anyhow::Result<bool> {
+        if self.get_version() < ProtocolVersion::V1_20_5 {
+            return Ok(false);
+        }
+        let host = host.to_owned();
+        match self.get_stage() {
+            Stage::Config => self.send_packet(Box::new(ServerTransferS2C { host, port })),
+            Stage::Play => self.send_packet(Box::new(PlayTransferS2C { host, port })),
+            _ => Ok(false)
+        }
+    }
+
+    /// Stores a cookie on the client, which it keeps across transfers (1.20.5+)
+    fn store_cookie(&mut self, key: &str, payload: Bytes) -> anyhow::Result<bool> {
+        if self.get_version() < ProtocolVersion::V1_20_5 {
+            return Ok(false);
+        }
+        let key = key.to_owned();
+        match self.get_stage() {
+            Stage::Config => self.send_packet(Box::new(StoreCookieS2C { key, payload })),
+            Stage::Play => self.send_packet(Box::new(PlayStoreCookieS2C { key, payload })),
+            _ => Ok(false)
+        }
+    }
+
     fn send_game_message(&mut self, text: TextComponent, overlay: bool) -> anyhow::Result<bool> {
         self.send_packet(Box::new(GameMessageS2C { text, overlay }))
     }
+
+    /// The commands declared to the client, for tab-completion and usage hints
+    fn get_commands(&self) -> Vec<CommandSpec> {
+        vec![]
+    }
+
+    fn send_commands(&mut self) -> anyhow::Result<bool> {
+        let nodes = CommandNode::build_graph(&self.get_commands());
+        self.send_packet(Box::new(DeclareCommandsS2C { nodes, root_index: 0 }))
+    }
 }
 
 pub struct DefaultPacketHandler {
     pub stage: Stage,
+    pub version: ProtocolVersion,
     // For sending packets
     pub channel: UnboundedSender<Box<dyn PacketS2C + Send>>,
     // User profile
@@ -71,6 +170,7 @@ impl DefaultPacketHandler {
     pub fn new() -> DefaultPacketHandler {
         DefaultPacketHandler {
             stage: Stage::Handshake,
+            version: ProtocolVersion::Unknown,
             channel: mpsc::unbounded_channel().0,  // placeholder channel
             profile: Profile {
                 id: Uuid::from_u128(0u128),
@@ -124,6 +224,14 @@ impl PacketHandler for DefaultPacketHandler {
         &self.stage
     }
 
+    fn set_version(&mut self, version: ProtocolVersion) {
+        self.version = version;
+    }
+
+    fn get_version(&self) -> ProtocolVersion {
+        self.version
+    }
+
     // Network actions
     fn send_packet(&mut self, packet: Box<dyn PacketS2C + Send>) -> anyhow::Result<bool> {
         match self.channel.send(packet) {
@@ -140,3 +248,158 @@ impl PacketHandler for DefaultPacketHandler {
         &self.profile
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use async_trait::async_trait;
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc::UnboundedSender;
+    use crate::server::base::Server;
+    use crate::server::client::Client;
+    use crate::server::common::{ClientInfo, Profile};
+    use crate::server::handler::{DefaultPacketHandler, PacketHandler};
+    use crate::server::packets::c2s::login::LoginHelloC2S;
+    use crate::server::packets::packet::PacketS2C;
+    use crate::server::packets::stage::Stage;
+    use crate::server::text::TextComponent;
+    use crate::server::version::ProtocolVersion;
+
+    /// Writes down which hooks were called, in order
+    struct RecordingHandler {
+        inner: DefaultPacketHandler,
+        events: Arc<Mutex<Vec<&'static str>>>
+    }
+
+    impl RecordingHandler {
+        fn record(&self, event: &'static str) {
+            self.events.lock().unwrap().push(event);
+        }
+    }
+
+    #[async_trait]
+    impl PacketHandler for RecordingHandler {
+        fn set_channel(&mut self, sender: UnboundedSender<Box<dyn PacketS2C + Send>>) {
+            self.inner.set_channel(sender);
+        }
+
+        fn set_stage(&mut self, new_stage: Stage) {
+            self.inner.stage = new_stage;
+        }
+
+        fn get_stage(&self) -> &Stage {
+            &self.inner.stage
+        }
+
+        fn set_version(&mut self, version: ProtocolVersion) {
+            self.inner.version = version;
+        }
+
+        fn get_version(&self) -> ProtocolVersion {
+            self.inner.version
+        }
+
+        fn send_packet(&mut self, packet: Box<dyn PacketS2C + Send>) -> anyhow::Result<bool> {
+            PacketHandler::send_packet(&mut self.inner, packet)
+        }
+
+        async fn on_login_start(&mut self, packet: LoginHelloC2S) -> anyhow::Result<bool> {
+            self.record("login_start");
+            if packet.name == "Banned" {
+                self.kick(TextComponent::plain("Go away"))?;
+                return Ok(false);
+            }
+            Ok(true)
+        }
+
+        async fn on_login_success(&mut self) -> anyhow::Result<bool> {
+            self.record("login_success");
+            Ok(true)
+        }
+
+        async fn on_client_info(&mut self, info: ClientInfo) -> anyhow::Result<bool> {
+            self.record("client_info");
+            Ok(true)
+        }
+
+        async fn on_config_ready(&mut self) -> anyhow::Result<bool> {
+            self.record("config_ready");
+            Ok(true)
+        }
+
+        async fn on_play_ready(&mut self) -> anyhow::Result<bool> {
+            self.record("play_ready");
+            self.send_game_message(TextComponent::plain("ready"), false)
+        }
+
+        async fn on_keepalive(&mut self, id: u64) -> anyhow::Result<bool> {
+            // Only the first one, so the log stays predictable
+            if !self.events.lock().unwrap().contains(&"keepalive") {
+                self.record("keepalive");
+                self.send_game_message(TextComponent::plain("pong"), false)?;
+            }
+            Ok(true)
+        }
+
+        async fn on_disconnect(&mut self) -> anyhow::Result<bool> {
+            self.record("disconnect");
+            Ok(true)
+        }
+
+        async fn set_profile(&mut self, profile: Profile) {
+            self.inner.profile = profile;
+        }
+
+        async fn get_profile(&mut self) -> &Profile {
+            &self.inner.profile
+        }
+    }
+
+    /// Waits for the connection to finish closing
+    async fn wait_for_disconnect(events: &Arc<Mutex<Vec<&'static str>>>) {
+        while !events.lock().unwrap().contains(&"disconnect") {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn lifecycle_hooks_run_in_order() {
+        let log: Arc<Mutex<Vec<Arc<Mutex<Vec<&'static str>>>>>> = Arc::new(Mutex::new(Vec::new()));
+        let connections = log.clone();
+        let mut server = Server::new();
+        server.set_online_mode(false);
+        server.set_handler_factory(move || {
+            let events = Arc::new(Mutex::new(Vec::new()));
+            connections.lock().unwrap().push(events.clone());
+            Box::new(RecordingHandler { inner: DefaultPacketHandler::new(), events })
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(server.serve(listener));
+
+        tokio::time::timeout(Duration::from_secs(60), async {
+            let versions = [ProtocolVersion::V1_20_4, ProtocolVersion::V1_20_6, ProtocolVersion::V1_21];
+            for (i, version) in versions.into_iter().enumerate() {
+                let mut client = Client::connect(&addr, version).await.unwrap();
+                client.login("Swan").await.unwrap();
+                client.configure().await.unwrap();
+                assert_eq!(client.next_message().await.unwrap().to_plain(), "ready");
+                assert_eq!(client.next_message().await.unwrap().to_plain(), "pong");
+                drop(client);
+                let events = log.lock().unwrap()[i].clone();
+                wait_for_disconnect(&events).await;
+                assert_eq!(*events.lock().unwrap(), vec!["login_start", "login_success", "client_info", "config_ready",
+                                                         "play_ready", "keepalive", "disconnect"], "on {}", version.get_name());
+            }
+
+            let mut client = Client::connect(&addr, ProtocolVersion::V1_21).await.unwrap();
+            assert!(client.login("Banned").await.is_err());
+            assert_eq!(client.disconnect_reason.take().unwrap().to_plain(), "Go away");
+            drop(client);
+            let events = log.lock().unwrap()[versions.len()].clone();
+            wait_for_disconnect(&events).await;
+            assert_eq!(*events.lock().unwrap(), vec!["login_start", "disconnect"]);
+        }).await.expect("timed out");
+    }
+}
@@ -7,4 +7,15 @@ pub(crate) mod handler;
 pub(crate) mod status;
 pub(crate) mod text;
 pub(crate) mod enums;
-pub(crate) mod common;
\ No newline at end of file
+pub(crate) mod common;
+pub(crate) mod forwarding;
+pub(crate) mod command;
+pub(crate) mod access;
+pub(crate) mod compression;
+pub(crate) mod codec;
+pub(crate) mod players;
+pub(crate) mod cookie;
+pub(crate) mod world;
+pub(crate) mod registry;
+#[cfg(any(test, feature = "client"))]
+pub mod client;
\ No newline at end of file
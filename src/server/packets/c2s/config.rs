@@ -1,10 +1,19 @@
-use bytes::{Buf, Bytes};
+use bytes::Bytes;
 use crate::server::common::ClientInfo;
+use crate::server::cookie::MAX_COOKIE_SIZE;
 use crate::server::enums::{Arm, ChatVisibility};
 use crate::server::packets::c2s::config::ResourcePackStatus::{Accepted, Declined, Failed, Success};
+use crate::server::packets::decode::{DecodeError, PacketReader, MAX_STRING_LENGTH};
 use crate::server::packets::packet::PacketC2S;
-use crate::server::utils::{read_string, read_varint};
+use crate::server::packets::ids;
+use crate::server::packets::stage::Stage;
 use crate::server::version::ProtocolVersion;
+#[cfg(any(test, feature = "client"))]
+use bytes::{BufMut, BytesMut};
+#[cfg(any(test, feature = "client"))]
+use crate::server::packets::c2s::login::encode_cookie_response;
+#[cfg(any(test, feature = "client"))]
+use crate::server::utils::{write_string, write_varint};
 
 #[derive(Debug, Clone)]
 pub struct ClientInfoC2S {
@@ -12,23 +21,39 @@ pub struct ClientInfoC2S {
 }
 
 impl PacketC2S for ClientInfoC2S {
-    fn decode(buf: &mut Bytes, _: ProtocolVersion) -> Self {
-        ClientInfoC2S {
+    fn decode(buf: &mut Bytes, _: ProtocolVersion) -> Result<Self, DecodeError> {
+        let mut r = PacketReader::new(buf, "client_information");
+        Ok(ClientInfoC2S {
             client_info: ClientInfo {
-                lang: read_string(buf),
-                view_distance: buf.get_u8(),
-                chat_visibility: ChatVisibility::from_i32(read_varint(buf)),
-                chat_colors_enabled: buf.get_u8() != 0,
-                player_model_parts: buf.get_u8(),
-                main_arm: Arm::from_i32(read_varint(buf)),
-                filters_text: buf.get_u8() != 0,
-                allows_server_listing: buf.get_u8() != 0
+                lang: r.string("language", 16)?,
+                view_distance: r.u8("view distance")?,
+                chat_visibility: ChatVisibility::from_i32(r.varint("chat visibility")?),
+                chat_colors_enabled: r.bool("chat colors")?,
+                player_model_parts: r.u8("model parts")?,
+                main_arm: Arm::from_i32(r.varint("main hand")?),
+                filters_text: r.bool("text filtering")?,
+                allows_server_listing: r.bool("server listing")?
             }
-        }
+        })
+    }
+
+    #[cfg(any(test, feature = "client"))]
+    fn encode(&self, _: ProtocolVersion) -> BytesMut {
+        let info = &self.client_info;
+        let mut buf = BytesMut::new();
+        write_string(&mut buf, &info.lang);
+        buf.put_u8(info.view_distance);
+        write_varint(&mut buf, info.chat_visibility.to_i32());
+        buf.put_u8(if info.chat_colors_enabled { 1 } else { 0 });
+        buf.put_u8(info.player_model_parts);
+        write_varint(&mut buf, info.main_arm.to_i32());
+        buf.put_u8(if info.filters_text { 1 } else { 0 });
+        buf.put_u8(if info.allows_server_listing { 1 } else { 0 });
+        buf
     }
 
-    fn id(_: ProtocolVersion) -> i32 {
-        0
+    fn id(v: ProtocolVersion) -> i32 {
+        ids::serverbound(Stage::Config, "client_information", v)
     }
 }
 
@@ -40,19 +65,23 @@ pub struct CookieResponseC2S {
 }
 
 impl PacketC2S for CookieResponseC2S {
-    fn decode(buf: &mut Bytes, _: ProtocolVersion) -> Self {
-        CookieResponseC2S {
-            key: read_string(buf),
-            payload: if buf.get_u8() != 0 { Some({
-                let p = buf.clone();
-                buf.advance(p.len());
-                p
-            }) } else { None }
-        }
+    fn decode(buf: &mut Bytes, _: ProtocolVersion) -> Result<Self, DecodeError> {
+        let mut r = PacketReader::new(buf, "cookie_response");
+        Ok(CookieResponseC2S {
+            key: r.string("key", MAX_STRING_LENGTH)?,
+            payload: if r.bool("has payload")? {
+                Some(r.byte_array("payload", MAX_COOKIE_SIZE)?)
+            } else { None }
+        })
     }
 
-    fn id(_: ProtocolVersion) -> i32 {
-        1
+    #[cfg(any(test, feature = "client"))]
+    fn encode(&self, _: ProtocolVersion) -> BytesMut {
+        encode_cookie_response(&self.key, &self.payload)
+    }
+
+    fn id(v: ProtocolVersion) -> i32 {
+        ids::serverbound(Stage::Config, "cookie_response", v)
     }
 }
 
@@ -64,19 +93,24 @@ pub struct CustomPayloadC2S {
 }
 
 impl PacketC2S for CustomPayloadC2S {
-    fn decode(buf: &mut Bytes, _: ProtocolVersion) -> Self {
-        CustomPayloadC2S {
-            key: read_string(buf),
-            payload: {
-                let p = buf.clone();
-                buf.advance(p.len());
-                p
-            }
-        }
+    fn decode(buf: &mut Bytes, _: ProtocolVersion) -> Result<Self, DecodeError> {
+        let mut r = PacketReader::new(buf, "custom_payload");
+        Ok(CustomPayloadC2S {
+            key: r.string("channel", MAX_STRING_LENGTH)?,
+            payload: r.rest()
+        })
+    }
+
+    #[cfg(any(test, feature = "client"))]
+    fn encode(&self, _: ProtocolVersion) -> BytesMut {
+        let mut buf = BytesMut::new();
+        write_string(&mut buf, &self.key);
+        buf.put(&self.payload[..]);
+        buf
     }
 
-    fn id(_: ProtocolVersion) -> i32 {
-        2
+    fn id(v: ProtocolVersion) -> i32 {
+        ids::serverbound(Stage::Config, "custom_payload", v)
     }
 }
 
@@ -86,12 +120,17 @@ impl PacketC2S for CustomPayloadC2S {
 pub struct ReadyC2S { }
 
 impl PacketC2S for ReadyC2S {
-    fn decode(_: &mut Bytes, _: ProtocolVersion) -> Self {
-        ReadyC2S { }
+    fn decode(_: &mut Bytes, _: ProtocolVersion) -> Result<Self, DecodeError> {
+        Ok(ReadyC2S { })
     }
 
-    fn id(_: ProtocolVersion) -> i32 {
-        3
+    #[cfg(any(test, feature = "client"))]
+    fn encode(&self, _: ProtocolVersion) -> BytesMut {
+        BytesMut::new()
+    }
+
+    fn id(v: ProtocolVersion) -> i32 {
+        ids::serverbound(Stage::Config, "finish_configuration", v)
     }
 }
 
@@ -102,14 +141,22 @@ pub struct KeepAliveC2S {
 }
 
 impl PacketC2S for KeepAliveC2S {
-    fn decode(buf: &mut Bytes, _: ProtocolVersion) -> Self {
-        KeepAliveC2S {
-            id: buf.get_u64()
-        }
+    fn decode(buf: &mut Bytes, _: ProtocolVersion) -> Result<Self, DecodeError> {
+        let mut r = PacketReader::new(buf, "keep_alive");
+        Ok(KeepAliveC2S {
+            id: r.u64("id")?
+        })
     }
 
-    fn id(_: ProtocolVersion) -> i32 {
-        4
+    #[cfg(any(test, feature = "client"))]
+    fn encode(&self, _: ProtocolVersion) -> BytesMut {
+        let mut buf = BytesMut::new();
+        buf.put_u64(self.id);
+        buf
+    }
+
+    fn id(v: ProtocolVersion) -> i32 {
+        ids::serverbound(Stage::Config, "keep_alive", v)
     }
 }
 
@@ -120,14 +167,22 @@ pub struct PongC2S {
 }
 
 impl PacketC2S for PongC2S {
-    fn decode(buf: &mut Bytes, _: ProtocolVersion) -> Self {
-        PongC2S {
-            id: buf.get_u32()
-        }
+    fn decode(buf: &mut Bytes, _: ProtocolVersion) -> Result<Self, DecodeError> {
+        let mut r = PacketReader::new(buf, "pong");
+        Ok(PongC2S {
+            id: r.u32("id")?
+        })
+    }
+
+    #[cfg(any(test, feature = "client"))]
+    fn encode(&self, _: ProtocolVersion) -> BytesMut {
+        let mut buf = BytesMut::new();
+        buf.put_u32(self.id);
+        buf
     }
 
-    fn id(_: ProtocolVersion) -> i32 {
-        5
+    fn id(v: ProtocolVersion) -> i32 {
+        ids::serverbound(Stage::Config, "pong", v)
     }
 }
 
@@ -149,6 +204,16 @@ impl ResourcePackStatus {
             _ => Failed,  // 2 or default
         }
     }
+
+    #[cfg(any(test, feature = "client"))]
+    pub fn to_i32(&self) -> i32 {
+        match self {
+            Success => 0,
+            Declined => 1,
+            Failed => 2,
+            Accepted => 3
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -157,14 +222,22 @@ pub struct ResourcePackStatusC2S {
 }
 
 impl PacketC2S for ResourcePackStatusC2S {
-    fn decode(buf: &mut Bytes, _: ProtocolVersion) -> Self {
-        ResourcePackStatusC2S {
-            status: ResourcePackStatus::from_i32(read_varint(buf))
-        }
+    fn decode(buf: &mut Bytes, _: ProtocolVersion) -> Result<Self, DecodeError> {
+        let mut r = PacketReader::new(buf, "resource_pack");
+        Ok(ResourcePackStatusC2S {
+            status: ResourcePackStatus::from_i32(r.varint("status")?)
+        })
+    }
+
+    #[cfg(any(test, feature = "client"))]
+    fn encode(&self, _: ProtocolVersion) -> BytesMut {
+        let mut buf = BytesMut::new();
+        write_varint(&mut buf, self.status.to_i32());
+        buf
     }
 
-    fn id(_: ProtocolVersion) -> i32 {
-        6
+    fn id(v: ProtocolVersion) -> i32 {
+        ids::serverbound(Stage::Config, "resource_pack", v)
     }
 }
 
@@ -182,20 +255,33 @@ pub struct SelectKnownPacksC2S {
 }
 
 impl PacketC2S for SelectKnownPacksC2S {
-    fn decode(buf: &mut Bytes, _: ProtocolVersion) -> Self {
-        let num_packs = read_varint(buf);
+    fn decode(buf: &mut Bytes, _: ProtocolVersion) -> Result<Self, DecodeError> {
+        let mut r = PacketReader::new(buf, "select_known_packs");
+        let num_packs = r.length("pack count", 64)?;
         let mut packs = Vec::new();
         for _ in 0..num_packs {
             packs.push(VersionedIdentifier {
-                namespace: read_string(buf),
-                id: read_string(buf),
-                version: read_string(buf),
+                namespace: r.string("pack namespace", MAX_STRING_LENGTH)?,
+                id: r.string("pack id", MAX_STRING_LENGTH)?,
+                version: r.string("pack version", MAX_STRING_LENGTH)?,
             });
         }
-        SelectKnownPacksC2S { known_packs: packs }
+        Ok(SelectKnownPacksC2S { known_packs: packs })
+    }
+
+    #[cfg(any(test, feature = "client"))]
+    fn encode(&self, _: ProtocolVersion) -> BytesMut {
+        let mut buf = BytesMut::new();
+        write_varint(&mut buf, self.known_packs.len() as i32);
+        for pack in &self.known_packs {
+            write_string(&mut buf, &pack.namespace);
+            write_string(&mut buf, &pack.id);
+            write_string(&mut buf, &pack.version);
+        }
+        buf
     }
 
-    fn id(_: ProtocolVersion) -> i32 {
-        7
+    fn id(v: ProtocolVersion) -> i32 {
+        ids::serverbound(Stage::Config, "select_known_packs", v)
     }
 }
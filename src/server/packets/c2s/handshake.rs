@@ -1,8 +1,13 @@
-use bytes::{Buf, Bytes};
+use bytes::Bytes;
+use crate::server::packets::decode::{DecodeError, PacketReader, MAX_STRING_LENGTH};
 use crate::server::packets::packet::PacketC2S;
+use crate::server::packets::ids;
 use crate::server::packets::stage::Stage;
-use crate::server::utils::{read_string, read_varint};
 use crate::server::version::ProtocolVersion;
+#[cfg(any(test, feature = "client"))]
+use bytes::{BufMut, BytesMut};
+#[cfg(any(test, feature = "client"))]
+use crate::server::utils::{write_string, write_varint};
 
 /// Unchanged since Netty rewrite
 #[derive(Debug, Clone)]
@@ -15,16 +20,28 @@ pub struct HandshakeC2S {
 
 impl PacketC2S for HandshakeC2S {
     /// The protocol version will still be Unknown here; this packet should set it
-    fn decode(buf: &mut Bytes, _: ProtocolVersion) -> Self {
-        HandshakeC2S {
-            version: ProtocolVersion::from_id(read_varint(buf)),
-            address: read_string(buf),
-            port: buf.get_u16(),
-            next_stage: Stage::from_id(read_varint(buf))
-        }
+    fn decode(buf: &mut Bytes, _: ProtocolVersion) -> Result<Self, DecodeError> {
+        let mut r = PacketReader::new(buf, "intention");
+        Ok(HandshakeC2S {
+            version: ProtocolVersion::from_id(r.varint("protocol version")?),
+            // BungeeCord forwarding appends the player's address and profile here
+            address: r.string("address", MAX_STRING_LENGTH)?,
+            port: r.u16("port")?,
+            next_stage: Stage::from_id(r.varint("next stage")?)
+        })
     }
 
-    fn id(_: ProtocolVersion) -> i32 {
-        0  // always
+    #[cfg(any(test, feature = "client"))]
+    fn encode(&self, _: ProtocolVersion) -> BytesMut {
+        let mut buf = BytesMut::new();
+        write_varint(&mut buf, self.version.get_id());
+        write_string(&mut buf, &self.address);
+        buf.put_u16(self.port);
+        write_varint(&mut buf, self.next_stage.get_id());
+        buf
+    }
+
+    fn id(v: ProtocolVersion) -> i32 {
+        ids::serverbound(Stage::Handshake, "intention", v)
     }
 }
@@ -1,9 +1,15 @@
-use bytes::{Buf, Bytes};
+use bytes::Bytes;
 use uuid::Uuid;
+use crate::server::packets::decode::{DecodeError, PacketReader, MAX_STRING_LENGTH};
 use crate::server::packets::packet::PacketC2S;
+use crate::server::packets::ids;
+use crate::server::cookie::MAX_COOKIE_SIZE;
 use crate::server::packets::stage::Stage;
-use crate::server::utils::{read_string, read_uuid, read_varint};
 use crate::server::version::ProtocolVersion;
+#[cfg(any(test, feature = "client"))]
+use bytes::{BufMut, BytesMut};
+#[cfg(any(test, feature = "client"))]
+use crate::server::utils::{write_string, write_uuid, write_varint};
 
 #[derive(Debug, Clone)]
 pub struct LoginHelloC2S {
@@ -18,49 +24,77 @@ pub struct LoginHelloC2S {
 }
 
 impl PacketC2S for LoginHelloC2S {
-    fn decode(buf: &mut Bytes, v: ProtocolVersion) -> Self {
-        let name = read_string(buf);
+    fn decode(buf: &mut Bytes, v: ProtocolVersion) -> Result<Self, DecodeError> {
+        let mut r = PacketReader::new(buf, "hello");
+        let name = r.string("name", 16)?;
         let mut uuid = None;
         let mut expires_at = None;
         let mut public_key = None;
         let mut signature = None;
         if v >= ProtocolVersion::V1_19 {
             if v < ProtocolVersion::V1_19_3 {  // only present for a few versions
-                let has_sig_data = buf.get_u8() != 0;
+                let has_sig_data = r.bool("has signature data")?;
                 if has_sig_data {
-                    expires_at = Some(buf.get_u64());
-                    let public_key_len = read_varint(buf) as usize;
-                    public_key = Some(buf.slice(0..public_key_len));
-                    buf.advance(public_key_len);
-                    let signature_len = read_varint(buf) as usize;
-                    signature = Some(buf.slice(0..signature_len));
-                    buf.advance(signature_len);
+                    expires_at = Some(r.u64("expires at")?);
+                    public_key = Some(r.byte_array("public key", 512)?);
+                    signature = Some(r.byte_array("signature", 4096)?);
                 }
             }
             let has_uuid = if v < ProtocolVersion::V1_20_2 {
-                buf.get_u8() != 0
+                r.bool("has uuid")?
             } else {
                 true
             };
             if has_uuid {
-                uuid = Some(read_uuid(buf));
+                uuid = Some(r.uuid("uuid")?);
             }
         }
-        LoginHelloC2S {
+        Ok(LoginHelloC2S {
             name,
             uuid,
             expires_at,
             public_key,
             signature
+        })
+    }
+
+    #[cfg(any(test, feature = "client"))]
+    fn encode(&self, v: ProtocolVersion) -> BytesMut {
+        let mut buf = BytesMut::new();
+        write_string(&mut buf, &self.name);
+        if v >= ProtocolVersion::V1_19 {
+            if v < ProtocolVersion::V1_19_3 {
+                match (self.expires_at, &self.public_key, &self.signature) {
+                    (Some(expires_at), Some(public_key), Some(signature)) => {
+                        buf.put_u8(1);
+                        buf.put_u64(expires_at);
+                        write_bytes(&mut buf, public_key);
+                        write_bytes(&mut buf, signature);
+                    },
+                    _ => buf.put_u8(0)
+                }
+            }
+            if v < ProtocolVersion::V1_20_2 {
+                buf.put_u8(if self.uuid.is_some() { 1 } else { 0 });
+            }
+            if let Some(uuid) = self.uuid {
+                write_uuid(&mut buf, uuid);
+            } else if v >= ProtocolVersion::V1_20_2 {
+                write_uuid(&mut buf, Uuid::nil());
+            }
         }
+        buf
     }
 
-    fn id(_: ProtocolVersion) -> i32 {
-        0
+    fn id(v: ProtocolVersion) -> i32 {
+        ids::serverbound(Stage::Login, "hello", v)
     }
 }
 
 
+/// Encrypted with our 2048-bit key, so these are 256 bytes
+const MAX_ENCRYPTED_LENGTH: usize = 256;
+
 #[derive(Debug, Clone)]
 pub struct LoginKeyC2S {
     pub(crate) shared_secret: Bytes,
@@ -73,59 +107,88 @@ pub struct LoginKeyC2S {
 }
 
 impl PacketC2S for LoginKeyC2S {
-    fn decode(buf: &mut Bytes, v: ProtocolVersion) -> Self {
-        let shared_secret_len = read_varint(buf) as usize;
-        let shared_secret = buf.slice(0..shared_secret_len);
+    fn decode(buf: &mut Bytes, v: ProtocolVersion) -> Result<Self, DecodeError> {
+        let mut r = PacketReader::new(buf, "key");
+        let shared_secret = r.byte_array("shared secret", MAX_ENCRYPTED_LENGTH)?;
         let mut nonce = None;
         let mut salt = None;
         let mut message_signature = None;
-        buf.advance(shared_secret_len);
         if v >= ProtocolVersion::V1_19 && v < ProtocolVersion::V1_19_3 {
-            let has_nonce = buf.get_u8() != 0;
+            let has_nonce = r.bool("has nonce")?;
             if has_nonce {
-                let nonce_len = read_varint(buf) as usize;
-                nonce = Some(buf.slice(0..nonce_len));
-                buf.advance(nonce_len);
+                nonce = Some(r.byte_array("nonce", MAX_ENCRYPTED_LENGTH)?);
             } else {
-                salt = Some(buf.get_u64());
-                let sig_len = read_varint(buf) as usize;
-                message_signature = Some(buf.slice(0..sig_len));
-                buf.advance(sig_len);
+                salt = Some(r.u64("salt")?);
+                message_signature = Some(r.byte_array("message signature", MAX_ENCRYPTED_LENGTH)?);
             }
         } else {
-            let nonce_len = read_varint(buf) as usize;
-            nonce = Some(buf.slice(0..nonce_len));
-            buf.advance(nonce_len);
+            nonce = Some(r.byte_array("nonce", MAX_ENCRYPTED_LENGTH)?);
         }
-        LoginKeyC2S {
+        Ok(LoginKeyC2S {
             shared_secret,
             nonce,
             salt,
             message_signature
+        })
+    }
+
+    #[cfg(any(test, feature = "client"))]
+    fn encode(&self, v: ProtocolVersion) -> BytesMut {
+        let mut buf = BytesMut::new();
+        write_bytes(&mut buf, &self.shared_secret);
+        if v >= ProtocolVersion::V1_19 && v < ProtocolVersion::V1_19_3 {
+            match (&self.nonce, self.salt, &self.message_signature) {
+                (Some(nonce), _, _) => {
+                    buf.put_u8(1);
+                    write_bytes(&mut buf, nonce);
+                },
+                (None, Some(salt), Some(signature)) => {
+                    buf.put_u8(0);
+                    buf.put_u64(salt);
+                    write_bytes(&mut buf, signature);
+                },
+                _ => buf.put_u8(1)
+            }
+        } else {
+            write_bytes(&mut buf, self.nonce.as_ref().unwrap_or(&Bytes::new()));
         }
+        buf
     }
 
-    fn id(_: ProtocolVersion) -> i32 {
-        1
+    fn id(v: ProtocolVersion) -> i32 {
+        ids::serverbound(Stage::Login, "key", v)
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct LoginQueryResponseC2S {
     pub(crate) query_id: i32,
+    /// False if the client didn't understand the channel
+    pub(crate) successful: bool,
     pub(crate) response: Bytes
 }
 
 impl PacketC2S for LoginQueryResponseC2S {
-    fn decode(buf: &mut Bytes, _: ProtocolVersion) -> Self {
-        LoginQueryResponseC2S {
-            query_id: read_varint(buf),
-            response: buf.slice(0..)
-        }
+    fn decode(buf: &mut Bytes, _: ProtocolVersion) -> Result<Self, DecodeError> {
+        let mut r = PacketReader::new(buf, "custom_query_answer");
+        Ok(LoginQueryResponseC2S {
+            query_id: r.varint("query id")?,
+            successful: r.bool("successful")?,
+            response: r.rest()
+        })
+    }
+
+    #[cfg(any(test, feature = "client"))]
+    fn encode(&self, _: ProtocolVersion) -> BytesMut {
+        let mut buf = BytesMut::new();
+        write_varint(&mut buf, self.query_id);
+        buf.put_u8(if self.successful { 1 } else { 0 });
+        buf.put(&self.response[..]);
+        buf
     }
 
-    fn id(_: ProtocolVersion) -> i32 {
-        2
+    fn id(v: ProtocolVersion) -> i32 {
+        ids::serverbound(Stage::Login, "custom_query_answer", v)
     }
 }
 
@@ -134,12 +197,17 @@ pub struct EnterConfigurationC2S {
 }
 
 impl PacketC2S for EnterConfigurationC2S {
-    fn decode(_: &mut Bytes, _: ProtocolVersion) -> Self {
-        EnterConfigurationC2S { }
+    fn decode(_: &mut Bytes, _: ProtocolVersion) -> Result<Self, DecodeError> {
+        Ok(EnterConfigurationC2S { })
     }
 
-    fn id(_: ProtocolVersion) -> i32 {
-        3
+    #[cfg(any(test, feature = "client"))]
+    fn encode(&self, _: ProtocolVersion) -> BytesMut {
+        BytesMut::new()
+    }
+
+    fn id(v: ProtocolVersion) -> i32 {
+        ids::serverbound(Stage::Login, "login_acknowledged", v)
     }
 }
 
@@ -150,14 +218,43 @@ pub struct CookieResponseC2S {
 }
 
 impl PacketC2S for CookieResponseC2S {
-    fn decode(buf: &mut Bytes, _: ProtocolVersion) -> Self {
-        CookieResponseC2S {
-            key: read_string(buf),
-            payload: if buf.get_u8() != 0 { Some(buf.slice(0..)) } else { None }
-        }
+    fn decode(buf: &mut Bytes, _: ProtocolVersion) -> Result<Self, DecodeError> {
+        let mut r = PacketReader::new(buf, "cookie_response");
+        Ok(CookieResponseC2S {
+            key: r.string("key", MAX_STRING_LENGTH)?,
+            payload: if r.bool("has payload")? {
+                Some(r.byte_array("payload", MAX_COOKIE_SIZE)?)
+            } else { None }
+        })
     }
 
-    fn id(_: ProtocolVersion) -> i32 {
-        4
+    #[cfg(any(test, feature = "client"))]
+    fn encode(&self, _: ProtocolVersion) -> BytesMut {
+        encode_cookie_response(&self.key, &self.payload)
+    }
+
+    fn id(v: ProtocolVersion) -> i32 {
+        ids::serverbound(Stage::Login, "cookie_response", v)
+    }
+}
+
+#[cfg(any(test, feature = "client"))]
+fn write_bytes(buf: &mut BytesMut, bytes: &Bytes) {
+    write_varint(buf, bytes.len() as i32);
+    buf.put(&bytes[..]);
+}
+
+/// Cookie responses look the same in the login and configuration stages
+#[cfg(any(test, feature = "client"))]
+pub(crate) fn encode_cookie_response(key: &str, payload: &Option<Bytes>) -> BytesMut {
+    let mut buf = BytesMut::new();
+    write_string(&mut buf, key);
+    match payload {
+        Some(payload) => {
+            buf.put_u8(1);
+            write_bytes(&mut buf, payload);
+        },
+        None => buf.put_u8(0)
     }
+    buf
 }
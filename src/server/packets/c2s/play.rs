@@ -1,7 +1,13 @@
-use bytes::{Buf, Bytes};
+use bytes::Bytes;
+use crate::server::packets::decode::{DecodeError, PacketReader, MAX_STRING_LENGTH};
 use crate::server::packets::packet::PacketC2S;
-use crate::server::utils::{read_string, read_varint};
+use crate::server::packets::ids;
+use crate::server::packets::stage::Stage;
 use crate::server::version::ProtocolVersion;
+#[cfg(any(test, feature = "client"))]
+use bytes::{BufMut, BytesMut};
+#[cfg(any(test, feature = "client"))]
+use crate::server::utils::{write_string, write_varint};
 
 #[derive(Debug, Clone)]
 pub struct ChatC2S {
@@ -14,23 +20,210 @@ pub struct ChatC2S {
 }
 
 impl PacketC2S for ChatC2S {
-    fn decode(buf: &mut Bytes, _: ProtocolVersion) -> Self {
-        ChatC2S {
-            message: read_string(buf),
-            timestamp: buf.get_u64(),
-            salt: buf.get_u64(),
-            signature: if buf.get_u8() != 0 {
-                let d = buf.slice(0..256);
-                buf.advance(256);
-                Some(d)
+    fn decode(buf: &mut Bytes, _: ProtocolVersion) -> Result<Self, DecodeError> {
+        let mut r = PacketReader::new(buf, "chat");
+        Ok(ChatC2S {
+            message: r.string("message", 256)?,
+            timestamp: r.u64("timestamp")?,
+            salt: r.u64("salt")?,
+            signature: if r.bool("has signature")? {
+                Some(r.bytes("signature", 256)?)
             } else { None },
-            message_count: read_varint(buf),
-            acknowledged: ((buf.get_u16() as u32) << 8) | (buf.get_u8() as u32)
+            message_count: r.varint("message count")?,
+            acknowledged: ((r.u16("acknowledged")? as u32) << 8) | (r.u8("acknowledged")? as u32)
+        })
+    }
 
+    #[cfg(any(test, feature = "client"))]
+    fn encode(&self, _: ProtocolVersion) -> BytesMut {
+        let mut buf = BytesMut::new();
+        write_string(&mut buf, &self.message);
+        buf.put_u64(self.timestamp);
+        buf.put_u64(self.salt);
+        match &self.signature {
+            Some(signature) => {
+                buf.put_u8(1);
+                buf.put(&signature[..]);
+            },
+            None => buf.put_u8(0)
         }
+        write_varint(&mut buf, self.message_count);
+        buf.put_u16((self.acknowledged >> 8) as u16);
+        buf.put_u8(self.acknowledged as u8);
+        buf
+    }
+
+    fn id(v: ProtocolVersion) -> i32 {
+        ids::serverbound(Stage::Play, "chat", v)
+    }
+}
+
+
+/// Commands are sent without the leading slash
+#[derive(Debug, Clone)]
+pub struct ChatCommandC2S {
+    pub(crate) command: String,
+}
+
+impl PacketC2S for ChatCommandC2S {
+    fn decode(buf: &mut Bytes, _: ProtocolVersion) -> Result<Self, DecodeError> {
+        let mut r = PacketReader::new(buf, "chat_command");
+        let command = r.string("command", MAX_STRING_LENGTH)?;
+        // Before 1.20.5 the signing data follows, which we don't verify
+        r.rest();
+        Ok(ChatCommandC2S { command })
+    }
+
+    #[cfg(any(test, feature = "client"))]
+    fn encode(&self, v: ProtocolVersion) -> BytesMut {
+        let mut buf = BytesMut::new();
+        write_string(&mut buf, &self.command);
+        if v < ProtocolVersion::V1_20_5 {
+            // Unsigned: timestamp, salt, no argument signatures, no acknowledgements
+            buf.put_u64(0);
+            buf.put_u64(0);
+            write_varint(&mut buf, 0);
+            write_varint(&mut buf, 0);
+            buf.put_bytes(0, 3);
+        }
+        buf
+    }
+
+    fn id(v: ProtocolVersion) -> i32 {
+        ids::serverbound(Stage::Play, "chat_command", v)
+    }
+}
+
+
+#[derive(Debug, Clone)]
+pub struct PlayKeepAliveC2S {
+    pub(crate) id: u64
+}
+
+impl PacketC2S for PlayKeepAliveC2S {
+    fn decode(buf: &mut Bytes, _: ProtocolVersion) -> Result<Self, DecodeError> {
+        let mut r = PacketReader::new(buf, "keep_alive");
+        Ok(PlayKeepAliveC2S {
+            id: r.u64("id")?
+        })
+    }
+
+    #[cfg(any(test, feature = "client"))]
+    fn encode(&self, _: ProtocolVersion) -> BytesMut {
+        let mut buf = BytesMut::new();
+        buf.put_u64(self.id);
+        buf
+    }
+
+    fn id(v: ProtocolVersion) -> i32 {
+        ids::serverbound(Stage::Play, "keep_alive", v)
+    }
+}
+
+
+#[derive(Debug, Clone)]
+pub struct PlayerPositionC2S {
+    pub(crate) x: f64,
+    pub(crate) y: f64,  // feet
+    pub(crate) z: f64,
+    pub(crate) on_ground: bool,
+}
+
+impl PacketC2S for PlayerPositionC2S {
+    fn decode(buf: &mut Bytes, _: ProtocolVersion) -> Result<Self, DecodeError> {
+        let mut r = PacketReader::new(buf, "move_player_pos");
+        Ok(PlayerPositionC2S {
+            x: r.f64("x")?,
+            y: r.f64("y")?,
+            z: r.f64("z")?,
+            on_ground: r.bool("on ground")?
+        })
+    }
+
+    #[cfg(any(test, feature = "client"))]
+    fn encode(&self, _: ProtocolVersion) -> BytesMut {
+        let mut buf = BytesMut::new();
+        buf.put_f64(self.x);
+        buf.put_f64(self.y);
+        buf.put_f64(self.z);
+        buf.put_u8(if self.on_ground { 1 } else { 0 });
+        buf
+    }
+
+    fn id(v: ProtocolVersion) -> i32 {
+        ids::serverbound(Stage::Play, "move_player_pos", v)
+    }
+}
+
+
+#[derive(Debug, Clone)]
+pub struct PlayerPositionRotationC2S {
+    pub(crate) x: f64,
+    pub(crate) y: f64,  // feet
+    pub(crate) z: f64,
+    pub(crate) yaw: f32,
+    pub(crate) pitch: f32,
+    pub(crate) on_ground: bool,
+}
+
+impl PacketC2S for PlayerPositionRotationC2S {
+    fn decode(buf: &mut Bytes, _: ProtocolVersion) -> Result<Self, DecodeError> {
+        let mut r = PacketReader::new(buf, "move_player_pos_rot");
+        Ok(PlayerPositionRotationC2S {
+            x: r.f64("x")?,
+            y: r.f64("y")?,
+            z: r.f64("z")?,
+            yaw: r.f32("yaw")?,
+            pitch: r.f32("pitch")?,
+            on_ground: r.bool("on ground")?
+        })
+    }
+
+    #[cfg(any(test, feature = "client"))]
+    fn encode(&self, _: ProtocolVersion) -> BytesMut {
+        let mut buf = BytesMut::new();
+        buf.put_f64(self.x);
+        buf.put_f64(self.y);
+        buf.put_f64(self.z);
+        buf.put_f32(self.yaw);
+        buf.put_f32(self.pitch);
+        buf.put_u8(if self.on_ground { 1 } else { 0 });
+        buf
+    }
+
+    fn id(v: ProtocolVersion) -> i32 {
+        ids::serverbound(Stage::Play, "move_player_pos_rot", v)
+    }
+}
+
+
+#[derive(Debug, Clone)]
+pub struct PlayerRotationC2S {
+    pub(crate) yaw: f32,
+    pub(crate) pitch: f32,
+    pub(crate) on_ground: bool,
+}
+
+impl PacketC2S for PlayerRotationC2S {
+    fn decode(buf: &mut Bytes, _: ProtocolVersion) -> Result<Self, DecodeError> {
+        let mut r = PacketReader::new(buf, "move_player_rot");
+        Ok(PlayerRotationC2S {
+            yaw: r.f32("yaw")?,
+            pitch: r.f32("pitch")?,
+            on_ground: r.bool("on ground")?
+        })
+    }
+
+    #[cfg(any(test, feature = "client"))]
+    fn encode(&self, _: ProtocolVersion) -> BytesMut {
+        let mut buf = BytesMut::new();
+        buf.put_f32(self.yaw);
+        buf.put_f32(self.pitch);
+        buf.put_u8(if self.on_ground { 1 } else { 0 });
+        buf
     }
 
-    fn id(_: ProtocolVersion) -> i32 {
-        6
+    fn id(v: ProtocolVersion) -> i32 {
+        ids::serverbound(Stage::Play, "move_player_rot", v)
     }
 }
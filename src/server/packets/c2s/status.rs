@@ -1,6 +1,11 @@
-use bytes::{Buf, Bytes};
+use bytes::Bytes;
+use crate::server::packets::decode::{DecodeError, PacketReader};
 use crate::server::packets::packet::PacketC2S;
+use crate::server::packets::ids;
+use crate::server::packets::stage::Stage;
 use crate::server::version::ProtocolVersion;
+#[cfg(any(test, feature = "client"))]
+use bytes::{BufMut, BytesMut};
 
 /// Unchanged since 1.8
 #[derive(Debug, Clone)]
@@ -9,12 +14,17 @@ pub struct StatusRequestC2S {
 }
 
 impl PacketC2S for StatusRequestC2S {
-    fn decode(_: &mut Bytes, _: ProtocolVersion) -> StatusRequestC2S {
-        StatusRequestC2S {}
+    fn decode(_: &mut Bytes, _: ProtocolVersion) -> Result<StatusRequestC2S, DecodeError> {
+        Ok(StatusRequestC2S {})
     }
 
-    fn id(_: ProtocolVersion) -> i32 {
-        0  // always
+    #[cfg(any(test, feature = "client"))]
+    fn encode(&self, _: ProtocolVersion) -> BytesMut {
+        BytesMut::new()
+    }
+
+    fn id(v: ProtocolVersion) -> i32 {
+        ids::serverbound(Stage::Status, "status_request", v)
     }
 }
 
@@ -25,13 +35,21 @@ pub struct PingRequestC2S {
 }
 
 impl PacketC2S for PingRequestC2S {
-    fn decode(buf: &mut Bytes, _: ProtocolVersion) -> Self {
-        PingRequestC2S {
-            payload: buf.get_u64()
-        }
+    fn decode(buf: &mut Bytes, _: ProtocolVersion) -> Result<Self, DecodeError> {
+        let mut r = PacketReader::new(buf, "ping_request");
+        Ok(PingRequestC2S {
+            payload: r.u64("payload")?
+        })
+    }
+
+    #[cfg(any(test, feature = "client"))]
+    fn encode(&self, _: ProtocolVersion) -> BytesMut {
+        let mut buf = BytesMut::new();
+        buf.put_u64(self.payload);
+        buf
     }
 
-    fn id(_: ProtocolVersion) -> i32 {
-        1  // always
+    fn id(v: ProtocolVersion) -> i32 {
+        ids::serverbound(Stage::Status, "ping_request", v)
     }
 }
@@ -0,0 +1,193 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use bytes::{Buf, Bytes};
+use uuid::Uuid;
+use crate::server::utils::{read_varint, ReadError};
+#[cfg(any(test, feature = "client"))]
+use crate::server::text::TextComponent;
+#[cfg(any(test, feature = "client"))]
+use crate::server::version::ProtocolVersion;
+#[cfg(any(test, feature = "client"))]
+use crate::Tag;
+
+/// The longest string vanilla accepts anywhere, in characters
+pub const MAX_STRING_LENGTH: usize = 32767;
+
+/// A packet a client sent that we couldn't read
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodeError {
+    pub(crate) packet: &'static str,
+    pub(crate) field: &'static str,
+    /// Bytes into the packet body where the field starts
+    pub(crate) offset: usize,
+    pub(crate) reason: ReadError
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "malformed {} packet: {} at byte {}: {}", self.packet, self.field, self.offset, self.reason)
+    }
+}
+
+impl Error for DecodeError {
+
+}
+
+/// Reads the fields of a packet body, failing with the packet, field and offset instead of panicking
+pub struct PacketReader<'a> {
+    buf: &'a mut Bytes,
+    packet: &'static str,
+    len: usize
+}
+
+impl<'a> PacketReader<'a> {
+    pub fn new(buf: &'a mut Bytes, packet: &'static str) -> PacketReader<'a> {
+        let len = buf.remaining();
+        PacketReader { buf, packet, len }
+    }
+
+    fn error(&self, field: &'static str, reason: ReadError) -> DecodeError {
+        DecodeError { packet: self.packet, field, offset: self.len - self.buf.remaining(), reason }
+    }
+
+    fn need(&self, field: &'static str, n: usize) -> Result<(), DecodeError> {
+        if self.buf.remaining() < n {
+            return Err(self.error(field, ReadError::UnexpectedEnd { needed: n, remaining: self.buf.remaining() }));
+        }
+        Ok(())
+    }
+
+    pub fn u8(&mut self, field: &'static str) -> Result<u8, DecodeError> {
+        self.need(field, 1)?;
+        Ok(self.buf.get_u8())
+    }
+
+    pub fn bool(&mut self, field: &'static str) -> Result<bool, DecodeError> {
+        Ok(self.u8(field)? != 0)
+    }
+
+    pub fn u16(&mut self, field: &'static str) -> Result<u16, DecodeError> {
+        self.need(field, 2)?;
+        Ok(self.buf.get_u16())
+    }
+
+    pub fn u32(&mut self, field: &'static str) -> Result<u32, DecodeError> {
+        self.need(field, 4)?;
+        Ok(self.buf.get_u32())
+    }
+
+    pub fn u64(&mut self, field: &'static str) -> Result<u64, DecodeError> {
+        self.need(field, 8)?;
+        Ok(self.buf.get_u64())
+    }
+
+    pub fn f32(&mut self, field: &'static str) -> Result<f32, DecodeError> {
+        self.need(field, 4)?;
+        Ok(self.buf.get_f32())
+    }
+
+    pub fn f64(&mut self, field: &'static str) -> Result<f64, DecodeError> {
+        self.need(field, 8)?;
+        Ok(self.buf.get_f64())
+    }
+
+    pub fn varint(&mut self, field: &'static str) -> Result<i32, DecodeError> {
+        let start = self.buf.clone();
+        read_varint(self.buf).map_err(|e| {
+            *self.buf = start;  // report the offset the varint started at
+            self.error(field, e)
+        })
+    }
+
+    /// A varint that counts something, so it can't be negative or more than `max`
+    pub fn length(&mut self, field: &'static str, max: usize) -> Result<usize, DecodeError> {
+        let len = self.varint(field)?;
+        if len < 0 || len as usize > max {
+            return Err(self.error(field, ReadError::BadLength { length: len as i64, max }));
+        }
+        Ok(len as usize)
+    }
+
+    pub fn bytes(&mut self, field: &'static str, n: usize) -> Result<Bytes, DecodeError> {
+        self.need(field, n)?;
+        Ok(self.buf.split_to(n))
+    }
+
+    /// Bytes prefixed with their length
+    pub fn byte_array(&mut self, field: &'static str, max: usize) -> Result<Bytes, DecodeError> {
+        let len = self.length(field, max)?;
+        self.bytes(field, len)
+    }
+
+    /// A UTF-8 string of at most `max_chars` characters
+    pub fn string(&mut self, field: &'static str, max_chars: usize) -> Result<String, DecodeError> {
+        // A character takes up to 3 bytes in Java's modified UTF-8
+        let len = self.length(field, max_chars * 3)?;
+        self.need(field, len)?;
+        let text = match std::str::from_utf8(&self.buf[..len]) {
+            Ok(text) => text.to_owned(),
+            Err(_) => return Err(self.error(field, ReadError::InvalidUtf8))
+        };
+        if text.chars().count() > max_chars {
+            return Err(self.error(field, ReadError::BadLength { length: text.chars().count() as i64, max: max_chars }));
+        }
+        self.buf.advance(len);
+        Ok(text)
+    }
+
+    pub fn uuid(&mut self, field: &'static str) -> Result<Uuid, DecodeError> {
+        let bytes = self.bytes(field, 16)?;
+        Ok(Uuid::from_slice(&bytes[..]).expect("16 bytes is always a UUID"))
+    }
+
+    /// A text component, sent as JSON before 1.20.3 and as NBT since.
+    /// Only the text survives; the NBT is trusted once its type is there, since only our own
+    /// server's packets are read this way.
+    #[cfg(any(test, feature = "client"))]
+    pub fn text(&mut self, field: &'static str, v: ProtocolVersion) -> Result<TextComponent, DecodeError> {
+        if v >= ProtocolVersion::V1_20_3 {
+            self.need(field, 1)?;
+            Ok(TextComponent::from_nbt(&Tag::parse_network(self.buf, v)))
+        } else {
+            Ok(TextComponent::from_json(&self.string(field, MAX_STRING_LENGTH)?))
+        }
+    }
+
+    /// Everything left in the packet, for fields that run to its end
+    pub fn rest(&mut self) -> Bytes {
+        self.buf.split_to(self.buf.remaining())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use crate::server::packets::c2s::play::ChatC2S;
+    use crate::server::packets::decode::DecodeError;
+    use crate::server::packets::packet::PacketC2S;
+    use crate::server::utils::ReadError;
+    use crate::server::version::ProtocolVersion;
+
+    #[test]
+    fn test_malformed_packets() {
+        // A message, then a timestamp that's cut short
+        let mut truncated = Bytes::from_static(&[2, b'h', b'i', 0, 0, 0]);
+        assert_eq!(ChatC2S::decode(&mut truncated, ProtocolVersion::V1_21).unwrap_err(), DecodeError {
+            packet: "chat",
+            field: "timestamp",
+            offset: 3,
+            reason: ReadError::UnexpectedEnd { needed: 8, remaining: 3 }
+        });
+
+        let mut long_varint = Bytes::from_static(&[0xff, 0xff, 0xff, 0xff, 0xff, 0x01]);
+        let error = ChatC2S::decode(&mut long_varint, ProtocolVersion::V1_21).unwrap_err();
+        assert_eq!((error.field, error.offset, error.reason), ("message", 0, ReadError::VarIntTooLong));
+
+        // The signature is always 256 bytes
+        let mut body = vec![0u8; 17];
+        body.push(1);
+        body.extend_from_slice(&[0u8; 100]);
+        let error = ChatC2S::decode(&mut Bytes::from(body), ProtocolVersion::V1_21).unwrap_err();
+        assert_eq!((error.field, error.offset), ("signature", 18));
+    }
+}
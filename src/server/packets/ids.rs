@@ -0,0 +1,103 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use anyhow::anyhow;
+use lazy_static::lazy_static;
+use crate::server::packets::stage::Stage;
+use crate::server::version::ProtocolVersion;
+
+static PACKET_IDS: &str = include_str!("ids.toml");
+
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
+pub enum Direction {
+    Serverbound,
+    Clientbound
+}
+
+/// The IDs something had across protocol versions, like a packet or a registry entry
+#[derive(Debug, Clone)]
+pub struct IdHistory {
+    /// (protocol version, ID) pairs, newest first
+    ids: Vec<(i32, i32)>
+}
+
+impl IdHistory {
+    /// Reads IDs keyed by the protocol version they were introduced in
+    pub fn parse(versions: &HashMap<String, i32>) -> anyhow::Result<IdHistory> {
+        let mut ids = versions.iter()
+            .map(|(protocol, id)| Ok((protocol.parse::<i32>()?, *id)))
+            .collect::<anyhow::Result<Vec<(i32, i32)>>>()?;
+        ids.sort_by_key(|(protocol, _)| Reverse(*protocol));
+        Ok(IdHistory { ids })
+    }
+
+    /// The ID in a protocol version, or None if it didn't exist yet
+    pub fn get(&self, v: ProtocolVersion) -> Option<i32> {
+        self.ids.iter().find(|(protocol, _)| v.get_id() >= *protocol).map(|(_, id)| *id)
+    }
+}
+
+/// IDs by stage, direction, packet name and protocol version, as written in the table
+type RawIds = HashMap<String, HashMap<String, HashMap<String, HashMap<String, i32>>>>;
+
+lazy_static! {
+    static ref IDS: HashMap<(Stage, Direction, String), IdHistory> = parse_ids(PACKET_IDS).expect("invalid packet ID table");
+}
+
+fn parse_ids(data: &str) -> anyhow::Result<HashMap<(Stage, Direction, String), IdHistory>> {
+    let raw: RawIds = toml::from_str(data)?;
+    let mut ids = HashMap::new();
+    for (stage_name, directions) in raw {
+        let stage = match stage_name.as_str() {
+            "handshake" => Stage::Handshake,
+            "status" => Stage::Status,
+            "login" => Stage::Login,
+            "config" => Stage::Config,
+            "play" => Stage::Play,
+            _ => return Err(anyhow!("unknown stage {}", stage_name))
+        };
+        for (direction_name, packets) in directions {
+            let direction = match direction_name.as_str() {
+                "serverbound" => Direction::Serverbound,
+                "clientbound" => Direction::Clientbound,
+                _ => return Err(anyhow!("unknown direction {}", direction_name))
+            };
+            for (name, versions) in packets {
+                ids.insert((stage, direction, name), IdHistory::parse(&versions)?);
+            }
+        }
+    }
+    Ok(ids)
+}
+
+/// A packet's ID in a protocol version, or None if it doesn't exist there
+pub fn lookup(stage: Stage, direction: Direction, name: &str, v: ProtocolVersion) -> Option<i32> {
+    IDS.get(&(stage, direction, name.to_owned()))?.get(v)
+}
+
+/// ID of a packet we send; -1 if the client's version doesn't have it
+pub fn clientbound(stage: Stage, name: &str, v: ProtocolVersion) -> i32 {
+    lookup(stage, Direction::Clientbound, name, v).unwrap_or(-1)
+}
+
+/// ID of a packet we receive; -1 (which never matches) if the client's version doesn't have it
+pub fn serverbound(stage: Stage, name: &str, v: ProtocolVersion) -> i32 {
+    lookup(stage, Direction::Serverbound, name, v).unwrap_or(-1)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server::packets::ids::{clientbound, serverbound};
+    use crate::server::packets::stage::Stage;
+    use crate::server::version::ProtocolVersion;
+
+    #[test]
+    fn test_lookup() {
+        assert_eq!(clientbound(Stage::Play, "keep_alive", ProtocolVersion::V1_21), 0x26);
+        assert_eq!(clientbound(Stage::Play, "keep_alive", ProtocolVersion::V1_20_4), 0x24);
+        assert_eq!(clientbound(Stage::Play, "keep_alive", ProtocolVersion::V1_19_2), 0x20);
+        assert_eq!(clientbound(Stage::Config, "registry_data", ProtocolVersion::V1_20_4), 5);
+        assert_eq!(serverbound(Stage::Config, "cookie_response", ProtocolVersion::V1_20_4), -1);
+        assert_eq!(serverbound(Stage::Login, "hello", ProtocolVersion::V1_8), 0);
+        assert_eq!(serverbound(Stage::Handshake, "intention", ProtocolVersion::Unknown), 0);
+    }
+}
@@ -1,4 +1,6 @@
 pub(crate) mod s2c;
 pub(crate) mod c2s;
 pub(crate) mod packet;
-pub(crate) mod stage;
\ No newline at end of file
+pub(crate) mod stage;
+pub(crate) mod ids;
+pub(crate) mod decode;
\ No newline at end of file
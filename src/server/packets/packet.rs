@@ -1,5 +1,6 @@
 use std::fmt::Debug;
 use bytes::{Bytes, BytesMut};
+use crate::server::packets::decode::DecodeError;
 use crate::server::version::ProtocolVersion;
 
 pub trait PacketS2C : Debug {
@@ -8,6 +9,16 @@ pub trait PacketS2C : Debug {
 }
 
 pub trait PacketC2S {
-    fn decode(buf: &mut Bytes, v: ProtocolVersion) -> Self;
+    fn decode(buf: &mut Bytes, v: ProtocolVersion) -> Result<Self, DecodeError> where Self: Sized;
+    /// The client's side, for the headless client in `client`
+    #[cfg(any(test, feature = "client"))]
+    fn encode(&self, v: ProtocolVersion) -> BytesMut;
+    fn id(v: ProtocolVersion) -> i32;
+}
+
+/// The client's side of a packet we send, for the headless client in `client`
+#[cfg(any(test, feature = "client"))]
+pub trait DecodeS2C {
+    fn decode(buf: &mut Bytes, v: ProtocolVersion) -> Result<Self, DecodeError> where Self: Sized;
     fn id(v: ProtocolVersion) -> i32;
 }
@@ -3,10 +3,18 @@ use base64::Engine;
 use bytes::{BufMut, Bytes, BytesMut};
 use crate::server::packets::c2s::config::VersionedIdentifier;
 use crate::server::packets::packet::PacketS2C;
+use crate::server::packets::ids;
+use crate::server::packets::stage::Stage;
 use crate::server::text::TextComponent;
 use crate::server::utils::{write_string, write_varint};
 use crate::server::version::ProtocolVersion;
 use crate::Tag;
+#[cfg(any(test, feature = "client"))]
+use crate::server::cookie::MAX_COOKIE_SIZE;
+#[cfg(any(test, feature = "client"))]
+use crate::server::packets::decode::{DecodeError, PacketReader, MAX_STRING_LENGTH};
+#[cfg(any(test, feature = "client"))]
+use crate::server::packets::packet::DecodeS2C;
 
 #[derive(Debug, Clone)]
 pub struct CookieRequestS2C {
@@ -20,8 +28,20 @@ impl PacketS2C for CookieRequestS2C {
         buf
     }
 
-    fn id(&self, _: ProtocolVersion) -> i32 {
-        0
+    fn id(&self, v: ProtocolVersion) -> i32 {
+        ids::clientbound(Stage::Config, "cookie_request", v)
+    }
+}
+
+#[cfg(any(test, feature = "client"))]
+impl DecodeS2C for CookieRequestS2C {
+    fn decode(buf: &mut Bytes, _: ProtocolVersion) -> Result<Self, DecodeError> {
+        let mut r = PacketReader::new(buf, "cookie_request");
+        Ok(CookieRequestS2C { key: r.string("key", MAX_STRING_LENGTH)? })
+    }
+
+    fn id(v: ProtocolVersion) -> i32 {
+        ids::clientbound(Stage::Config, "cookie_request", v)
     }
 }
 
@@ -40,8 +60,23 @@ impl PacketS2C for CustomPayloadS2C {
         buf
     }
 
-    fn id(&self, _: ProtocolVersion) -> i32 {
-        1
+    fn id(&self, v: ProtocolVersion) -> i32 {
+        ids::clientbound(Stage::Config, "custom_payload", v)
+    }
+}
+
+#[cfg(any(test, feature = "client"))]
+impl DecodeS2C for CustomPayloadS2C {
+    fn decode(buf: &mut Bytes, _: ProtocolVersion) -> Result<Self, DecodeError> {
+        let mut r = PacketReader::new(buf, "custom_payload");
+        Ok(CustomPayloadS2C {
+            key: r.string("channel", MAX_STRING_LENGTH)?,
+            payload: r.rest()
+        })
+    }
+
+    fn id(v: ProtocolVersion) -> i32 {
+        ids::clientbound(Stage::Config, "custom_payload", v)
     }
 }
 
@@ -52,14 +87,30 @@ pub struct ConfigDisconnectS2C {
 }
 
 impl PacketS2C for ConfigDisconnectS2C {
-    fn encode(&self, _: ProtocolVersion) -> BytesMut {
+    fn encode(&self, v: ProtocolVersion) -> BytesMut {
         let mut buf = BytesMut::new();
-        write_string(&mut buf, &serde_json::ser::to_string(&self.reason).unwrap());
+        if v >= ProtocolVersion::V1_20_3 {
+            self.reason.to_nbt().serialize(&mut buf, true);
+        } else {
+            write_string(&mut buf, &serde_json::ser::to_string(&self.reason).unwrap());
+        }
         buf
     }
 
-    fn id(&self, _: ProtocolVersion) -> i32 {
-        2
+    fn id(&self, v: ProtocolVersion) -> i32 {
+        ids::clientbound(Stage::Config, "disconnect", v)
+    }
+}
+
+#[cfg(any(test, feature = "client"))]
+impl DecodeS2C for ConfigDisconnectS2C {
+    fn decode(buf: &mut Bytes, v: ProtocolVersion) -> Result<Self, DecodeError> {
+        let mut r = PacketReader::new(buf, "disconnect");
+        Ok(ConfigDisconnectS2C { reason: r.text("reason", v)? })
+    }
+
+    fn id(v: ProtocolVersion) -> i32 {
+        ids::clientbound(Stage::Config, "disconnect", v)
     }
 }
 
@@ -72,8 +123,19 @@ impl PacketS2C for ReadyS2C {
         BytesMut::new()
     }
 
-    fn id(&self, _: ProtocolVersion) -> i32 {
-        3
+    fn id(&self, v: ProtocolVersion) -> i32 {
+        ids::clientbound(Stage::Config, "finish_configuration", v)
+    }
+}
+
+#[cfg(any(test, feature = "client"))]
+impl DecodeS2C for ReadyS2C {
+    fn decode(buf: &mut Bytes, _: ProtocolVersion) -> Result<Self, DecodeError> {
+        Ok(ReadyS2C {})
+    }
+
+    fn id(v: ProtocolVersion) -> i32 {
+        ids::clientbound(Stage::Config, "finish_configuration", v)
     }
 }
 
@@ -90,8 +152,20 @@ impl PacketS2C for KeepAliveS2C {
         buf
     }
 
-    fn id(&self, _: ProtocolVersion) -> i32 {
-        4
+    fn id(&self, v: ProtocolVersion) -> i32 {
+        ids::clientbound(Stage::Config, "keep_alive", v)
+    }
+}
+
+#[cfg(any(test, feature = "client"))]
+impl DecodeS2C for KeepAliveS2C {
+    fn decode(buf: &mut Bytes, _: ProtocolVersion) -> Result<Self, DecodeError> {
+        let mut r = PacketReader::new(buf, "keep_alive");
+        Ok(KeepAliveS2C { payload: r.u64("payload")? })
+    }
+
+    fn id(v: ProtocolVersion) -> i32 {
+        ids::clientbound(Stage::Config, "keep_alive", v)
     }
 }
 
@@ -108,8 +182,20 @@ impl PacketS2C for PingS2C {
         buf
     }
 
-    fn id(&self, _: ProtocolVersion) -> i32 {
-        5
+    fn id(&self, v: ProtocolVersion) -> i32 {
+        ids::clientbound(Stage::Config, "ping", v)
+    }
+}
+
+#[cfg(any(test, feature = "client"))]
+impl DecodeS2C for PingS2C {
+    fn decode(buf: &mut Bytes, _: ProtocolVersion) -> Result<Self, DecodeError> {
+        let mut r = PacketReader::new(buf, "ping");
+        Ok(PingS2C { parameter: r.u32("parameter")? })
+    }
+
+    fn id(v: ProtocolVersion) -> i32 {
+        ids::clientbound(Stage::Config, "ping", v)
     }
 }
 
@@ -122,8 +208,8 @@ impl PacketS2C for ResetChatS2C {
         BytesMut::new()
     }
 
-    fn id(&self, _: ProtocolVersion) -> i32 {
-        6
+    fn id(&self, v: ProtocolVersion) -> i32 {
+        ids::clientbound(Stage::Config, "reset_chat", v)
     }
 }
 
@@ -155,8 +241,27 @@ impl PacketS2C for DynamicRegistriesS2C {
         buf
     }
 
-    fn id(&self, _: ProtocolVersion) -> i32 {
-        7
+    fn id(&self, v: ProtocolVersion) -> i32 {
+        ids::clientbound(Stage::Config, "registry_data", v)
+    }
+}
+
+
+/// Before 1.20.5, all registries are sent at once as a single compound
+#[derive(Debug, Clone)]
+pub struct RegistryCodecS2C {
+    pub(crate) codec: Tag
+}
+
+impl PacketS2C for RegistryCodecS2C {
+    fn encode(&self, v: ProtocolVersion) -> BytesMut {
+        let mut buf = BytesMut::new();
+        self.codec.serialize(&mut buf, v >= ProtocolVersion::V1_20_2);
+        buf
+    }
+
+    fn id(&self, v: ProtocolVersion) -> i32 {
+        ids::clientbound(Stage::Config, "registry_data", v)
     }
 }
 
@@ -176,8 +281,8 @@ impl PacketS2C for RemoveResourcePackS2C {
         buf
     }
 
-    fn id(&self, _: ProtocolVersion) -> i32 {
-        8
+    fn id(&self, v: ProtocolVersion) -> i32 {
+        ids::clientbound(Stage::Config, "resource_pack_pop", v)
     }
 }
 
@@ -203,8 +308,8 @@ impl PacketS2C for SendResourcePackS2C {
         buf
     }
 
-    fn id(&self, _: ProtocolVersion) -> i32 {
-        9
+    fn id(&self, v: ProtocolVersion) -> i32 {
+        ids::clientbound(Stage::Config, "resource_pack_push", v)
     }
 }
 
@@ -219,12 +324,28 @@ impl PacketS2C for StoreCookieS2C {
     fn encode(&self, _: ProtocolVersion) -> BytesMut {
         let mut buf = BytesMut::new();
         write_string(&mut buf, &self.key);
+        write_varint(&mut buf, self.payload.len() as i32);
         buf.put(self.payload.clone());
         buf
     }
 
-    fn id(&self, _: ProtocolVersion) -> i32 {
-        10
+    fn id(&self, v: ProtocolVersion) -> i32 {
+        ids::clientbound(Stage::Config, "store_cookie", v)
+    }
+}
+
+#[cfg(any(test, feature = "client"))]
+impl DecodeS2C for StoreCookieS2C {
+    fn decode(buf: &mut Bytes, _: ProtocolVersion) -> Result<Self, DecodeError> {
+        let mut r = PacketReader::new(buf, "store_cookie");
+        Ok(StoreCookieS2C {
+            key: r.string("key", MAX_STRING_LENGTH)?,
+            payload: r.byte_array("payload", MAX_COOKIE_SIZE)?
+        })
+    }
+
+    fn id(v: ProtocolVersion) -> i32 {
+        ids::clientbound(Stage::Config, "store_cookie", v)
     }
 }
 
@@ -243,8 +364,23 @@ impl PacketS2C for ServerTransferS2C {
         buf
     }
 
-    fn id(&self, _: ProtocolVersion) -> i32 {
-        11
+    fn id(&self, v: ProtocolVersion) -> i32 {
+        ids::clientbound(Stage::Config, "transfer", v)
+    }
+}
+
+#[cfg(any(test, feature = "client"))]
+impl DecodeS2C for ServerTransferS2C {
+    fn decode(buf: &mut Bytes, _: ProtocolVersion) -> Result<Self, DecodeError> {
+        let mut r = PacketReader::new(buf, "transfer");
+        Ok(ServerTransferS2C {
+            host: r.string("host", MAX_STRING_LENGTH)?,
+            port: r.varint("port")? as u16
+        })
+    }
+
+    fn id(v: ProtocolVersion) -> i32 {
+        ids::clientbound(Stage::Config, "transfer", v)
     }
 }
 
@@ -264,8 +400,8 @@ impl PacketS2C for FeaturesS2C {
         buf
     }
 
-    fn id(&self, _: ProtocolVersion) -> i32 {
-        12
+    fn id(&self, v: ProtocolVersion) -> i32 {
+        ids::clientbound(Stage::Config, "update_enabled_features", v)
     }
 }
 
@@ -299,8 +435,8 @@ impl PacketS2C for SyncTagsS2C {
         buf
     }
 
-    fn id(&self, _: ProtocolVersion) -> i32 {
-        13
+    fn id(&self, v: ProtocolVersion) -> i32 {
+        ids::clientbound(Stage::Config, "update_tags", v)
     }
 }
 
@@ -322,8 +458,28 @@ impl PacketS2C for SelectKnownPacksS2C {
         buf
     }
 
-    fn id(&self, _: ProtocolVersion) -> i32 {
-        14
+    fn id(&self, v: ProtocolVersion) -> i32 {
+        ids::clientbound(Stage::Config, "select_known_packs", v)
+    }
+}
+
+#[cfg(any(test, feature = "client"))]
+impl DecodeS2C for SelectKnownPacksS2C {
+    fn decode(buf: &mut Bytes, _: ProtocolVersion) -> Result<Self, DecodeError> {
+        let mut r = PacketReader::new(buf, "select_known_packs");
+        let mut known_packs = Vec::new();
+        for _ in 0..r.length("known packs", 64)? {
+            known_packs.push(VersionedIdentifier {
+                namespace: r.string("namespace", MAX_STRING_LENGTH)?,
+                id: r.string("id", MAX_STRING_LENGTH)?,
+                version: r.string("version", MAX_STRING_LENGTH)?
+            });
+        }
+        Ok(SelectKnownPacksS2C { known_packs })
+    }
+
+    fn id(v: ProtocolVersion) -> i32 {
+        ids::clientbound(Stage::Config, "select_known_packs", v)
     }
 }
 
@@ -344,8 +500,8 @@ impl PacketS2C for ReportDetailsS2C {
         buf
     }
 
-    fn id(&self, _: ProtocolVersion) -> i32 {
-        15
+    fn id(&self, v: ProtocolVersion) -> i32 {
+        ids::clientbound(Stage::Config, "custom_report_details", v)
     }
 }
 
@@ -417,7 +573,7 @@ impl PacketS2C for LinksS2C {
         buf
     }
 
-    fn id(&self, _: ProtocolVersion) -> i32 {
-        16
+    fn id(&self, v: ProtocolVersion) -> i32 {
+        ids::clientbound(Stage::Config, "server_links", v)
     }
 }
@@ -1,12 +1,19 @@
-use bytes::{Buf, BufMut, Bytes, BytesMut};
-use serde_derive::Deserialize;
-use uuid::Uuid;
+use bytes::{BufMut, Bytes, BytesMut};
 use crate::server::common::Profile;
 use crate::server::packets::packet::PacketS2C;
+use crate::server::packets::ids;
 use crate::server::packets::stage::Stage;
 use crate::server::text::TextComponent;
 use crate::server::utils::{write_string, write_uuid, write_varint};
 use crate::server::version::ProtocolVersion;
+#[cfg(any(test, feature = "client"))]
+use uuid::Uuid;
+#[cfg(any(test, feature = "client"))]
+use crate::server::common::ProfileProperty;
+#[cfg(any(test, feature = "client"))]
+use crate::server::packets::decode::{DecodeError, PacketReader, MAX_STRING_LENGTH};
+#[cfg(any(test, feature = "client"))]
+use crate::server::packets::packet::DecodeS2C;
 
 /// Unchanged since Netty rewrite
 #[derive(Debug, Clone)]
@@ -21,8 +28,22 @@ impl PacketS2C for LoginDisconnectS2C {
         buf
     }
 
-    fn id(&self, _: ProtocolVersion) -> i32 {
-        0
+    fn id(&self, v: ProtocolVersion) -> i32 {
+        ids::clientbound(Stage::Login, "login_disconnect", v)
+    }
+}
+
+#[cfg(any(test, feature = "client"))]
+impl DecodeS2C for LoginDisconnectS2C {
+    fn decode(buf: &mut Bytes, _: ProtocolVersion) -> Result<Self, DecodeError> {
+        let mut r = PacketReader::new(buf, "login_disconnect");
+        Ok(LoginDisconnectS2C {
+            reason: TextComponent::from_json(&r.string("reason", MAX_STRING_LENGTH)?)
+        })
+    }
+
+    fn id(v: ProtocolVersion) -> i32 {
+        ids::clientbound(Stage::Login, "login_disconnect", v)
     }
 }
 
@@ -48,8 +69,25 @@ impl PacketS2C for LoginHelloS2C {
         buf
     }
 
-    fn id(&self, _: ProtocolVersion) -> i32 {
-        1
+    fn id(&self, v: ProtocolVersion) -> i32 {
+        ids::clientbound(Stage::Login, "hello", v)
+    }
+}
+
+#[cfg(any(test, feature = "client"))]
+impl DecodeS2C for LoginHelloS2C {
+    fn decode(buf: &mut Bytes, v: ProtocolVersion) -> Result<Self, DecodeError> {
+        let mut r = PacketReader::new(buf, "hello");
+        Ok(LoginHelloS2C {
+            server_id: r.string("server id", 20)?,
+            public_key: r.byte_array("public key", MAX_STRING_LENGTH)?,
+            nonce: r.byte_array("nonce", MAX_STRING_LENGTH)?,
+            needs_authentication: v < ProtocolVersion::V1_20_5 || r.bool("needs authentication")?
+        })
+    }
+
+    fn id(v: ProtocolVersion) -> i32 {
+        ids::clientbound(Stage::Login, "hello", v)
     }
 }
 
@@ -88,8 +126,43 @@ impl PacketS2C for LoginSuccessS2C {
         buf
     }
 
-    fn id(&self, _: ProtocolVersion) -> i32 {
-        2
+    fn id(&self, v: ProtocolVersion) -> i32 {
+        ids::clientbound(Stage::Login, "game_profile", v)
+    }
+}
+
+#[cfg(any(test, feature = "client"))]
+impl DecodeS2C for LoginSuccessS2C {
+    fn decode(buf: &mut Bytes, v: ProtocolVersion) -> Result<Self, DecodeError> {
+        let mut r = PacketReader::new(buf, "game_profile");
+        let id = if v < ProtocolVersion::V20w12a {
+            let id = r.string("uuid", 36)?;
+            Uuid::parse_str(&id).unwrap_or_default()
+        } else {
+            r.uuid("uuid")?
+        };
+        let name = r.string("name", 16)?;
+        let mut properties = Vec::new();
+        if v > ProtocolVersion::V1_19 {
+            for _ in 0..r.length("properties", 16)? {
+                let name = r.string("property name", 64)?;
+                let value = r.string("property value", MAX_STRING_LENGTH)?;
+                let signature = if r.bool("signed")? {
+                    Some(r.string("signature", 1024)?)
+                } else {
+                    None
+                };
+                properties.push(ProfileProperty { name, value, signature });
+            }
+        }
+        Ok(LoginSuccessS2C {
+            profile: Profile { id, name, properties },
+            strict_error_handling: v >= ProtocolVersion::V1_20_5 && r.bool("strict error handling")?
+        })
+    }
+
+    fn id(v: ProtocolVersion) -> i32 {
+        ids::clientbound(Stage::Login, "game_profile", v)
     }
 }
 
@@ -106,8 +179,20 @@ impl PacketS2C for LoginCompressionS2C {
         buf
     }
 
-    fn id(&self, _: ProtocolVersion) -> i32 {
-        3
+    fn id(&self, v: ProtocolVersion) -> i32 {
+        ids::clientbound(Stage::Login, "login_compression", v)
+    }
+}
+
+#[cfg(any(test, feature = "client"))]
+impl DecodeS2C for LoginCompressionS2C {
+    fn decode(buf: &mut Bytes, _: ProtocolVersion) -> Result<Self, DecodeError> {
+        let mut r = PacketReader::new(buf, "login_compression");
+        Ok(LoginCompressionS2C { threshold: r.varint("threshold")? })
+    }
+
+    fn id(v: ProtocolVersion) -> i32 {
+        ids::clientbound(Stage::Login, "login_compression", v)
     }
 }
 
@@ -127,8 +212,24 @@ impl PacketS2C for LoginQueryRequestS2C {
         buf
     }
 
-    fn id(&self, _: ProtocolVersion) -> i32 {
-        4
+    fn id(&self, v: ProtocolVersion) -> i32 {
+        ids::clientbound(Stage::Login, "custom_query", v)
+    }
+}
+
+#[cfg(any(test, feature = "client"))]
+impl DecodeS2C for LoginQueryRequestS2C {
+    fn decode(buf: &mut Bytes, _: ProtocolVersion) -> Result<Self, DecodeError> {
+        let mut r = PacketReader::new(buf, "custom_query");
+        Ok(LoginQueryRequestS2C {
+            query_id: r.varint("query id")?,
+            channel: r.string("channel", MAX_STRING_LENGTH)?,
+            data: r.rest()
+        })
+    }
+
+    fn id(v: ProtocolVersion) -> i32 {
+        ids::clientbound(Stage::Login, "custom_query", v)
     }
 }
 
@@ -144,7 +245,19 @@ impl PacketS2C for LoginCookieRequestS2C {
         buf
     }
 
-    fn id(&self, _: ProtocolVersion) -> i32 {
-        5
+    fn id(&self, v: ProtocolVersion) -> i32 {
+        ids::clientbound(Stage::Login, "cookie_request", v)
+    }
+}
+
+#[cfg(any(test, feature = "client"))]
+impl DecodeS2C for LoginCookieRequestS2C {
+    fn decode(buf: &mut Bytes, _: ProtocolVersion) -> Result<Self, DecodeError> {
+        let mut r = PacketReader::new(buf, "cookie_request");
+        Ok(LoginCookieRequestS2C { key: r.string("key", MAX_STRING_LENGTH)? })
+    }
+
+    fn id(v: ProtocolVersion) -> i32 {
+        ids::clientbound(Stage::Login, "cookie_request", v)
     }
 }
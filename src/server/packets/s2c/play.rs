@@ -1,12 +1,21 @@
 use std::collections::HashMap;
 use bytes::{BufMut, Bytes, BytesMut};
 use crate::chunk::Chunk;
+use crate::server::command::CommandNode;
 use crate::server::common::Position;
 use crate::server::packets::packet::PacketS2C;
+use crate::server::packets::ids;
+use crate::server::packets::stage::Stage;
 use crate::server::text::TextComponent;
 use crate::server::utils::{write_string, write_varint};
 use crate::server::version::ProtocolVersion;
 use crate::Tag;
+#[cfg(any(test, feature = "client"))]
+use crate::server::cookie::MAX_COOKIE_SIZE;
+#[cfg(any(test, feature = "client"))]
+use crate::server::packets::decode::{DecodeError, PacketReader, MAX_STRING_LENGTH};
+#[cfg(any(test, feature = "client"))]
+use crate::server::packets::packet::DecodeS2C;
 
 #[derive(Debug, Clone)]
 pub struct PlayDisconnectS2C {
@@ -17,12 +26,28 @@ pub struct PlayDisconnectS2C {
 impl PacketS2C for PlayDisconnectS2C {
     fn encode(&self, v: ProtocolVersion) -> BytesMut {
         let mut buf = BytesMut::new();
-        self.reason.to_nbt().serialize(&mut buf, v >= ProtocolVersion::V1_20_2);
+        if v >= ProtocolVersion::V1_20_3 {
+            self.reason.to_nbt().serialize(&mut buf, true);
+        } else {
+            write_string(&mut buf, &serde_json::to_string(&self.reason).unwrap());
+        }
         buf
     }
 
-    fn id(&self, _: ProtocolVersion) -> i32 {
-        0x1d
+    fn id(&self, v: ProtocolVersion) -> i32 {
+        ids::clientbound(Stage::Play, "disconnect", v)
+    }
+}
+
+#[cfg(any(test, feature = "client"))]
+impl DecodeS2C for PlayDisconnectS2C {
+    fn decode(buf: &mut Bytes, v: ProtocolVersion) -> Result<Self, DecodeError> {
+        let mut r = PacketReader::new(buf, "disconnect");
+        Ok(PlayDisconnectS2C { reason: r.text("reason", v)? })
+    }
+
+    fn id(v: ProtocolVersion) -> i32 {
+        ids::clientbound(Stage::Play, "disconnect", v)
     }
 }
 
@@ -40,31 +65,19 @@ impl PacketS2C for KeepAliveS2C {
     }
 
     fn id(&self, v: ProtocolVersion) -> i32 {
-        if v >= ProtocolVersion::V1_20_6 {
-            0x26
-        } else if v >= ProtocolVersion::V1_20_2 {
-            0x24
-        } else if v >= ProtocolVersion::V1_19_4 {
-            0x23
-        } else if v >= ProtocolVersion::V1_19_3 {
-            0x1f
-        } else if v >= ProtocolVersion::V1_19_2 {
-            0x20
-        } else if v >= ProtocolVersion::V1_19 {
-            0x1e
-        } else if v >= ProtocolVersion::V1_17 {
-            0x21
-        } else if v >= ProtocolVersion::V1_16_2 {
-            0x1f
-        } else if v >= ProtocolVersion::V1_16_1 {
-            0x20
-        } else if v >= ProtocolVersion::V1_15 {
-            0x21
-        } else if v >= ProtocolVersion::V1_14 {
-            0x20
-        } else {  // 1.13.2
-            0x21
-        }
+        ids::clientbound(Stage::Play, "keep_alive", v)
+    }
+}
+
+#[cfg(any(test, feature = "client"))]
+impl DecodeS2C for KeepAliveS2C {
+    fn decode(buf: &mut Bytes, _: ProtocolVersion) -> Result<Self, DecodeError> {
+        let mut r = PacketReader::new(buf, "keep_alive");
+        Ok(KeepAliveS2C { payload: r.u64("payload")? })
+    }
+
+    fn id(v: ProtocolVersion) -> i32 {
+        ids::clientbound(Stage::Play, "keep_alive", v)
     }
 }
 
@@ -122,29 +135,7 @@ impl PacketS2C for GameEventS2C {
     }
 
     fn id(&self, v: ProtocolVersion) -> i32 {
-        if v >= ProtocolVersion::V1_20_6 {
-            0x22
-        } else if v >= ProtocolVersion::V1_20_2 {
-            0x20
-        } else if v >= ProtocolVersion::V1_19_4 {
-            0x1f
-        } else if v >= ProtocolVersion::V1_19_3 {
-            0x1c
-        } else if v >= ProtocolVersion::V1_19_2 {
-            0x1d
-        } else if v >= ProtocolVersion::V1_19 {
-            0x1b
-        } else if v >= ProtocolVersion::V1_17 {
-            0x1e
-        } else if v >= ProtocolVersion::V1_16_2 {
-            0x1d
-        } else if v >= ProtocolVersion::V1_16_1 {
-            0x1e
-        } else if v >= ProtocolVersion::V1_15 {
-            0x1f
-        } else {  // 1.13.2
-            0x1e
-        }
+        ids::clientbound(Stage::Play, "game_event", v)
     }
 }
 
@@ -337,27 +328,7 @@ impl PacketS2C for JoinGameS2C {
     }
 
     fn id(&self, v: ProtocolVersion) -> i32 {
-        return if v >= ProtocolVersion::V1_20_6 {
-            0x2B
-        } else if v >= ProtocolVersion::V1_20_2 {
-            0x29
-        } else if v >= ProtocolVersion::V1_20 {
-            0x28
-        } else if v >= ProtocolVersion::V1_19_3 {
-            0x24
-        } else if v >= ProtocolVersion::V1_19 {
-            0x23
-        } else if v >= ProtocolVersion::V1_18_1 {
-            0x26
-        } else if v >= ProtocolVersion::V1_16 {
-            0x25
-        } else if v >= ProtocolVersion::V1_15_2 {
-            0x26
-        } else if v >= ProtocolVersion::V1_13_2 {
-            0x25
-        } else {
-            0x25
-        }
+        ids::clientbound(Stage::Play, "login", v)
     }
 }
 
@@ -382,31 +353,54 @@ impl PacketS2C for ChunkDataS2C {
     }
 
     fn id(&self, v: ProtocolVersion) -> i32 {
-        if v >= ProtocolVersion::V1_20_6 {
-            0x27
-        } else if v >= ProtocolVersion::V1_20_2 {
-            0x25
-        } else if v >= ProtocolVersion::V1_19_4 {
-            0x24
-        } else if v >= ProtocolVersion::V1_19_3 {
-            0x20
-        } else if v >= ProtocolVersion::V1_19_2 {
-            0x21
-        } else if v >= ProtocolVersion::V1_19 {
-            0x1f
-        } else if v >= ProtocolVersion::V1_17 {
-            0x22
-        } else if v >= ProtocolVersion::V1_16_2 {
-            0x20
-        } else if v >= ProtocolVersion::V1_16_1 {
-            0x21
-        } else if v >= ProtocolVersion::V1_15 {
-            0x22
-        } else if v >= ProtocolVersion::V1_14 {
-            0x21
-        } else {  // 1.13.2
-            0x22
+        ids::clientbound(Stage::Play, "level_chunk_with_light", v)
+    }
+}
+
+
+#[derive(Debug, Clone)]
+pub struct UnloadChunkS2C {
+    pub(crate) x: i32,
+    pub(crate) z: i32,
+}
+
+impl PacketS2C for UnloadChunkS2C {
+    fn encode(&self, v: ProtocolVersion) -> BytesMut {
+        let mut buf = BytesMut::new();
+        // Since 1.20.2 this is read as one long, with Z in the upper half
+        if v >= ProtocolVersion::V1_20_2 {
+            buf.put_i32(self.z);
+            buf.put_i32(self.x);
+        } else {
+            buf.put_i32(self.x);
+            buf.put_i32(self.z);
         }
+        buf
+    }
+
+    fn id(&self, v: ProtocolVersion) -> i32 {
+        ids::clientbound(Stage::Play, "forget_level_chunk", v)
+    }
+}
+
+
+/// Moves the center of the client's chunk cache, outside of which chunks are dropped
+#[derive(Debug, Clone)]
+pub struct SetCenterChunkS2C {
+    pub(crate) x: i32,
+    pub(crate) z: i32,
+}
+
+impl PacketS2C for SetCenterChunkS2C {
+    fn encode(&self, _: ProtocolVersion) -> BytesMut {
+        let mut buf = BytesMut::new();
+        write_varint(&mut buf, self.x);
+        write_varint(&mut buf, self.z);
+        buf
+    }
+
+    fn id(&self, v: ProtocolVersion) -> i32 {
+        ids::clientbound(Stage::Play, "set_chunk_cache_center", v)
     }
 }
 
@@ -441,31 +435,7 @@ impl PacketS2C for SyncPlayerPositionS2C {
     }
 
     fn id(&self, v: ProtocolVersion) -> i32 {
-        if v >= ProtocolVersion::V1_20_6 {
-            0x40
-        } else if v >= ProtocolVersion::V1_20_2 {
-            0x3e
-        } else if v >= ProtocolVersion::V1_19_4 {
-            0x3c
-        } else if v >= ProtocolVersion::V1_19_3 {
-            0x38
-        } else if v >= ProtocolVersion::V1_19_2 {
-            0x39
-        } else if v >= ProtocolVersion::V1_19 {
-            0x36
-        } else if v >= ProtocolVersion::V1_17 {
-            0x38
-        } else if v >= ProtocolVersion::V1_16_2 {
-            0x34
-        } else if v >= ProtocolVersion::V1_16_1 {
-            0x35
-        } else if v >= ProtocolVersion::V1_15 {
-            0x36
-        } else if v >= ProtocolVersion::V1_14 {
-            0x35
-        } else {  // 1.13.2
-            0x32
-        }
+        ids::clientbound(Stage::Play, "player_position", v)
     }
 }
 
@@ -480,12 +450,129 @@ pub struct GameMessageS2C {
 impl PacketS2C for GameMessageS2C {
     fn encode(&self, v: ProtocolVersion) -> BytesMut {
         let mut buf = BytesMut::new();
-        self.text.to_nbt().serialize(&mut buf, v >= ProtocolVersion::V1_20_2);
+        if v >= ProtocolVersion::V1_20_3 {
+            self.text.to_nbt().serialize(&mut buf, true);
+        } else {
+            write_string(&mut buf, &serde_json::to_string(&self.text).unwrap());
+        }
         buf.put_u8(if self.overlay { 1 } else { 0 });
         buf
     }
 
-    fn id(&self, _: ProtocolVersion) -> i32 {
-        0x6c
+    fn id(&self, v: ProtocolVersion) -> i32 {
+        ids::clientbound(Stage::Play, "system_chat", v)
+    }
+}
+
+#[cfg(any(test, feature = "client"))]
+impl DecodeS2C for GameMessageS2C {
+    fn decode(buf: &mut Bytes, v: ProtocolVersion) -> Result<Self, DecodeError> {
+        let mut r = PacketReader::new(buf, "system_chat");
+        Ok(GameMessageS2C {
+            text: r.text("text", v)?,
+            overlay: r.bool("overlay")?
+        })
+    }
+
+    fn id(v: ProtocolVersion) -> i32 {
+        ids::clientbound(Stage::Play, "system_chat", v)
+    }
+}
+
+
+
+#[derive(Debug, Clone)]
+pub struct DeclareCommandsS2C {
+    pub(crate) nodes: Vec<CommandNode>,
+    pub(crate) root_index: i32,
+}
+
+
+impl PacketS2C for DeclareCommandsS2C {
+    fn encode(&self, v: ProtocolVersion) -> BytesMut {
+        let mut buf = BytesMut::new();
+        write_varint(&mut buf, self.nodes.len() as i32);
+        for node in &self.nodes {
+            node.write_to(&mut buf, v);
+        }
+        write_varint(&mut buf, self.root_index);
+        buf
+    }
+
+    fn id(&self, v: ProtocolVersion) -> i32 {
+        ids::clientbound(Stage::Play, "commands", v)
+    }
+}
+
+
+/// Added in 1.20.5
+#[derive(Debug, Clone)]
+pub struct PlayStoreCookieS2C {
+    pub(crate) key: String,
+    pub(crate) payload: Bytes,
+}
+
+impl PacketS2C for PlayStoreCookieS2C {
+    fn encode(&self, _: ProtocolVersion) -> BytesMut {
+        let mut buf = BytesMut::new();
+        write_string(&mut buf, &self.key);
+        write_varint(&mut buf, self.payload.len() as i32);
+        buf.put(self.payload.clone());
+        buf
+    }
+
+    fn id(&self, v: ProtocolVersion) -> i32 {
+        ids::clientbound(Stage::Play, "store_cookie", v)
+    }
+}
+
+#[cfg(any(test, feature = "client"))]
+impl DecodeS2C for PlayStoreCookieS2C {
+    fn decode(buf: &mut Bytes, _: ProtocolVersion) -> Result<Self, DecodeError> {
+        let mut r = PacketReader::new(buf, "store_cookie");
+        Ok(PlayStoreCookieS2C {
+            key: r.string("key", MAX_STRING_LENGTH)?,
+            payload: r.byte_array("payload", MAX_COOKIE_SIZE)?
+        })
+    }
+
+    fn id(v: ProtocolVersion) -> i32 {
+        ids::clientbound(Stage::Play, "store_cookie", v)
+    }
+}
+
+
+/// Added in 1.20.5
+#[derive(Debug, Clone)]
+pub struct PlayTransferS2C {
+    pub(crate) host: String,
+    pub(crate) port: u16,
+}
+
+impl PacketS2C for PlayTransferS2C {
+    fn encode(&self, _: ProtocolVersion) -> BytesMut {
+        let mut buf = BytesMut::new();
+        write_string(&mut buf, &self.host);
+        write_varint(&mut buf, self.port as i32);
+        buf
+    }
+
+    fn id(&self, v: ProtocolVersion) -> i32 {
+        ids::clientbound(Stage::Play, "transfer", v)
+    }
+}
+
+#[cfg(any(test, feature = "client"))]
+impl DecodeS2C for PlayTransferS2C {
+    fn decode(buf: &mut Bytes, _: ProtocolVersion) -> Result<Self, DecodeError> {
+        let mut r = PacketReader::new(buf, "transfer");
+        Ok(PlayTransferS2C {
+            host: r.string("host", MAX_STRING_LENGTH)?,
+            port: r.varint("port")? as u16
+        })
+    }
+
+    fn id(v: ProtocolVersion) -> i32 {
+        ids::clientbound(Stage::Play, "transfer", v)
     }
 }
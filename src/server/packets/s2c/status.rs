@@ -1,7 +1,15 @@
 use bytes::{BufMut, BytesMut};
 use crate::server::packets::packet::PacketS2C;
+use crate::server::packets::ids;
+use crate::server::packets::stage::Stage;
 use crate::server::utils::write_string;
 use crate::server::version::ProtocolVersion;
+#[cfg(any(test, feature = "client"))]
+use bytes::Bytes;
+#[cfg(any(test, feature = "client"))]
+use crate::server::packets::decode::{DecodeError, PacketReader, MAX_STRING_LENGTH};
+#[cfg(any(test, feature = "client"))]
+use crate::server::packets::packet::DecodeS2C;
 
 /// Unchanged since 1.8
 #[derive(Debug, Clone)]
@@ -22,8 +30,20 @@ impl PacketS2C for StatusResponseS2C {
         buf
     }
 
-    fn id(&self, _: ProtocolVersion) -> i32 {
-        0  // always
+    fn id(&self, v: ProtocolVersion) -> i32 {
+        ids::clientbound(Stage::Status, "status_response", v)
+    }
+}
+
+#[cfg(any(test, feature = "client"))]
+impl DecodeS2C for StatusResponseS2C {
+    fn decode(buf: &mut Bytes, _: ProtocolVersion) -> Result<Self, DecodeError> {
+        let mut r = PacketReader::new(buf, "status_response");
+        Ok(StatusResponseS2C::new(r.string("response", MAX_STRING_LENGTH)?))
+    }
+
+    fn id(v: ProtocolVersion) -> i32 {
+        ids::clientbound(Stage::Status, "status_response", v)
     }
 }
 
@@ -46,7 +66,19 @@ impl PacketS2C for PingResponseS2C {
         buf
     }
 
-    fn id(&self, _: ProtocolVersion) -> i32 {
-        1  // always
+    fn id(&self, v: ProtocolVersion) -> i32 {
+        ids::clientbound(Stage::Status, "pong_response", v)
+    }
+}
+
+#[cfg(any(test, feature = "client"))]
+impl DecodeS2C for PingResponseS2C {
+    fn decode(buf: &mut Bytes, _: ProtocolVersion) -> Result<Self, DecodeError> {
+        let mut r = PacketReader::new(buf, "pong_response");
+        Ok(PingResponseS2C::new(r.u64("payload")?))
+    }
+
+    fn id(v: ProtocolVersion) -> i32 {
+        ids::clientbound(Stage::Status, "pong_response", v)
     }
 }
@@ -1,4 +1,4 @@
-#[derive(Debug, Copy, Clone, Hash, PartialEq)]
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
 pub enum Stage {
     Handshake,
     Status,
@@ -18,4 +18,14 @@ impl Stage {
             _ => Stage::Invalid
         }
     }
+
+    /// The ID a handshake uses to ask for this stage
+    pub fn get_id(&self) -> i32 {
+        match self {
+            Stage::Status => 1,
+            Stage::Login => 2,
+            Stage::Transfer => 3,
+            _ => 0
+        }
+    }
 }
@@ -0,0 +1,72 @@
+use std::collections::HashMap;
+use uuid::Uuid;
+use crate::server::common::Profile;
+
+/// Players who have finished logging in, for the status response
+pub struct PlayerRegistry {
+    /// Names by UUID, with how many connections each player currently has
+    players: HashMap<Uuid, (String, usize)>
+}
+
+impl PlayerRegistry {
+    pub fn new() -> PlayerRegistry {
+        PlayerRegistry {
+            players: HashMap::new()
+        }
+    }
+
+    pub fn add(&mut self, profile: &Profile) {
+        let entry = self.players.entry(profile.id).or_insert_with(|| (profile.name.clone(), 0));
+        entry.0 = profile.name.clone();
+        entry.1 += 1;
+    }
+
+    /// Forgets one of a player's connections, returning whether they are gone completely
+    pub fn remove(&mut self, id: Uuid) -> bool {
+        match self.players.get_mut(&id) {
+            Some(entry) if entry.1 > 1 => {
+                entry.1 -= 1;
+                false
+            },
+            Some(_) => {
+                self.players.remove(&id);
+                true
+            },
+            None => false
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.players.len()
+    }
+
+    /// Up to `limit` players as (name, UUID), sorted by name so the sample is stable
+    pub fn sample(&self, limit: usize) -> Vec<(String, Uuid)> {
+        let mut players: Vec<(String, Uuid)> = self.players.iter()
+            .map(|(id, (name, _))| (name.clone(), *id))
+            .collect();
+        players.sort();
+        players.truncate(limit);
+        players
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+    use crate::server::common::Profile;
+    use crate::server::players::PlayerRegistry;
+
+    #[test]
+    fn counts_players_once() {
+        let profile = Profile { id: Uuid::from_u128(1), name: "Swan".to_owned(), properties: vec![] };
+        let mut players = PlayerRegistry::new();
+        players.add(&profile);
+        players.add(&profile);
+        assert_eq!(players.count(), 1);
+        assert!(!players.remove(profile.id));
+        assert_eq!(players.sample(12), vec![("Swan".to_owned(), profile.id)]);
+        assert!(players.remove(profile.id));
+        assert_eq!(players.count(), 0);
+    }
+}
@@ -0,0 +1,155 @@
+use std::collections::HashMap;
+use bytes::Bytes;
+use lazy_static::lazy_static;
+use serde_derive::Deserialize;
+use crate::block::{Block, BlockStates};
+use crate::server::packets::ids::IdHistory;
+use crate::server::version::ProtocolVersion;
+use crate::nbt::Tag;
+
+pub(crate) static REGISTRY_121: &[u8] = include_bytes!("registry_1.21.nbt");
+pub(crate) static REGISTRY_1206: &[u8] = include_bytes!("registry_1.20.6.nbt");
+pub(crate) static REGISTRY_DEFAULT: &[u8] = include_bytes!("registry.nbt");
+static BLOCK_ENTITY_TYPES: &str = include_str!("block_entity_types.toml");
+/// Block state reports, generated with the vanilla server's `--reports` and checked by build.rs
+static BLOCKS_121: &str = include_str!(concat!(env!("OUT_DIR"), "/blocks_1.21.json"));
+static BLOCKS_1206: &str = include_str!(concat!(env!("OUT_DIR"), "/blocks_1.20.6.json"));
+static BLOCKS_1204: &str = include_str!(concat!(env!("OUT_DIR"), "/blocks_1.20.4.json"));
+static BLOCK_FALLBACKS: &str = include_str!("block_fallbacks.toml");
+
+lazy_static! {
+    static ref BIOMES_121: HashMap<String, i32> = read_biome_ids(REGISTRY_121);
+    static ref BIOMES_1206: HashMap<String, i32> = read_biome_ids(REGISTRY_1206);
+    static ref BIOMES_DEFAULT: HashMap<String, i32> = read_biome_ids(REGISTRY_DEFAULT);
+    static ref BLOCK_ENTITY_IDS: HashMap<String, IdHistory> = parse_block_entity_types(BLOCK_ENTITY_TYPES)
+        .expect("invalid block entity type table");
+    static ref BLOCK_STATES_121: BlockStates = BlockStates::from_report(BLOCKS_121).expect("invalid 1.21 block state report");
+    static ref BLOCK_STATES_1206: BlockStates = BlockStates::from_report(BLOCKS_1206).expect("invalid 1.20.6 block state report");
+    static ref BLOCK_STATES_1204: BlockStates = BlockStates::from_report(BLOCKS_1204).expect("invalid 1.20.4 block state report");
+    static ref FALLBACKS: BlockFallbacks = toml::from_str(BLOCK_FALLBACKS).expect("invalid block fallback table");
+}
+
+#[derive(Deserialize)]
+struct BlockFallbacks {
+    /// Old name to new name
+    renamed: HashMap<String, String>,
+    /// Block to a stand-in for clients without it
+    missing: HashMap<String, String>
+}
+
+/// The registry NBT sent to a client, as separate registries from 1.20.5 and as one codec before
+pub fn registry_data(v: ProtocolVersion) -> &'static [u8] {
+    if v >= ProtocolVersion::V1_21 {
+        REGISTRY_121
+    } else if v >= ProtocolVersion::V1_20_5 {
+        REGISTRY_1206
+    } else {
+        REGISTRY_DEFAULT
+    }
+}
+
+/// Network IDs of biomes, matching the registry data the client was sent
+pub fn biome_ids(v: ProtocolVersion) -> &'static HashMap<String, i32> {
+    if v >= ProtocolVersion::V1_21 {
+        &BIOMES_121
+    } else if v >= ProtocolVersion::V1_20_5 {
+        &BIOMES_1206
+    } else {
+        &BIOMES_DEFAULT
+    }
+}
+
+/// Block state IDs for a client's version
+pub fn block_states(v: ProtocolVersion) -> &'static BlockStates {
+    if v >= ProtocolVersion::V1_21 {
+        &BLOCK_STATES_121
+    } else if v >= ProtocolVersion::V1_20_5 {
+        &BLOCK_STATES_1206
+    } else {
+        &BLOCK_STATES_1204
+    }
+}
+
+/// State ID of a saved block for a client, converting blocks the client's version doesn't have
+pub fn block_state_id(block: &Block, v: ProtocolVersion) -> i32 {
+    let states = block_states(v);
+    let name = match block.name() {
+        Some(name) => name.trim_start_matches("minecraft:"),
+        None => return 0
+    };
+    let renamed = FALLBACKS.renamed.get(name).map(|n| n.as_str())
+        .or_else(|| FALLBACKS.renamed.iter().find(|(_, new)| *new == name).map(|(old, _)| old.as_str()));
+    let candidates = [Some(name), renamed, FALLBACKS.missing.get(name).map(|n| n.as_str())];
+    for candidate in candidates.into_iter().flatten() {
+        if let Some(id) = states.get_id(&format!("minecraft:{candidate}"), block.properties()) {
+            return id;
+        }
+    }
+    0  // air
+}
+
+/// Reads `minecraft:worldgen/biome` out of registry NBT. Clients number the entries in the order
+/// they're sent, which is also what their `id` says.
+fn read_biome_ids(data: &'static [u8]) -> HashMap<String, i32> {
+    let registries = Tag::parse(&mut Bytes::from(data));
+    let mut ids = HashMap::new();
+    let entries = registries.get("minecraft:worldgen/biome").ok()
+        .and_then(|r| r.get("value").ok())
+        .and_then(|v| v.as_list().ok());
+    if let Some(entries) = entries {
+        for (i, entry) in entries.iter().enumerate() {
+            if let Ok(name) = entry.get("name").and_then(|n| n.as_string()) {
+                let id = entry.get("id").ok().and_then(|id| id.as_int().ok()).unwrap_or(i as i32);
+                ids.insert(name.clone(), id);
+            }
+        }
+    }
+    ids
+}
+
+fn parse_block_entity_types(data: &str) -> anyhow::Result<HashMap<String, IdHistory>> {
+    let raw: HashMap<String, HashMap<String, i32>> = toml::from_str(data)?;
+    let mut ids = HashMap::new();
+    for (name, versions) in raw {
+        ids.insert(format!("minecraft:{name}"), IdHistory::parse(&versions)?);
+    }
+    Ok(ids)
+}
+
+/// Network ID of a block entity type like `minecraft:chest`, or None if the client's version doesn't have it
+pub fn block_entity_type_id(name: &str, v: ProtocolVersion) -> Option<i32> {
+    BLOCK_ENTITY_IDS.get(name)?.get(v)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use crate::block::Block;
+    use crate::server::registry::{biome_ids, block_entity_type_id, block_state_id};
+    use crate::server::version::ProtocolVersion;
+
+    #[test]
+    fn biome_ids_follow_registry_order() {
+        for v in [ProtocolVersion::V1_20_4, ProtocolVersion::V1_20_6, ProtocolVersion::V1_21] {
+            let ids = biome_ids(v);
+            assert_eq!(ids.get("minecraft:badlands"), Some(&0));
+            assert!(ids.contains_key("minecraft:plains"));
+        }
+    }
+
+    #[test]
+    fn block_entity_types_by_version() {
+        assert_eq!(block_entity_type_id("minecraft:sign", ProtocolVersion::V1_20_4), Some(7));
+        assert_eq!(block_entity_type_id("minecraft:vault", ProtocolVersion::V1_21), Some(43));
+        assert_eq!(block_entity_type_id("minecraft:vault", ProtocolVersion::V1_20_4), None);
+    }
+
+    #[test]
+    fn stone_resolves_on_every_version() {
+        // Fails with the empty reports SWANDIST_SKIP_BLOCK_REPORTS builds with
+        let stone = Block::new("minecraft:stone", HashMap::new());
+        for v in [ProtocolVersion::V1_20_4, ProtocolVersion::V1_20_6, ProtocolVersion::V1_21] {
+            assert_eq!(block_state_id(&stone, v), 1, "stone on {}", v.get_name());
+        }
+    }
+}
@@ -1,12 +1,21 @@
+use std::collections::HashMap;
+use std::fs;
 use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use anyhow::Context;
 use base64::Engine;
 use base64::engine::general_purpose;
-use image::{DynamicImage, ImageFormat};
+use image::{DynamicImage, ImageFormat, ImageReader};
 use image::imageops::FilterType;
-use serde_derive::Serialize;
-use crate::server::text::TextComponent;
+use log::warn;
+use serde_derive::{Deserialize, Serialize};
+use crate::server::players::PlayerRegistry;
+use crate::server::text::{ChatColor, TextComponent};
 use crate::server::version::ProtocolVersion;
 
+/// The most players vanilla shows when hovering over the player count
+const MAX_SAMPLE: usize = 12;
+
 #[derive(Serialize)]
 pub struct StatusVersion {
     name: String,
@@ -66,6 +75,12 @@ impl StatusBuilder {
         }
     }
 
+    /// Shown instead of the protocol's name, e.g. to advertise a range of versions
+    pub fn with_version_name(&mut self, name: &str) -> &mut StatusBuilder {
+        self.version.name = name.to_owned();
+        self
+    }
+
     pub fn with_description(&mut self, description: TextComponent) -> &mut StatusBuilder {
         self.description = Some(description);
         self
@@ -79,11 +94,13 @@ impl StatusBuilder {
     }
 
     pub fn with_favicon(&mut self, icon: DynamicImage) -> &mut StatusBuilder {
-        let mut bytes: Vec<u8> = Vec::new();
-        let icon = icon.resize_exact(64, 64, FilterType::Lanczos3);
-        // FIXME error handling
-        icon.write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png).expect("invalid favicon");
-        self.favicon = Some(format!("data:image/png;base64,{}", general_purpose::STANDARD.encode(bytes)));
+        self.favicon = Some(encode_favicon(icon).expect("invalid favicon"));
+        self
+    }
+
+    /// Uses a favicon already encoded by `encode_favicon`
+    pub fn with_encoded_favicon(&mut self, favicon: String) -> &mut StatusBuilder {
+        self.favicon = Some(favicon);
         self
     }
 
@@ -109,4 +126,220 @@ impl StatusBuilder {
     pub fn finish(&self) -> String {
         serde_json::to_string(self).unwrap()
     }
+
+    /// The kick message answering a pre-Netty server list ping
+    pub fn finish_legacy(&self, format: LegacyPingFormat) -> String {
+        let motd = self.description.as_ref().map(|d| d.to_plain()).unwrap_or_default();
+        let (online, max) = self.players.as_ref().map(|p| (p.online, p.max)).unwrap_or((0, 0));
+        match format {
+            LegacyPingFormat::V1_4 => format!("§1\0{}\0{}\0{}\0{}\0{}",
+                self.version.protocol, self.version.name, motd, online, max),
+            // § separates the fields here, so it can't appear in the MOTD
+            LegacyPingFormat::Beta => format!("{}§{}§{}", motd.replace('§', ""), online, max)
+        }
+    }
+}
+
+/// Scales an icon to 64x64 and encodes it as a PNG data URL
+pub fn encode_favicon(icon: DynamicImage) -> anyhow::Result<String> {
+    let mut bytes: Vec<u8> = Vec::new();
+    let icon = icon.resize_exact(64, 64, FilterType::Lanczos3);
+    icon.write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png)?;
+    Ok(format!("data:image/png;base64,{}", general_purpose::STANDARD.encode(bytes)))
+}
+
+/// What the server list shows
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct StatusConfig {
+    pub(crate) motd: String,
+    /// Color names or `#rrggbb`; one color sets the MOTD's color, more make a gradient
+    pub(crate) motd_colors: Vec<String>,
+    pub(crate) favicon: Option<PathBuf>,
+    /// Shown instead of the supported version range
+    pub(crate) version_name: Option<String>,
+    pub(crate) max_players: i32,
+    /// Lists online players when hovering over the player count
+    pub(crate) show_players: bool,
+    /// Fixed lines to show instead of online players
+    pub(crate) sample: Vec<String>
+}
+
+impl Default for StatusConfig {
+    fn default() -> Self {
+        StatusConfig {
+            motd: "A Minecraft Server".to_owned(),
+            motd_colors: Vec::new(),
+            favicon: None,
+            version_name: None,
+            max_players: 20,
+            show_players: true,
+            sample: Vec::new()
+        }
+    }
+}
+
+impl StatusConfig {
+    /// Loads a config as TOML if it has a `.toml` extension and JSON otherwise
+    pub fn load(path: &Path) -> anyhow::Result<StatusConfig> {
+        let data = fs::read_to_string(path).with_context(|| format!("could not read {}", path.display()))?;
+        if path.extension().and_then(|e| e.to_str()) == Some("toml") {
+            Ok(toml::from_str(&data)?)
+        } else {
+            Ok(serde_json::from_str(&data)?)
+        }
+    }
+
+    pub fn motd_component(&self) -> TextComponent {
+        let mut motd = TextComponent::plain(&self.motd);
+        let colors: Vec<ChatColor> = self.motd_colors.iter().filter_map(|c| {
+            let color = ChatColor::from_name(c);
+            if color.is_none() {
+                warn!("unknown MOTD color {c}");
+            }
+            color
+        }).collect();
+        match colors.len() {
+            0 => {},
+            1 => motd.set_color(colors[0].clone()),
+            _ => motd.set_gradient(&colors)
+        }
+        motd
+    }
+}
+
+/// Prepares the parts of the status that don't change between pings, and remembers finished responses
+pub struct StatusCache {
+    config: StatusConfig,
+    motd: TextComponent,
+    favicon: Option<String>,
+    /// Finished responses by protocol ID, cleared whenever something in them changes
+    responses: HashMap<i32, String>
+}
+
+impl StatusCache {
+    pub fn new(config: StatusConfig) -> StatusCache {
+        let favicon = config.favicon.as_ref().and_then(|path| {
+            let icon = ImageReader::open(path).map_err(anyhow::Error::from)
+                .and_then(|r| Ok(r.decode()?))
+                .and_then(encode_favicon);
+            match icon {
+                Ok(icon) => Some(icon),
+                Err(e) => {
+                    warn!("could not load favicon {}: {e}", path.display());
+                    None
+                }
+            }
+        });
+        StatusCache {
+            motd: config.motd_component(),
+            config,
+            favicon,
+            responses: HashMap::new()
+        }
+    }
+
+    pub fn set_motd(&mut self, motd: TextComponent) {
+        self.motd = motd;
+        self.invalidate();
+    }
+
+    /// Forgets finished responses, e.g. once the player count changes
+    pub fn invalidate(&mut self) {
+        self.responses.clear();
+    }
+
+    pub fn build(&self, v: ProtocolVersion, players: &PlayerRegistry) -> StatusBuilder {
+        let sample = if !self.config.sample.is_empty() {
+            self.config.sample.iter()
+                .map(|line| PlayerSample::new(line.clone(), "00000000-0000-0000-0000-000000000000".to_owned()))
+                .collect()
+        } else if self.config.show_players {
+            players.sample(MAX_SAMPLE).into_iter()
+                .map(|(name, id)| PlayerSample::new(name, id.as_hyphenated().to_string()))
+                .collect()
+        } else {
+            Vec::new()
+        };
+
+        let mut status = StatusBuilder::new(v);
+        status.with_version_name(&self.config.version_name.clone().unwrap_or_else(ProtocolVersion::supported_range_name))
+            .with_description(self.motd.clone())
+            .with_player_sample(self.config.max_players, players.count() as i32, sample);
+        if let Some(favicon) = &self.favicon {
+            status.with_encoded_favicon(favicon.clone());
+        }
+        status
+    }
+
+    /// The status JSON for a protocol version, built only the first time it's asked for
+    pub fn get(&mut self, v: ProtocolVersion, players: &PlayerRegistry) -> String {
+        if let Some(response) = self.responses.get(&v.get_id()) {
+            return response.clone();
+        }
+        let response = self.build(v, players).finish();
+        self.responses.insert(v.get_id(), response.clone());
+        response
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LegacyPingFormat {
+    /// Beta 1.8 to 1.3, sends just 0xFE
+    Beta,
+    /// 1.4 to 1.6, sends 0xFE 0x01 (and a plugin message in 1.6)
+    V1_4
+}
+
+impl LegacyPingFormat {
+    /// Works out the format from the bytes of a ping that starts with 0xFE
+    pub fn detect(ping: &[u8]) -> LegacyPingFormat {
+        if ping.get(1) == Some(&0x01) { LegacyPingFormat::V1_4 } else { LegacyPingFormat::Beta }
+    }
+}
+
+/// Wraps a message in a legacy kick packet: 0xFF, then a UTF-16BE string prefixed with its length
+pub fn legacy_kick(message: &str) -> Vec<u8> {
+    let chars: Vec<u16> = message.encode_utf16().collect();
+    let mut buf = Vec::with_capacity(3 + chars.len() * 2);
+    buf.push(0xFF);
+    buf.extend_from_slice(&(chars.len() as u16).to_be_bytes());
+    for c in chars {
+        buf.extend_from_slice(&c.to_be_bytes());
+    }
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+    use crate::server::common::Profile;
+    use crate::server::players::PlayerRegistry;
+    use crate::server::status::{legacy_kick, LegacyPingFormat, StatusBuilder, StatusCache, StatusConfig};
+    use crate::server::version::ProtocolVersion;
+
+    #[test]
+    fn legacy_ping_response() {
+        let mut status = StatusBuilder::new(ProtocolVersion::V1_21);
+        status.with_version_name("1.21").with_plain_description("§bHi").with_player_sample(20, 3, Vec::new());
+        assert_eq!(LegacyPingFormat::detect(&[0xFE, 0x01, 0xFA]), LegacyPingFormat::V1_4);
+        assert_eq!(LegacyPingFormat::detect(&[0xFE]), LegacyPingFormat::Beta);
+        assert_eq!(status.finish_legacy(LegacyPingFormat::V1_4), "§1\0767\01.21\0§bHi\03\020");
+        assert_eq!(status.finish_legacy(LegacyPingFormat::Beta), "bHi§3§20");
+        assert_eq!(legacy_kick("§1"), vec![0xFF, 0x00, 0x02, 0x00, 0xA7, 0x00, 0x31]);
+    }
+
+    #[test]
+    fn cached_status_follows_players() {
+        let mut cache = StatusCache::new(StatusConfig::default());
+        let mut players = PlayerRegistry::new();
+        assert!(cache.get(ProtocolVersion::V1_21, &players).contains("\"online\":0"));
+        players.add(&Profile { id: Uuid::from_u128(1), name: "Swan".to_owned(), properties: vec![] });
+        // Still cached until invalidated
+        assert!(cache.get(ProtocolVersion::V1_21, &players).contains("\"online\":0"));
+        cache.invalidate();
+        let status = cache.get(ProtocolVersion::V1_21, &players);
+        assert!(status.contains("\"online\":1"));
+        assert!(status.contains("\"name\":\"Swan\""));
+    }
 }
@@ -1,6 +1,7 @@
 use std::collections::HashMap;
 use colorgrad::Color;
 use serde_derive::Serialize;
+use serde_json::Value;
 use crate::Tag;
 
 const FORMAT_CHAR: &str = "§";
@@ -15,12 +16,38 @@ pub enum ClickEventType {
     CopyToClipboard
 }
 
+impl ClickEventType {
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            ClickEventType::OpenUrl => "open_url",
+            ClickEventType::RunCommand => "run_command",
+            ClickEventType::SuggestCommand => "suggest_command",
+            ClickEventType::ChangePage => "change_page",
+            ClickEventType::CopyToClipboard => "copy_to_clipboard"
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Clone)]
 pub struct ClickEvent {
     action: ClickEventType,
     value: String
 }
 
+impl ClickEvent {
+    pub fn open_url(url: &str) -> ClickEvent {
+        ClickEvent { action: ClickEventType::OpenUrl, value: url.to_owned() }
+    }
+
+    pub fn run_command(command: &str) -> ClickEvent {
+        ClickEvent { action: ClickEventType::RunCommand, value: command.to_owned() }
+    }
+
+    pub fn suggest_command(command: &str) -> ClickEvent {
+        ClickEvent { action: ClickEventType::SuggestCommand, value: command.to_owned() }
+    }
+}
+
 #[derive(Debug, Serialize, Clone)]
 pub struct ShowItemEvent {
     id: String,
@@ -137,6 +164,36 @@ impl ChatColor {
         }.to_owned()
     }
 
+    /// Parses a vanilla color name or a `#rrggbb` hex color
+    pub fn from_name(name: &str) -> Option<ChatColor> {
+        if let Some(hex) = name.strip_prefix('#') {
+            return if hex.len() == 6 && u32::from_str_radix(hex, 16).is_ok() {
+                Some(ChatColor::Custom(hex.to_owned()))
+            } else {
+                None
+            };
+        }
+        Some(match name {
+            "black" => ChatColor::Black,
+            "dark_blue" => ChatColor::DarkBlue,
+            "dark_green" => ChatColor::DarkGreen,
+            "dark_aqua" => ChatColor::DarkCyan,
+            "dark_red" => ChatColor::DarkRed,
+            "dark_purple" => ChatColor::Purple,
+            "gold" => ChatColor::Gold,
+            "gray" => ChatColor::Gray,
+            "dark_gray" => ChatColor::DarkGray,
+            "blue" => ChatColor::Blue,
+            "green" => ChatColor::Green,
+            "aqua" => ChatColor::Aqua,
+            "red" => ChatColor::Red,
+            "light_purple" => ChatColor::LightPurple,
+            "yellow" => ChatColor::Yellow,
+            "white" => ChatColor::White,
+            _ => return None
+        })
+    }
+
     pub fn as_rgba(&self) -> (u8, u8, u8, u8) {
         match self {
             ChatColor::Black => (0x00, 0x00, 0x00, 0xff),
@@ -336,10 +393,25 @@ impl TextComponent {
         self.text = Some("".to_owned());
     }
 
+    pub fn set_click_event(&mut self, event: ClickEvent) {
+        self.click_event = Some(event);
+    }
+
     pub fn set_hover_event(&mut self, event: HoverEvent) {
         self.hover_event = Some(Box::new(event));
     }
 
+    /// The text content of this component and its siblings, without formatting
+    pub fn to_plain(&self) -> String {
+        let mut text = self.text.clone().unwrap_or_default();
+        if let Some(extra) = &self.extra {
+            for component in extra {
+                text.push_str(&component.to_plain());
+            }
+        }
+        text
+    }
+
     pub fn to_nbt(&self) -> Tag {
         let mut root = HashMap::new();
         root.insert("type".to_owned(), Tag::String(self.r#type.clone()));
@@ -371,6 +443,70 @@ impl TextComponent {
             root.insert("obfuscated".to_owned(), Tag::Byte(if obfuscated { 1 } else { 0 }));
         }
 
+        if let Some(click) = &self.click_event {
+            let mut event = HashMap::new();
+            event.insert("action".to_owned(), Tag::String(click.action.get_name().to_owned()));
+            event.insert("value".to_owned(), Tag::String(click.value.clone()));
+            root.insert("clickEvent".to_owned(), Tag::Compound(event));
+        }
+
         Tag::Compound(root)
     }
-}
\ No newline at end of file
+
+    /// Reads back the text of a component sent as JSON, dropping its formatting
+    pub fn from_json(json: &str) -> TextComponent {
+        match serde_json::from_str::<Value>(json) {
+            Ok(value) => Self::from_json_value(&value),
+            // Not JSON, so it's probably just the text
+            Err(_) => TextComponent::plain(json)
+        }
+    }
+
+    fn from_json_value(value: &Value) -> TextComponent {
+        match value {
+            Value::String(text) => TextComponent::plain(text),
+            Value::Array(parts) => {
+                let mut component = TextComponent::plain("");
+                for part in parts {
+                    component.add_component(Self::from_json_value(part));
+                }
+                component
+            },
+            Value::Object(fields) => {
+                let mut component = TextComponent::plain(fields.get("text").and_then(|t| t.as_str()).unwrap_or(""));
+                if let Some(Value::Array(extra)) = fields.get("extra") {
+                    for part in extra {
+                        component.add_component(Self::from_json_value(part));
+                    }
+                }
+                component
+            },
+            other => TextComponent::plain(&other.to_string())
+        }
+    }
+
+    /// Reads back the text of a component sent as NBT (1.20.3+), dropping its formatting
+    pub fn from_nbt(tag: &Tag) -> TextComponent {
+        match tag {
+            Tag::String(text) => TextComponent::plain(text),
+            Tag::List(parts) => {
+                let mut component = TextComponent::plain("");
+                for part in parts {
+                    component.add_component(Self::from_nbt(part));
+                }
+                component
+            },
+            Tag::Compound(fields) => {
+                let text = fields.get("text").and_then(|t| t.as_string().ok()).cloned().unwrap_or_default();
+                let mut component = TextComponent::plain(&text);
+                if let Some(Tag::List(extra)) = fields.get("extra") {
+                    for part in extra {
+                        component.add_component(Self::from_nbt(part));
+                    }
+                }
+                component
+            },
+            _ => TextComponent::new()
+        }
+    }
+}
@@ -1,20 +1,47 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter};
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 use uuid::Uuid;
 
+/// Why a value couldn't be read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadError {
+    UnexpectedEnd { needed: usize, remaining: usize },
+    VarIntTooLong,
+    BadLength { length: i64, max: usize },
+    InvalidUtf8
+}
+
+impl Display for ReadError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReadError::UnexpectedEnd { needed, remaining } => write!(f, "needed {needed} bytes, but only {remaining} are left"),
+            ReadError::VarIntTooLong => f.write_str("varint is longer than 5 bytes"),
+            ReadError::BadLength { length, max } => write!(f, "length {length} is not between 0 and {max}"),
+            ReadError::InvalidUtf8 => f.write_str("string is not valid UTF-8")
+        }
+    }
+}
+
+impl Error for ReadError {
+
+}
 
 // VarInt implementations based on https://github.com/valence-rs/valence/blob/main/crates/valence_protocol/src/var_int.rs
 // (which is also based on https://github.com/as-com/varint-simd/blob/master/src/encode/mod.rs)
-pub fn read_varint(buf: &mut Bytes) -> i32 {
+pub fn read_varint(buf: &mut Bytes) -> Result<i32, ReadError> {
     let mut val = 0;
     for i in 0..5 {
+        if !buf.has_remaining() {
+            return Err(ReadError::UnexpectedEnd { needed: 1, remaining: 0 });
+        }
         let byte = buf.get_u8();
         val |= (i32::from(byte) & 0b01111111) << (i * 7);
         if byte & 0b10000000 == 0 {
-            break;
+            return Ok(val);
         }
     }
-    // FIXME: what if the varint is more than 5 bytes long?
-    return val;
+    Err(ReadError::VarIntTooLong)
 }
 
 pub fn write_varint(buf: &mut BytesMut, value: i32) -> () {
@@ -86,12 +113,17 @@ pub fn write_varlong(buf: &mut BytesMut, val: i64) -> () {
     ()
 }
 
-pub fn read_string(buf: &mut Bytes) -> String {
-    let len = read_varint(buf) as usize;
-    let text = buf.slice(0..len);
-    buf.advance(len);
-    // FIXME: handle invalid strings better
-    String::from_utf8_lossy(&text[..]).to_string()
+pub fn read_string(buf: &mut Bytes) -> Result<String, ReadError> {
+    let len = read_varint(buf)?;
+    if len < 0 {
+        return Err(ReadError::BadLength { length: len as i64, max: buf.remaining() });
+    }
+    let len = len as usize;
+    if buf.remaining() < len {
+        return Err(ReadError::UnexpectedEnd { needed: len, remaining: buf.remaining() });
+    }
+    let text = buf.split_to(len);
+    String::from_utf8(text.to_vec()).map_err(|_| ReadError::InvalidUtf8)
 }
 
 pub fn write_string(buf: &mut BytesMut, s: &str) -> () {
@@ -99,10 +131,11 @@ pub fn write_string(buf: &mut BytesMut, s: &str) -> () {
     buf.put(s.as_bytes());
 }
 
-pub fn read_uuid(buf: &mut Bytes) -> Uuid {
-    let id = Uuid::from_slice(&buf.slice(0..16)[..]).unwrap();
-    buf.advance(16);
-    id
+pub fn read_uuid(buf: &mut Bytes) -> Result<Uuid, ReadError> {
+    if buf.remaining() < 16 {
+        return Err(ReadError::UnexpectedEnd { needed: 16, remaining: buf.remaining() });
+    }
+    Ok(Uuid::from_slice(&buf.split_to(16)[..]).expect("16 bytes is always a UUID"))
 }
 
 pub fn write_uuid(buf: &mut BytesMut, id: Uuid) {
@@ -1811,3 +1811,38 @@ impl Ord for ProtocolVersion {
         self.get_id().cmp(&other.get_id())
     }
 }
+
+/// A protocol version players can log in with, and the releases that use it
+pub struct SupportedVersion {
+    pub(crate) protocol: ProtocolVersion,
+    pub(crate) releases: &'static [&'static str]
+}
+
+/// Oldest first
+pub const SUPPORTED_VERSIONS: &[SupportedVersion] = &[
+    SupportedVersion { protocol: ProtocolVersion::V1_20_4, releases: &["1.20.3", "1.20.4"] },
+    SupportedVersion { protocol: ProtocolVersion::V1_20_6, releases: &["1.20.5", "1.20.6"] },
+    SupportedVersion { protocol: ProtocolVersion::V1_21, releases: &["1.21", "1.21.1"] },
+];
+
+impl ProtocolVersion {
+    pub fn is_supported(&self) -> bool {
+        SUPPORTED_VERSIONS.iter().any(|s| s.protocol == *self)
+    }
+
+    /// Release names sharing this protocol version, e.g. for known data packs
+    pub fn release_names(&self) -> &'static [&'static str] {
+        SUPPORTED_VERSIONS.iter().find(|s| s.protocol == *self).map(|s| s.releases).unwrap_or(&[])
+    }
+
+    pub fn latest_supported() -> ProtocolVersion {
+        SUPPORTED_VERSIONS.last().unwrap().protocol
+    }
+
+    /// The supported range for players to read, e.g. `1.20.3-1.21.1`
+    pub fn supported_range_name() -> String {
+        let first = SUPPORTED_VERSIONS.first().unwrap().releases.first().unwrap();
+        let last = SUPPORTED_VERSIONS.last().unwrap().releases.last().unwrap();
+        format!("{}-{}", first, last)
+    }
+}
@@ -0,0 +1,163 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use crate::chunk::Chunk;
+use crate::Region;
+
+/// What a player sees after joining: the region files to read and where they start out
+#[derive(Debug, Clone)]
+pub struct WorldPreview {
+    /// A dimension's `region` directory
+    pub(crate) region_dir: PathBuf,
+    pub(crate) spawn: (f64, f64, f64),
+    /// The chunks the player may see; ones missing from the world are sent empty
+    pub(crate) chunks: Vec<(i32, i32)>
+}
+
+/// The chunk a block position is in
+pub fn chunk_pos(x: f64, z: f64) -> (i32, i32) {
+    ((x.floor() as i32) >> 4, (z.floor() as i32) >> 4)
+}
+
+/// The region directory of a dimension within a world folder
+pub fn region_dir(world: &Path, dimension: &str) -> PathBuf {
+    match dimension {
+        "minecraft:the_nether" => world.join("DIM-1").join("region"),
+        "minecraft:the_end" => world.join("DIM1").join("region"),
+        _ => world.join("region")
+    }
+}
+
+/// Reads chunks out of a region directory, opening each region file once
+pub struct RegionReader {
+    dir: PathBuf,
+    /// None for regions that don't exist
+    regions: HashMap<(i32, i32), Option<Region<File>>>
+}
+
+impl RegionReader {
+    pub fn new(dir: &Path) -> RegionReader {
+        RegionReader {
+            dir: dir.to_path_buf(),
+            regions: HashMap::new()
+        }
+    }
+
+    pub fn get_chunk(&mut self, chunk_x: i32, chunk_z: i32) -> Option<Chunk> {
+        let (region_x, region_z) = (chunk_x >> 5, chunk_z >> 5);
+        let dir = &self.dir;
+        let region = self.regions.entry((region_x, region_z)).or_insert_with(|| {
+            File::open(dir.join(format!("r.{}.{}.mca", region_x, region_z))).ok().map(Region::load)
+        });
+        region.as_mut()?.get_chunk(chunk_x & 31, chunk_z & 31)
+    }
+}
+
+/// Where streamed chunks come from: a preview's region files, or empty chunks for a void world
+pub struct ChunkSource {
+    reader: Option<RegionReader>,
+    bounds: Option<HashSet<(i32, i32)>>
+}
+
+impl ChunkSource {
+    pub fn new(preview: Option<&WorldPreview>) -> ChunkSource {
+        ChunkSource {
+            reader: preview.map(|p| RegionReader::new(&p.region_dir)),
+            bounds: preview.map(|p| p.chunks.iter().copied().collect())
+        }
+    }
+
+    /// The chunk to send, or None if it's outside the preview
+    pub fn get_chunk(&mut self, chunk_x: i32, chunk_z: i32) -> Option<Chunk> {
+        if let Some(bounds) = &self.bounds {
+            if !bounds.contains(&(chunk_x, chunk_z)) {
+                return None;
+            }
+        }
+        Some(self.reader.as_mut().and_then(|r| r.get_chunk(chunk_x, chunk_z)).unwrap_or_else(Chunk::empty))
+    }
+}
+
+/// Chunks to load and chunks to unload
+pub type ChunkChanges = (Vec<(i32, i32)>, Vec<(i32, i32)>);
+
+/// The square of chunks a client should have loaded around the chunk it's in
+pub struct ChunkView {
+    center: (i32, i32),
+    radius: i32,
+    loaded: HashSet<(i32, i32)>
+}
+
+impl ChunkView {
+    /// Starts out with nothing loaded; `move_to` gives the first chunks to send
+    pub fn new(radius: i32) -> ChunkView {
+        ChunkView {
+            center: (0, 0),
+            radius,
+            loaded: HashSet::new()
+        }
+    }
+
+    pub fn center(&self) -> (i32, i32) {
+        self.center
+    }
+
+    pub fn is_loaded(&self, chunk: (i32, i32)) -> bool {
+        self.loaded.contains(&chunk)
+    }
+
+    fn in_range(&self, (x, z): (i32, i32)) -> bool {
+        (x - self.center.0).abs() <= self.radius && (z - self.center.1).abs() <= self.radius
+    }
+
+    /// Forgets every loaded chunk, returning them so they can be unloaded
+    pub fn clear(&mut self) -> Vec<(i32, i32)> {
+        self.loaded.drain().collect()
+    }
+
+    /// Recenters the view, returning the chunks to load (nearest first) and the ones to unload
+    pub fn move_to(&mut self, center: (i32, i32)) -> ChunkChanges {
+        self.center = center;
+
+        let unload: Vec<(i32, i32)> = self.loaded.iter().copied().filter(|c| !self.in_range(*c)).collect();
+        for chunk in &unload {
+            self.loaded.remove(chunk);
+        }
+
+        let (cx, cz) = center;
+        let mut load: Vec<(i32, i32)> = ((cx - self.radius)..=(cx + self.radius))
+            .flat_map(|x| ((cz - self.radius)..=(cz + self.radius)).map(move |z| (x, z)))
+            .filter(|c| !self.loaded.contains(c))
+            .collect();
+        load.sort_by_key(|(x, z)| (x - cx).pow(2) + (z - cz).pow(2));
+        self.loaded.extend(load.iter().copied());
+
+        (load, unload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server::world::{chunk_pos, ChunkView};
+
+    #[test]
+    fn chunk_view_follows_player() {
+        let mut view = ChunkView::new(1);
+        let (load, unload) = view.move_to((0, 0));
+        assert_eq!(load.len(), 9);
+        assert_eq!(load[0], (0, 0));
+        assert!(unload.is_empty());
+
+        let (load, mut unload) = view.move_to((1, 0));
+        unload.sort();
+        assert_eq!(load.len(), 3);
+        assert!(load.iter().all(|(x, _)| *x == 2));
+        assert_eq!(unload, vec![(-1, -1), (-1, 0), (-1, 1)]);
+        assert!(view.is_loaded((2, 1)) && !view.is_loaded((-1, 0)));
+        assert_eq!(chunk_pos(-0.5, 31.9), (-1, 1));
+
+        assert_eq!(view.clear().len(), 9);
+        assert!(!view.is_loaded((1, 0)));
+        assert_eq!(view.move_to((1, 0)).0.len(), 9);
+    }
+}